@@ -0,0 +1,142 @@
+// The request handling hot path without the network: 'router::route' and
+// the api method the request goes to, on a generated in-memory dataset
+#![feature(test)]
+
+extern crate test;
+extern crate hyper;
+extern crate highloadcup;
+
+use hyper::{Method, Uri};
+use test::{Bencher, black_box};
+
+use highloadcup::api::Api;
+use highloadcup::cache::AverageCache;
+use highloadcup::data::*;
+use highloadcup::database::Database;
+use highloadcup::request::Request;
+use highloadcup::router;
+
+const USERS: u32 = 1000;
+const LOCATIONS: u32 = 100;
+const VISITS: u32 = 10000;
+
+// the same dataset every run, without a random number crate
+fn next(seed: &mut u64) -> u32 {
+    *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    (*seed >> 33) as u32
+}
+
+fn api() -> Api {
+    let mut seed = 1;
+    let users = (1..USERS + 1)
+        .map(|id| User {
+            id: UserId(id),
+            email: format!("user{}@mail.ru", id),
+            first_name: "Данила".to_string(),
+            last_name: "Стамленский".to_string(),
+            gender: if id % 2 == 0 { Gender::Male } else { Gender::Female },
+            birth_date: (next(&mut seed) % 1000000000) as Timestamp - 500000000
+        })
+        .collect();
+    let locations = (1..LOCATIONS + 1)
+        .map(|id| Location {
+            id: LocationId(id),
+            place: "Набережная".into(),
+            country: format!("Страна {}", id % 10).into(),
+            city: "Москва".into(),
+            distance: next(&mut seed) % 100
+        })
+        .collect();
+    let visits = (1..VISITS + 1)
+        .map(|id| Visit {
+            id: VisitId(id),
+            location: LocationId(next(&mut seed) % LOCATIONS + 1),
+            user: UserId(next(&mut seed) % USERS + 1),
+            visited_at: 1000000000 + (next(&mut seed) % 100000000) as Timestamp,
+            mark: (next(&mut seed) % 6) as u8
+        })
+        .collect();
+
+    Api::new(Database::from_entities(users, locations, visits))
+}
+
+fn handle(api: &Api, method: Method, uri: &Uri, body: &[u8]) -> usize {
+    let response = match router::route(method, uri.clone(), body).unwrap() {
+        Request::Get(request) => api.do_get(request).unwrap(),
+        Request::Post(request) => api.do_post(request).unwrap(),
+        request => panic!("Unexpected request: {:?}", request)
+    };
+    response.len()
+}
+
+// routing alone, no api
+#[bench]
+fn route(b: &mut Bencher) {
+    let uris: Vec<Uri> = ["/users/500", "/users/500/visits",
+                          "/locations/50", "/locations/50/avg", "/visits/5000"]
+        .iter()
+        .map(|uri| uri.parse().unwrap())
+        .collect();
+    b.iter(|| for uri in &uris {
+        black_box(router::route(Method::Get, uri.clone(), b"").unwrap());
+    });
+}
+
+fn bench_get(b: &mut Bencher, uri: &str) {
+    let api = api();
+    let uri = uri.parse().unwrap();
+    b.iter(|| black_box(handle(&api, Method::Get, &uri, b"")));
+}
+
+#[bench]
+fn get_entity(b: &mut Bencher) {
+    bench_get(b, "/users/500");
+}
+
+#[bench]
+fn get_visits(b: &mut Bencher) {
+    bench_get(b, "/users/500/visits");
+}
+
+#[bench]
+fn get_visits_by_country(b: &mut Bencher) {
+    bench_get(b, "/users/500/visits?country=%D0%A1%D1%82%D1%80%D0%B0%D0%BD%D0%B0%203");
+}
+
+#[bench]
+fn get_average(b: &mut Bencher) {
+    bench_get(b, "/locations/50/avg");
+}
+
+#[bench]
+fn get_average_cached(b: &mut Bencher) {
+    let mut api = api();
+    api.avg_cache = Some(AverageCache::new(1000));
+    let uri = "/locations/50/avg".parse().unwrap();
+    b.iter(|| black_box(handle(&api, Method::Get, &uri, b"")));
+}
+
+#[bench]
+fn post_update(b: &mut Bencher) {
+    let api = api();
+    let uri = "/visits/500".parse().unwrap();
+    let mut mark = 0;
+    b.iter(|| {
+        mark = (mark + 1) % 6;
+        let body = format!(r#"{{"mark":{}}}"#, mark);
+        black_box(handle(&api, Method::Post, &uri, body.as_bytes()))
+    });
+}
+
+#[bench]
+fn post_create(b: &mut Bencher) {
+    let api = api();
+    let uri = "/visits/new".parse().unwrap();
+    let mut id = VISITS;
+    b.iter(|| {
+        id += 1;
+        let body = format!(r#"{{"id":{},"location":{},"user":{},"visited_at":1100000000,"mark":3}}"#, 
+                           id, id % LOCATIONS + 1, id % USERS + 1);
+        black_box(handle(&api, Method::Post, &uri, body.as_bytes()))
+    });
+}
@@ -1,26 +1,534 @@
+use std::collections::HashSet;
+use std::collections::Bound::{self, Included, Excluded, Unbounded};
+use std::io;
+use std::cmp;
+use std::hash::Hash;
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::vec;
+use std::mem;
+use std::fmt::Write;
+use std::sync::{Arc, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::Serialize;
 use serde_json;
 use hyper::StatusCode;
 use bytes::Bytes;
 
 use data::*;
 use request::*;
-use database::Database;
+use database::{Database, Shards, ShardKey, Rater, Index, IndexStats, AGE_BUCKET_SECONDS};
+use wal::{Wal, Record};
+use cache::{AverageCache, CacheStats};
+use Phase;
+
+// incremented by the server threads
+#[derive(Default)]
+pub struct Counters {
+    pub get_requests:  AtomicU64,
+    pub post_requests: AtomicU64,
+    // across all workers
+    pub active_connections: AtomicU64,
+    pub accept_errors: AtomicU64,
+    // requests answered '429 Too Many Requests'
+    pub rate_limited: AtomicU64,
+    pub request_durations: Histogram
+}
+
+// upper bounds of the 'http_request_duration_seconds' buckets, in microseconds
+// and as rendered
+const DURATION_BUCKETS: [(u64, &str); 10] = [
+    (100, "0.0001"), (250, "0.00025"), (500, "0.0005"), (1000, "0.001"), (2500, "0.0025"),
+    (5000, "0.005"), (10000, "0.01"), (100000, "0.1"), (1000000, "1"), (10000000, "10")
+];
+
+// Counts per bucket exclusively, the last one for longer durations
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; 11],
+    sum_micros: AtomicU64
+}
+
+impl Histogram {
+    #[inline]
+    pub fn observe(&self, duration: Duration) {
+        let micros = duration.as_secs() * 1000000 + duration.subsec_micros() as u64;
+        let bucket = DURATION_BUCKETS.iter()
+            .position(|&(bound, _)| micros <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+}
 
 pub struct Api {
-    pub database: Database
+    pub database: Database,
+    // kept when the database is reloaded, see 'with_database'
+    pub counters: Arc<Counters>,
+    // current time for age and birth date checks, 'NOW' unless overridden
+    pub now: Timestamp,
+    pub phase: Phase,
+    // averages are rendered with all 'round' decimal places, otherwise the
+    // trailing zeros are dropped
+    pub avg_trailing_zeros: bool,
+    // ids of a 'GetEntities' request, more are '400 Bad Request'
+    pub max_multiget: usize,
+    // 'country' of 'GetVisits' matches regardless of case
+    pub case_insensitive_country: bool,
+    // a user can't have two visits at the same 'visited_at'
+    pub unique_visit_time: bool,
+    // 'visited_at' of created or updated visits, inclusive. Unbounded by
+    // default, loaded visits aren't checked
+    pub min_visited_at: Timestamp,
+    pub max_visited_at: Timestamp,
+    // responses of '/locations/<id>/avg', not cached if 'None'
+    pub avg_cache: Option<AverageCache>,
+    wal: Option<Wal>
+}
+
+static EMPTY_VISITS_RESPONSE: &[u8] = b"{\"visits\":[]}";
+static ZERO_COUNT_RESPONSE: &[u8] = b"{\"count\":0}";
+static ZERO_AVERAGE_RESPONSE: &[u8] = b"{\"avg\":0}";
+static ZERO_AVERAGE_COUNT_RESPONSE: &[u8] = b"{\"avg\":0,\"count\":0}";
+pub static POST_RESPONSE: &[u8] = b"{}";
+static HEALTH_RESPONSE: &[u8] = b"{\"status\":\"ok\"}";
+
+pub const DEFAULT_MAX_MULTIGET: usize = 100;
+// 2000-01-01T00:00:00Z and 2030-01-01T00:00:00Z
+pub const DEFAULT_MIN_VISITED_AT: Timestamp = 946684800;
+pub const DEFAULT_MAX_VISITED_AT: Timestamp = 1893456000;
+
+// visits per chunk of 'VisitChunks'
+const VISITS_PER_CHUNK: usize = 256;
+
+#[derive(Serialize)]
+struct VisitItem<'a> {
+    mark: u8,
+    visited_at: Timestamp,
+    place: &'a str
+}
+
+// Body of a 'GetVisits' response, see 'Api::visit_chunks'
+pub struct VisitChunks<A> {
+    api: A,
+    visits: vec::IntoIter<(Timestamp, u8, LocationId)>,
+    next: Option<Cursor>,
+    is_started: bool,
+    is_finished: bool
+}
+
+impl<A> VisitChunks<A> {
+    // number of visits not serialized yet
+    pub fn len(&self) -> usize {
+        self.visits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.visits.len() == 0
+    }
+}
+
+impl<A: Deref<Target = Api>> Iterator for VisitChunks<A> {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Bytes> {
+        if self.is_finished {
+            return None;
+        }
+
+        let mut chunk = Vec::with_capacity(VISITS_PER_CHUNK * 64);
+        if !self.is_started {
+            chunk.extend_from_slice(b"{\"visits\":[");
+        }
+
+        {
+            // visits of a location deleted meanwhile are left without a place
+            let locations = self.api.database.locations.read_all();
+            for (visited_at, mark, location) in self.visits.by_ref().take(VISITS_PER_CHUNK) {
+                if self.is_started {
+                    chunk.push(b',');
+                }
+                self.is_started = true;
+
+                let place = locations.get(&location).map_or("", |location| location.place.as_str());
+                serde_json::to_writer(&mut chunk, &VisitItem { mark, visited_at, place }).unwrap();
+            }
+        }
+
+        if self.visits.len() == 0 {
+            self.is_started = true;
+            self.is_finished = true;
+            chunk.push(b']');
+            write_next(&mut chunk, self.next);
+            chunk.push(b'}');
+        }
+        Some(chunk.into())
+    }
+}
+
+// ids per chunk of 'ExportChunks'
+const ENTITIES_PER_CHUNK: u64 = 1024;
+
+// Body of an 'Export' response, a line of JSON per entity, see
+// 'Api::export_chunks'. Ids are walked in order, each chunk is serialized
+// under the locks, so entities changed meanwhile show up as they are when
+// their chunk is reached
+pub struct ExportChunks<A> {
+    api: A,
+    kind: EntityKind,
+    ids: Range<u64>
+}
+
+impl<A: Deref<Target = Api>> Iterator for ExportChunks<A> {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Bytes> {
+        // ids are dense, but empty chunks are skipped anyway
+        while self.ids.start < self.ids.end {
+            let end = cmp::min(self.ids.start + ENTITIES_PER_CHUNK, self.ids.end);
+            let ids = self.ids.start..end;
+            self.ids.start = end;
+
+            let mut chunk = Vec::with_capacity(ENTITIES_PER_CHUNK as usize * 96);
+            let database = &self.api.database;
+            match self.kind {
+                EntityKind::Users => write_lines(&mut chunk, &database.users, ids, UserId),
+                EntityKind::Locations => write_lines(&mut chunk, &database.locations, ids, LocationId),
+                EntityKind::Visits => write_lines(&mut chunk, &database.visits, ids, VisitId)
+            }
+            if !chunk.is_empty() {
+                return Some(chunk.into());
+            }
+        }
+        None
+    }
+}
+
+#[inline]
+fn write_lines<K, V, F>(chunk: &mut Vec<u8>, shards: &Shards<K, V>, ids: Range<u64>, key: F)
+where
+    K: ShardKey + Hash + Eq,
+    V: Serialize,
+    F: Fn(u32) -> K
+{
+    let shards = shards.read_all();
+    for id in ids {
+        if let Some(entity) = shards.get(&key(id as u32)) {
+            serde_json::to_writer(&mut *chunk, entity).unwrap();
+            chunk.push(b'\n');
+        }
+    }
+}
+
+// '{"<name>":[...],"missing":[...]}', both in the order of 'ids'
+#[inline]
+fn entities_response<K, V, F>(name: &str, shards: &Shards<K, V>, ids: &[u32], key: F) -> Vec<u8>
+where
+    K: ShardKey + Hash + Eq,
+    V: Serialize,
+    F: Fn(u32) -> K
+{
+    let shards = shards.read_all();
+    let mut body = format!("{{\"{}\":[", name).into_bytes();
+    let mut missing = Vec::new();
+    let mut is_first = true;
+    for &id in ids {
+        match shards.get(&key(id)) {
+            Some(entity) => {
+                if !is_first {
+                    body.push(b',');
+                }
+                is_first = false;
+                serde_json::to_writer(&mut body, entity).unwrap();
+            },
+            None => missing.push(id)
+        }
+    }
+    body.extend_from_slice(b"],\"missing\":");
+    serde_json::to_writer(&mut body, &missing).unwrap();
+    body.push(b'}');
+    body
+}
+
+#[inline]
+fn id_range<K, V, F>(shards: &Shards<K, V>, id: F) -> Range<u64>
+where
+    K: ShardKey + Hash + Eq,
+    F: Fn(&V) -> u32
+{
+    let shards = shards.read_all();
+    shards.values()
+        .map(|entity| id(entity) as u64)
+        .fold(None, |range: Option<Range<u64>>, id| Some(match range {
+            Some(range) => cmp::min(range.start, id)..cmp::max(range.end, id + 1),
+            None => id..id + 1
+        }))
+        .unwrap_or(0..0)
+}
+
+// Rejected request, a non-empty 'message' is sent back as '{"error":"..."}'.
+// Clients match on messages, so they shouldn't change
+#[derive(Debug, PartialEq)]
+pub struct ApiError {
+    pub code: StatusCode,
+    pub message: &'static str
+}
+
+impl ApiError {
+    #[inline]
+    fn bad_request(message: &'static str) -> ApiError {
+        ApiError { code: StatusCode::BadRequest, message }
+    }
+
+    #[inline]
+    fn not_found() -> ApiError {
+        ApiError { code: StatusCode::NotFound, message: "not found" }
+    }
+}
+
+impl From<StatusCode> for ApiError {
+    #[inline]
+    fn from(code: StatusCode) -> ApiError {
+        ApiError { code, message: "" }
+    }
+}
+
+// ETag of an entity response, recomputed from the body so it changes with
+// every update. Not stable across builds, which only costs caches a miss
+#[inline]
+pub fn entity_tag(body: &[u8]) -> String {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+
+    let mut hasher = DefaultHasher::new();
+    hasher.write(body);
+    format!("{:016x}", hasher.finish())
+}
+
+// Without 'trailing_zeros' '4.50000' is '4.5' and '5.00000' is '5', with
+// 'with_count' the number of marks averaged is added as "count"
+#[inline]
+fn average_response(sum: u64, count: u64, decimal_places: usize, trailing_zeros: bool, with_count: bool) -> Bytes {
+    if count == 0 {
+        let response = if with_count { ZERO_AVERAGE_COUNT_RESPONSE } else { ZERO_AVERAGE_RESPONSE };
+        return Bytes::from_static(response);
+    }
+
+    let avg = sum as f64 / count as f64;
+    let scale = 10f64.powi(decimal_places as i32);
+    let avg = (avg * scale).round() / scale;
+    // using format here because of floating point arithmetic inaccuracy
+    let mut avg = format!("{:.*}", decimal_places, avg);
+    if !trailing_zeros && avg.contains('.') {
+        let length = avg.trim_end_matches('0').trim_end_matches('.').len();
+        avg.truncate(length);
+    }
+    if with_count {
+        format!("{{\"avg\":{},\"count\":{}}}", avg, count).into_bytes().into()
+    } else {
+        format!("{{\"avg\":{}}}", avg).into_bytes().into()
+    }
+}
+
+type DateRange = (Bound<(Timestamp, VisitId)>, Bound<(Timestamp, VisitId)>);
+
+// Range of 'VisitMap' keys between the dates, 'None' if there are none. Visits 
+// exactly at 'from_date'/'to_date' are excluded whatever their id, unless 
+// the bound is inclusive
+#[inline]
+fn date_range(from_date: Option<Timestamp>, from_inclusive: bool, 
+              to_date: Option<Timestamp>, to_inclusive: bool) -> Option<DateRange> {
+    let from_date = from_date.unwrap_or(Timestamp::min_value());
+    let to_date = to_date.unwrap_or(Timestamp::max_value());
+
+    let is_empty = if from_inclusive && to_inclusive {
+        from_date > to_date
+    } else {
+        from_date >= to_date
+    };
+
+    if is_empty {
+        return None;
+    }
+
+    let from = if from_inclusive {
+        Included((from_date, VisitId(0)))
+    } else {
+        Excluded((from_date, VisitId(u32::max_value())))
+    };
+    let to = if to_inclusive {
+        Included((to_date, VisitId(u32::max_value())))
+    } else {
+        Excluded((to_date, VisitId(0)))
+    };
+
+    Some((from, to))
+}
+
+// Narrows 'range' to the visits after 'cursor' in the 'order' of the 
+// listing, 'None' if none are left
+#[inline]
+fn resume_after(range: DateRange, cursor: Cursor, order: Order) -> Option<DateRange> {
+    #[inline]
+    fn key(bound: Bound<Cursor>) -> Option<Cursor> {
+        match bound {
+            Included(key) | Excluded(key) => Some(key),
+            Unbounded => None
+        }
+    }
+
+    let (from, to) = range;
+    let (from, to) = match order {
+        Order::Ascending if key(from).is_none_or(|key| cursor >= key) => (Excluded(cursor), to),
+        Order::Descending if key(to).is_none_or(|key| cursor <= key) => (from, Excluded(cursor)),
+        _ => (from, to)
+    };
+
+    // 'BTreeMap::range' panics on these
+    let is_empty = match (from, to) {
+        (Included(from), Included(to)) => from > to,
+        (Included(from), Excluded(to)) | (Excluded(from), Included(to)) | (Excluded(from), Excluded(to)) 
+            => from >= to,
+        _ => false
+    };
+    if is_empty {
+        None
+    } else {
+        Some((from, to))
+    }
+}
+
+#[inline]
+fn in_order<'a, I>(visits: I, order: Order) -> Box<Iterator<Item = I::Item> + 'a>
+where
+    I: DoubleEndedIterator + 'a
+{
+    match order {
+        Order::Ascending => Box::new(visits),
+        Order::Descending => Box::new(visits.rev())
+    }
 }
 
-static EMPTY_VISITS_RESPONSE: &'static [u8] = b"{\"visits\":[]}";
-static ZERO_AVERAGE_RESPONSE: &'static [u8] = b"{\"avg\":0}";
-static POST_RESPONSE: &'static [u8] = b"{}";
+// Unicode lowercase comparison (Cyrillic included) without allocating
+#[inline]
+fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.chars().flat_map(char::to_lowercase).eq(b.chars().flat_map(char::to_lowercase))
+}
+
+#[inline]
+fn write_next(body: &mut Vec<u8>, next: Option<Cursor>) {
+    if let Some((visited_at, VisitId(id))) = next {
+        body.extend_from_slice(format!(",\"next\":\"{}:{}\"", visited_at, id).as_bytes());
+    }
+}
 
 impl Api {
+    pub fn new(database: Database) -> Api {
+        Api { 
+            database, 
+            counters: Default::default(), 
+            now: *::NOW, 
+            phase: *::PHASE, 
+            avg_trailing_zeros: true, 
+            max_multiget: DEFAULT_MAX_MULTIGET, 
+            case_insensitive_country: false, 
+            unique_visit_time: false, 
+            min_visited_at: Timestamp::MIN, 
+            max_visited_at: Timestamp::MAX, 
+            avg_cache: None, 
+            wal: None 
+        }
+    }
+
+    // Replays the log on top of 'database' and appends new mutations to it.
+    // Records are replayed the same way they were applied originally, 
+    // including the ones that have failed
+    pub fn with_wal<P: AsRef<Path>>(database: Database, path: P) -> io::Result<Api> {
+        Api::new(database).replay(path)
+    }
+
+    // Same as 'with_wal', with the settings of this api, which have to be
+    // the ones the log was written with for the records to fail the same way
+    pub fn replay<P: AsRef<Path>>(self, path: P) -> io::Result<Api> {
+        for record in Wal::read(&path)? {
+            let _ = self.apply(record);
+        }
+
+        let wal = Wal::open(path)?;
+        Ok(Api { wal: Some(wal), ..self })
+    }
+
+    // Same settings and counters, but another database and an empty cache.
+    // Without the log, which has the mutations of this one
+    pub fn with_database(&self, database: Database) -> Api {
+        Api { 
+            database, 
+            counters: self.counters.clone(), 
+            now: self.now, 
+            phase: self.phase, 
+            avg_trailing_zeros: self.avg_trailing_zeros, 
+            max_multiget: self.max_multiget, 
+            case_insensitive_country: self.case_insensitive_country, 
+            unique_visit_time: self.unique_visit_time, 
+            min_visited_at: self.min_visited_at, 
+            max_visited_at: self.max_visited_at, 
+            avg_cache: self.avg_cache.as_ref().map(|cache| AverageCache::new(cache.capacity())), 
+            wal: None 
+        }
+    }
+
+    #[inline]
+    pub fn has_wal(&self) -> bool {
+        self.wal.is_some()
+    }
+
+    #[inline]
+    pub fn do_post(&self, request: PostRequest) -> Result<Bytes, ApiError> {
+        self.write(Record::Post(request))
+    }
+
+    #[inline]
+    pub fn do_delete(&self, request: DeleteRequest) -> Result<Bytes, ApiError> {
+        self.write(Record::Delete(request))
+    }
+
+    #[inline]
+    fn write(&self, record: Record) -> Result<Bytes, ApiError> {
+        match self.wal {
+            Some(ref wal) => wal.append(record, |record| self.apply(record))
+                .unwrap_or_else(|e| {
+                    error!("Unable to write to log: {}", e);
+                    Err(StatusCode::InternalServerError.into())
+                }),
+            None => self.apply(record)
+        }
+    }
+
     #[inline]
-    pub fn do_post(&mut self, request: PostRequest) -> Result<Bytes, StatusCode> {
+    fn apply(&self, record: Record) -> Result<Bytes, ApiError> {
         use request::PostRequest::*;
-        match request {
-            UpdateEntity(update) => self.update_entity(update),
-            CreateEntity(entity) => self.create_entity(entity)
+        match record {
+            Record::Post(UpdateEntity(update)) => self.update_entity(update, None),
+            Record::Post(UpdateEntityIfVersion(update, version)) => self.update_entity(update, Some(version)),
+            Record::Post(CreateEntity(entity)) => self.create_entity(entity),
+            Record::Delete(request) => self.delete_entity(request.entity, request.cascade)
+        }
+    }
+
+    // the entity and its version, consistent with each other. Updates bump
+    // the version under the lock they change the entity with, so the body
+    // is of that version if it didn't change while the body was built
+    #[inline]
+    pub fn do_get_versioned(&self, request: GetEntity) -> Result<(Bytes, u64), StatusCode> {
+        let entity = request.id();
+        loop {
+            let version = self.database.version(entity);
+            let body = self.get_entity(request)?;
+            if self.database.version(entity) == version {
+                return Ok((body, version));
+            }
         }
     }
 
@@ -30,28 +538,175 @@ impl Api {
         match request {
             GetEntity(entity_request) => self.get_entity(entity_request),
             GetVisits(id, parameters) => self.get_visits(id, parameters),
+            GetLocationVisits(id, parameters) => self.get_location_visits(id, parameters),
             GetAverageLocationRating(id, parameters) 
-                => self.get_average_location_rating(id, parameters)
+                => self.get_average_location_rating(id, parameters),
+            GetVisitRange(from_id, to_id) => self.get_visit_range(from_id, to_id),
+            GetEntities(kind, ids) => self.get_entities(kind, &ids),
+            Health => Ok(Bytes::from_static(HEALTH_RESPONSE)),
+            Stats(verbose) => self.get_stats(verbose),
+            Metrics => Ok(self.get_metrics()),
+            Export(kind) => Ok(Api::export_chunks(self, kind).collect::<Vec<_>>().concat().into())
+        }
+    }
+
+    // Renders into a single buffer, the counters are read one by one
+    #[inline]
+    fn get_metrics(&self) -> Bytes {
+        let counters = &self.counters;
+        let mut metrics = String::with_capacity(2048);
+
+        metrics.push_str("# HELP http_requests_total Requests received, by method.\n");
+        metrics.push_str("# TYPE http_requests_total counter\n");
+        for &(method, counter) in &[("GET", &counters.get_requests), ("POST", &counters.post_requests)] {
+            writeln!(metrics, "http_requests_total{{method=\"{}\"}} {}", method, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        metrics.push_str("# HELP http_request_duration_seconds Time to build responses.\n");
+        metrics.push_str("# TYPE http_request_duration_seconds histogram\n");
+        let histogram = &counters.request_durations;
+        let mut count = 0;
+        for (&(_, bound), bucket) in DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+            count += bucket.load(Ordering::Relaxed);
+            writeln!(metrics, "http_request_duration_seconds_bucket{{le=\"{}\"}} {}", bound, count).unwrap();
+        }
+        count += histogram.buckets[DURATION_BUCKETS.len()].load(Ordering::Relaxed);
+        writeln!(metrics, "http_request_duration_seconds_bucket{{le=\"+Inf\"}} {}", count).unwrap();
+        let sum = histogram.sum_micros.load(Ordering::Relaxed);
+        writeln!(metrics, "http_request_duration_seconds_sum {}.{:06}", sum / 1000000, sum % 1000000).unwrap();
+        writeln!(metrics, "http_request_duration_seconds_count {}", count).unwrap();
+
+        metrics.push_str("# HELP http_active_connections Connections open across workers.\n");
+        metrics.push_str("# TYPE http_active_connections gauge\n");
+        writeln!(metrics, "http_active_connections {}", counters.active_connections.load(Ordering::Relaxed)).unwrap();
+
+        metrics.push_str("# HELP entities Stored entities, by type.\n");
+        metrics.push_str("# TYPE entities gauge\n");
+        for &(name, count) in &[("users", self.database.users.len()), 
+                                ("locations", self.database.locations.len()),
+                                ("visits", self.database.visits.len())] {
+            writeln!(metrics, "entities{{type=\"{}\"}} {}", name, count).unwrap();
+        }
+
+        metrics.into()
+    }
+
+    #[inline]
+    fn get_stats(&self, verbose: bool) -> Result<Bytes, StatusCode> {
+        #[derive(Serialize)]
+        struct StatsResponse {
+            users: usize,
+            locations: usize,
+            visits: usize,
+            get_requests: u64,
+            post_requests: u64,
+            active_connections: u64,
+            accept_errors: u64,
+            rate_limited: u64,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            avg_cache: Option<CacheStats>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            index: Option<IndexStats>
         }
+
+        let response = StatsResponse {
+            users: self.database.users.len(),
+            locations: self.database.locations.len(),
+            visits: self.database.visits.len(),
+            get_requests: self.counters.get_requests.load(Ordering::Relaxed),
+            post_requests: self.counters.post_requests.load(Ordering::Relaxed),
+            active_connections: self.counters.active_connections.load(Ordering::Relaxed),
+            accept_errors: self.counters.accept_errors.load(Ordering::Relaxed),
+            rate_limited: self.counters.rate_limited.load(Ordering::Relaxed),
+            avg_cache: self.avg_cache.as_ref().map(AverageCache::stats),
+            index: if verbose { Some(self.database.stats()) } else { None }
+        };
+
+        Ok(serde_json::to_vec(&response).unwrap().into())
     }
 
     #[inline]
     fn get_entity(&self, request: GetEntity) -> Result<Bytes, StatusCode> {
         let bytes = match request {
             GetEntity::User(id) => {
-                let user = self.database.users.get(&id)
+                let users = self.database.users.read(&id);
+                let user = users.get(&id)
                     .ok_or(StatusCode::NotFound)?;
 
                 serde_json::to_vec(user).unwrap()
             },
+            GetEntity::UserWithVisits(id, limit) => {
+                #[derive(Serialize)]
+                struct UserItem<'a> {
+                    id: UserId,
+                    email: &'a str,
+                    first_name: &'a str,
+                    last_name: &'a str,
+                    gender: Gender,
+                    birth_date: Timestamp,
+                    visits: Vec<VisitItem<'a>>
+                }
+
+                // in 'GetVisits' order, the places are copied out of the locks
+                let mut visits = Vec::new();
+                let parameters = GetVisits { limit, ..Default::default() };
+                self.for_each_visit(id, &parameters, |visit, location| {
+                    visits.push((visit.mark, visit.visited_at, location.place.clone()));
+                })?;
+
+                let users = self.database.users.read(&id);
+                let user = users.get(&id)
+                    .ok_or(StatusCode::NotFound)?;
+
+                let item = UserItem {
+                    id: user.id,
+                    email: &user.email,
+                    first_name: &user.first_name,
+                    last_name: &user.last_name,
+                    gender: user.gender,
+                    birth_date: user.birth_date,
+                    visits: visits.iter()
+                        .map(|&(mark, visited_at, ref place)| VisitItem { mark, visited_at, place })
+                        .collect()
+                };
+                serde_json::to_vec(&item).unwrap()
+            },
             GetEntity::Location(id) => {
-                let location = self.database.locations.get(&id)
+                let locations = self.database.locations.read(&id);
+                let location = locations.get(&id)
                     .ok_or(StatusCode::NotFound)?;
 
                 serde_json::to_vec(location).unwrap()
             },
+            GetEntity::LocationWithVisitCount(id) => {
+                #[derive(Serialize)]
+                struct LocationItem<'a> {
+                    id: LocationId,
+                    place: &'a str,
+                    country: &'a str,
+                    city: &'a str,
+                    distance: u32,
+                    visit_count: usize
+                }
+
+                let index = self.database.index.read().unwrap_or_else(PoisonError::into_inner);
+                let locations = self.database.locations.read(&id);
+                let location = locations.get(&id)
+                    .ok_or(StatusCode::NotFound)?;
+
+                let item = LocationItem {
+                    id: location.id,
+                    place: &location.place,
+                    country: &location.country,
+                    city: &location.city,
+                    distance: location.distance,
+                    visit_count: index.visits_by_location.get(&id).map_or(0, |visits| visits.len())
+                };
+                serde_json::to_vec(&item).unwrap()
+            },
             GetEntity::Visit(id) => {
-                let visit = self.database.visits.get(&id)
+                let visits = self.database.visits.read(&id);
+                let visit = visits.get(&id)
                     .ok_or(StatusCode::NotFound)?;
 
                 serde_json::to_vec(visit).unwrap()
@@ -63,152 +718,448 @@ impl Api {
 
     #[inline]
     fn get_visits(&self, id: UserId, parameters: GetVisits) -> Result<Bytes, StatusCode> {
-        use std::collections::Bound::Excluded;
-        if !self.database.users.contains_key(&id) {
-            return Err(StatusCode::NotFound);
-        }
-        
-        #[derive(Serialize)]
-        struct VisitItem<'a> {
-            mark: u8,
-            visited_at: Timestamp,
-            place: &'a str
-        }
-
-        #[derive(Serialize)]
-        struct VisitsResponse<'a> {
-            visits: Vec<VisitItem<'a>>
-        }
-        
-        let from_date = parameters.from_date.unwrap_or(Timestamp::min_value());
-        let to_date = parameters.to_date.unwrap_or(Timestamp::max_value());
-
-        if from_date >= to_date {
-            return Ok(Bytes::from_static(EMPTY_VISITS_RESPONSE));
+        if parameters.count {
+            let mut count = 0;
+            self.for_each_visit(id, &parameters, |_, _| count += 1)?;
+            return if count == 0 {
+                Ok(Bytes::from_static(ZERO_COUNT_RESPONSE))
+            } else {
+                Ok(format!("{{\"count\":{}}}", count).into_bytes().into())
+            };
         }
 
-        let user_visits = match self.database.visits_by_user.get(&id) {
-            Some(visits) => visits,
-            None => return Ok(Bytes::from_static(EMPTY_VISITS_RESPONSE))
-        };
-
-        let mut visits = Vec::new();
-        for (_visit_id, visit) in user_visits.range((Excluded(from_date), Excluded(to_date))) {
-            let location = self.database.locations.get(&visit.location)
-                .ok_or(StatusCode::InternalServerError)?;
-            
-            if parameters.to_distance.is_some() 
-            && location.distance >= parameters.to_distance.unwrap() {
-                    continue;
-            }
-
-            if parameters.country.is_some() 
-            && location.country.as_str() != parameters.country.as_ref().unwrap() {
-                continue;
+        let mut body = b"{\"visits\":[".to_vec();
+        let mut is_first = true;
+        let next = self.for_each_visit(id, &parameters, |visit, location| {
+            if !is_first {
+                body.push(b',');
             }
+            is_first = false;
 
             let &Visit { visited_at, mark, .. } = visit;
             let place = location.place.as_str();
-            visits.push(VisitItem { mark, visited_at, place });
-        }
+            serde_json::to_writer(&mut body, &VisitItem { mark, visited_at, place }).unwrap();
+        })?;
 
-        if !visits.is_empty() {
-            Ok(serde_json::to_vec(&VisitsResponse { visits }).unwrap().into())
-        } else {
+        if is_first {
             Ok(Bytes::from_static(EMPTY_VISITS_RESPONSE))
+        } else {
+            body.push(b']');
+            write_next(&mut body, next);
+            body.push(b'}');
+            Ok(body.into())
         }
     }
 
+    // Same visits as 'GetVisits' responses, serialized lazily chunk by chunk.
+    // Only the matches are collected upfront, places are looked up when a
+    // chunk is serialized. 'count' is ignored
+    pub fn visit_chunks<A>(api: A, id: UserId, parameters: &GetVisits) -> Result<VisitChunks<A>, StatusCode> 
+    where
+        A: Deref<Target = Api>
+    {
+        let mut visits = Vec::new();
+        let next = api.for_each_visit(id, parameters, |visit, _| {
+            visits.push((visit.visited_at, visit.mark, visit.location));
+        })?;
+
+        Ok(VisitChunks { api, visits: visits.into_iter(), next, is_started: false, is_finished: false })
+    }
+
+    // Entities of the kind, serialized lazily chunk by chunk. Only the range 
+    // of ids is taken upfront, entities created later beyond it are left out
+    pub fn export_chunks<A>(api: A, kind: EntityKind) -> ExportChunks<A>
+    where
+        A: Deref<Target = Api>
+    {
+        let ids = match kind {
+            EntityKind::Users => id_range(&api.database.users, |user: &User| user.id.0),
+            EntityKind::Locations => id_range(&api.database.locations, |location: &Location| location.id.0),
+            EntityKind::Visits => id_range(&api.database.visits, |visit: &Visit| visit.id.0)
+        };
+        ExportChunks { api, kind, ids }
+    }
+
+    // Calls 'f' for the visits of 'GetVisits' in order, up to the limit.
+    // Returns the cursor of the last one if the limit has left others out
     #[inline]
-    fn get_average_location_rating(&self, id: LocationId, 
-                                   parameters: GetAverageLocationRating) 
-                                   -> Result<Bytes, StatusCode> 
+    fn for_each_visit<F>(&self, id: UserId, parameters: &GetVisits, mut f: F) -> Result<Option<Cursor>, StatusCode>
+    where
+        F: FnMut(&Visit, &Location)
     {
-        use std::collections::Bound::Excluded;
-        if !self.database.locations.contains_key(&id) {
+        let index = self.database.index.read().unwrap_or_else(PoisonError::into_inner);
+        if !self.database.users.contains_key(&id) {
             return Err(StatusCode::NotFound);
         }
 
-        let visits = match self.database.visits_by_location.get(&id) {
-            Some(visits) => visits,
-            None => return Ok(Bytes::from_static(ZERO_AVERAGE_RESPONSE))
+        let range = date_range(parameters.from_date, parameters.from_date_inclusive,
+                               parameters.to_date, parameters.to_date_inclusive)
+            .and_then(|range| match parameters.after {
+                Some(cursor) => resume_after(range, cursor, parameters.order),
+                None => Some(range)
+            });
+        let range = match range {
+            Some(range) => range,
+            None => return Ok(None)
         };
 
-        let needs_user_data = 
-               parameters.gender.is_some() 
-            || parameters.from_age.is_some() 
-            || parameters.to_age.is_some();
+        let user_visits = match parameters.country {
+            // the user's countries are few, but several spellings may match
+            Some(ref country) if self.case_insensitive_country => {
+                let mut spellings = index.visits_by_user_country.get(&id)
+                    .into_iter()
+                    .flat_map(|countries| countries.iter())
+                    .filter(|&(name, _)| eq_ignore_case(name, country))
+                    .map(|(_, visits)| visits);
+                match (spellings.next(), spellings.next()) {
+                    (Some(first), None) => in_order(first.range(range), parameters.order),
+                    (Some(first), Some(second)) => {
+                        let mut visits: Vec<_> = [first, second].iter()
+                            .cloned()
+                            .chain(spellings)
+                            .flat_map(|visits| visits.range(range))
+                            .collect();
+                        visits.sort_by_key(|&(key, _)| *key);
+                        in_order(visits.into_iter(), parameters.order)
+                    },
+                    (None, _) => return Ok(None)
+                }
+            },
+            Some(ref country) => match index.visits_by_user_country.get(&id)
+                .and_then(|countries| countries.get(country.as_str())) {
+                Some(visits) => in_order(visits.range(range), parameters.order),
+                None => return Ok(None)
+            },
+            None => match index.visits_by_user.get(&id) {
+                Some(visits) => in_order(visits.range(range), parameters.order),
+                None => return Ok(None)
+            }
+        };
 
-        const SECONDS_IN_YEAR: i64 = 31557600; // 365.25 days
+        let limit = parameters.limit.unwrap_or(usize::max_value());
 
-        let max_birth_date = parameters.from_age
-            .map(|age| *::NOW - SECONDS_IN_YEAR * age)
-            .unwrap_or(Timestamp::max_value());
-        let min_birth_date = parameters.to_age
-            .map(|age| *::NOW - SECONDS_IN_YEAR * age)
-            .unwrap_or(Timestamp::min_value());
+        let locations = self.database.locations.read_all();
 
-        let from_date = parameters.from_date.unwrap_or(Timestamp::min_value());
-        let to_date   = parameters.to_date.unwrap_or(Timestamp::max_value());
+        let (mut count, mut last) = (0, None);
+        for (&key, visit) in user_visits {
+            let location = locations.get(&visit.location)
+                .ok_or(StatusCode::InternalServerError)?;
+            
+            if parameters.from_distance.is_some_and(|from_distance| location.distance < from_distance)
+            || parameters.to_distance.is_some_and(|to_distance| location.distance >= to_distance) {
+                continue;
+            }
 
-        if from_date >= to_date || min_birth_date >= max_birth_date {
-            return Ok(Bytes::from_static(ZERO_AVERAGE_RESPONSE));
-        }
+            if parameters.from_mark.is_some_and(|from_mark| visit.mark < from_mark)
+            || parameters.to_mark.is_some_and(|to_mark| visit.mark > to_mark) {
+                continue;
+            }
 
-        let mut sum = 0usize;
-        let mut count = 0;
-        for (_visit_id, visit) in visits.range((Excluded(from_date), Excluded(to_date))) {
-            if needs_user_data {
-                let user = self.database.users.get(&visit.user)
-                    .ok_or(StatusCode::InternalServerError)?;
-                
-                if parameters.gender.is_some() && 
-                   user.gender != parameters.gender.unwrap() {
+            if let Some(ref city) = parameters.city {
+                if location.city != *city {
                     continue;
                 }
+            }
 
-                if user.birth_date <= min_birth_date ||
-                   user.birth_date >= max_birth_date {
-                    continue;
-                }
-            };
+            // a match is left, the listing goes on after the last one
+            if count >= limit {
+                return Ok(last);
+            }
 
-            sum += visit.mark as usize;
             count += 1;
+            last = Some(key);
+            f(visit, location);
         }
 
-        if count != 0 {
-            let avg = sum as f64 / count as f64;
-            let avg = (avg * 100000.0).round() / 100000.0;
-            // using format here because of floating point arithmetic inaccuracy
-            let bytes = format!("{{\"avg\":{:.5}}}", avg).into_bytes();
-            Ok(bytes.into())
-        } else {
-            Ok(Bytes::from_static(ZERO_AVERAGE_RESPONSE))
-        }
-    } 
+        Ok(None)
+    }
 
+    // in the order of 'visits_by_location', by time and then id
     #[inline]
-    fn update_entity(&mut self, request: UpdateEntity) -> Result<Bytes, StatusCode> {
-        use request::Optional::Something;
-        
-        match request {
-            UpdateEntity::User(id, update) => {
-                let user = self.database.users.get_mut(&id)
-                    .ok_or(StatusCode::NotFound)?;
-                
-                if let Something(email) = update.email {
-                    user.email = email;
-                }
+    fn get_location_visits(&self, id: LocationId, parameters: GetLocationVisits) -> Result<Bytes, StatusCode> {
+        #[derive(Serialize)]
+        struct LocationVisitItem {
+            user: UserId,
+            mark: u8,
+            visited_at: Timestamp
+        }
 
-                if let Something(first_name) = update.first_name {
-                    user.first_name = first_name;
-                }
+        #[derive(Serialize)]
+        struct LocationVisitsResponse {
+            visits: Vec<LocationVisitItem>
+        }
+
+        let index = self.database.index.read().unwrap_or_else(PoisonError::into_inner);
+        if !self.database.locations.contains_key(&id) {
+            return Err(StatusCode::NotFound);
+        }
+
+        let range = date_range(parameters.from_date, parameters.from_date_inclusive,
+                               parameters.to_date, parameters.to_date_inclusive);
+        let (range, visits) = match (range, index.visits_by_location.get(&id)) {
+            (Some(range), Some(visits)) => (range, visits),
+            _ => return Ok(Bytes::from_static(EMPTY_VISITS_RESPONSE))
+        };
+
+        let visits = visits.range(range)
+            .map(|(_, visit)| visit)
+            .filter(|visit| parameters.from_mark.is_none_or(|from_mark| visit.mark >= from_mark))
+            .filter(|visit| parameters.to_mark.is_none_or(|to_mark| visit.mark <= to_mark))
+            .map(|visit| LocationVisitItem { user: visit.user, mark: visit.mark, visited_at: visit.visited_at })
+            .collect();
+
+        Ok(serde_json::to_vec(&LocationVisitsResponse { visits }).unwrap().into())
+    }
+
+    // Visits aren't ordered by id, but ids are dense, so a range smaller than
+    // the number of visits is looked up id by id. Larger ones scan all the
+    // visits and sort the matches, which is linear in their total count
+    #[inline]
+    fn get_visit_range(&self, from_id: VisitId, to_id: VisitId) -> Result<Bytes, StatusCode> {
+        #[derive(Serialize)]
+        struct VisitRangeResponse<'a> {
+            visits: Vec<&'a Visit>
+        }
+
+        if from_id >= to_id {
+            return Ok(Bytes::from_static(EMPTY_VISITS_RESPONSE));
+        }
+
+        // counted before 'read_all', locking a shard again behind a waiting
+        // writer would deadlock
+        let count = self.database.visits.len();
+        let shards = self.database.visits.read_all();
+        let (VisitId(from), VisitId(to)) = (from_id, to_id);
+        let visits = if ((to - from) as usize) <= count {
+            (from..to).filter_map(|id| shards.get(&VisitId(id))).collect()
+        } else {
+            let mut visits: Vec<&Visit> = shards.values()
+                .filter(|visit| visit.id >= from_id && visit.id < to_id)
+                .collect();
+            visits.sort_by_key(|visit| visit.id);
+            visits
+        };
+
+        Ok(serde_json::to_vec(&VisitRangeResponse { visits }).unwrap().into())
+    }
+
+    #[inline]
+    fn get_entities(&self, kind: EntityKind, ids: &[u32]) -> Result<Bytes, StatusCode> {
+        if ids.len() > self.max_multiget {
+            return Err(StatusCode::BadRequest);
+        }
+
+        let database = &self.database;
+        let body = match kind {
+            EntityKind::Users => entities_response("users", &database.users, ids, UserId),
+            EntityKind::Locations => entities_response("locations", &database.locations, ids, LocationId),
+            EntityKind::Visits => entities_response("visits", &database.visits, ids, VisitId)
+        };
+        Ok(body.into())
+    }
+
+    #[inline]
+    fn get_average_location_rating(&self, id: LocationId, 
+                                   parameters: GetAverageLocationRating) 
+                                   -> Result<Bytes, StatusCode> 
+    {
+        let index = self.database.index.read().unwrap_or_else(PoisonError::into_inner);
+        if !self.database.locations.contains_key(&id) {
+            return Err(StatusCode::NotFound);
+        }
+
+        let cache = match self.avg_cache {
+            Some(ref cache) => cache,
+            None => return self.average_location_rating(&index, id, parameters)
+        };
+
+        // looked up and filled under the index lock, which the writes
+        // invalidating the responses hold exclusively
+        let key = GetAverageLocationRating { 
+            round: Some(parameters.round.unwrap_or(DEFAULT_DECIMAL_PLACES)), 
+            ..parameters 
+        };
+        if let Some(bytes) = cache.get(id, &key) {
+            return Ok(bytes);
+        }
+        let bytes = self.average_location_rating(&index, id, parameters)?;
+        cache.insert(id, key, bytes.clone());
+        Ok(bytes)
+    }
+
+    fn average_location_rating(&self, index: &Index, id: LocationId, 
+                               parameters: GetAverageLocationRating) 
+                               -> Result<Bytes, StatusCode> 
+    {
+        let decimal_places = parameters.round.unwrap_or(DEFAULT_DECIMAL_PLACES);
+        let response = |sum: u64, count: u64| 
+            average_response(sum, count, decimal_places, self.avg_trailing_zeros, parameters.with_count);
+        let needs_user_data = 
+               parameters.gender.is_some() 
+            || parameters.from_age.is_some() 
+            || parameters.to_age.is_some();
+
+        if !needs_user_data && parameters.from_date.is_none() && parameters.to_date.is_none() {
+            return match index.marks_by_location.get(&id) {
+                Some(marks) => Ok(response(marks.sum, marks.count as u64)),
+                None => Ok(response(0, 0))
+            };
+        }
+
+        let visits = match index.visits_by_location.get(&id) {
+            Some(visits) => visits,
+            None => return Ok(response(0, 0))
+        };
+
+        const SECONDS_IN_YEAR: i64 = 31557600; // 365.25 days
+
+        let birth_date = |age: Timestamp| SECONDS_IN_YEAR.checked_mul(age)
+            .and_then(|seconds| self.now.checked_sub(seconds))
+            .ok_or(StatusCode::BadRequest);
+
+        // both bounds are exclusive, 'fromAge < age < toAge'. A missing bound
+        // is no bound rather than an extreme birth date, so every birth date
+        // passes it
+        let max_birth_date = parameters.from_age.map(birth_date).transpose()?;
+        let min_birth_date = parameters.to_age.map(birth_date).transpose()?;
+        let is_empty_age_range = match (min_birth_date, max_birth_date) {
+            (Some(min_birth_date), Some(max_birth_date)) => min_birth_date >= max_birth_date,
+            _ => false
+        };
+
+        let range = date_range(parameters.from_date, parameters.from_date_inclusive,
+                               parameters.to_date, parameters.to_date_inclusive);
+        let range = match range {
+            Some(range) if !is_empty_age_range => range,
+            _ => return Ok(response(0, 0))
+        };
+
+        let passes_age = |birth_date: Timestamp| 
+               min_birth_date.is_none_or(|min_birth_date| birth_date > min_birth_date)
+            && max_birth_date.is_none_or(|max_birth_date| birth_date < max_birth_date);
+
+        // the buckets miss visits of unknown users, which are an error below
+        let buckets = index.visits_by_location_age.get(&id)
+            .filter(|buckets| buckets.values().map(|visits| visits.len()).sum::<usize>() == visits.len());
+
+        let mut sum = 0u64;
+        let mut count = 0;
+        if !needs_user_data {
+            for (_key, visit) in visits.range(range) {
+                sum += visit.mark as u64;
+                count += 1;
+            }
+        } else if let Some(buckets) = buckets {
+            for (&bucket, visits) in buckets {
+                // birth dates only grow within a bucket, so checking the ends 
+                // tells if all of them pass or none does
+                let (first, last) = (bucket, bucket + AGE_BUCKET_SECONDS - 1);
+                let passes_all = passes_age(first) && passes_age(last);
+                let passes_none = max_birth_date.is_some_and(|max_birth_date| first >= max_birth_date)
+                               || min_birth_date.is_some_and(|min_birth_date| last <= min_birth_date);
+                if passes_none {
+                    continue;
+                }
+
+                for (_key, &(mark, rater)) in visits.range(range) {
+                    if parameters.gender.is_some_and(|gender| rater.gender != gender) {
+                        continue;
+                    }
+
+                    if !passes_all && !passes_age(rater.birth_date) {
+                        continue;
+                    }
+
+                    sum += mark as u64;
+                    count += 1;
+                }
+            }
+        } else {
+            let users = self.database.users.read_all();
+            for (_key, visit) in visits.range(range) {
+                let user = users.get(&visit.user)
+                    .ok_or(StatusCode::InternalServerError)?;
+                
+                if parameters.gender.is_some() && 
+                   user.gender != parameters.gender.unwrap() {
+                    continue;
+                }
+
+                if !passes_age(user.birth_date) {
+                    continue;
+                }
+
+                sum += visit.mark as u64;
+                count += 1;
+            }
+        }
+
+        Ok(response(sum, count))
+    } 
+
+    // With 'expected_version' a missing entity is still '404 Not Found'
+    #[inline]
+    fn update_entity(&self, request: UpdateEntity, expected_version: Option<u64>) -> Result<Bytes, ApiError> {
+        use request::Optional::Something;
+
+        // the version is checked and bumped under the lock of the entity's shard
+        let check_version = |version| match expected_version {
+            Some(expected_version) if expected_version != version 
+                => Err(ApiError { code: StatusCode::Conflict, message: "version mismatch" }),
+            _ => Ok(())
+        };
+        
+        match request {
+            UpdateEntity::User(id, update) => {
+                // visits are indexed by age and gender of their users too
+                let mut index = match (&update.gender, &update.birth_date) {
+                    (&Optional::Nothing, &Optional::Nothing) => None,
+                    _ => Some(self.database.index.write().unwrap_or_else(PoisonError::into_inner))
+                };
+                let mut emails = match update.email {
+                    Something(_) => Some(self.database.emails.write().unwrap_or_else(PoisonError::into_inner)),
+                    _ => None
+                };
+                let mut users = self.database.users.write(&id);
+                let version = users.version(&id);
+                let user = users.get_mut(&id)
+                    .ok_or_else(ApiError::not_found)?;
+                check_version(version)?;
+
+                if let Something(ref email) = update.email {
+                    if !is_valid_email(email) {
+                        return Err(ApiError::bad_request("invalid email"));
+                    }
+
+                    // setting the current email again is fine
+                    let emails = emails.as_ref().unwrap();
+                    if emails.get(email).is_some_and(|owner| *owner != id) {
+                        return Err(ApiError::bad_request("duplicate email"));
+                    }
+                }
+
+                if let Something(birth_date) = update.birth_date {
+                    if !is_valid_birth_date(birth_date, self.now) {
+                        return Err(ApiError::bad_request("invalid birth date"));
+                    }
+                }
+                
+                if let Something(email) = update.email {
+                    let emails = emails.as_mut().unwrap();
+                    emails.remove(&user.email);
+                    emails.insert(email.clone(), id);
+                    user.email = email;
+                }
+
+                if let Something(first_name) = update.first_name {
+                    user.first_name = first_name;
+                }
 
                 if let Something(last_name) = update.last_name {
                     user.last_name = last_name;
                 }
 
+                let rater = Rater::from(&*user);
                 if let Something(gender) = update.gender {
                     user.gender = gender;
                 }
@@ -216,71 +1167,104 @@ impl Api {
                 if let Something(birth_date) = update.birth_date {
                     user.birth_date = birth_date;
                 }
+
+                let new_rater = Rater::from(&*user);
+                users.bump_version(id);
+                // released before the cache is locked, the index still keeps
+                // the raters from being read until they are changed
+                drop(users);
+
+                if let Some(ref mut index) = index {
+                    index.change_rater(id, rater, new_rater);
+                    // filtered averages depend on the raters
+                    for visit in index.visits_by_user.get(&id).into_iter().flat_map(|visits| visits.values()) {
+                        self.invalidate_average(visit.location);
+                    }
+                }
             },
             UpdateEntity::Location(id, update) => {
-                let location = self.database.locations.get_mut(&id)
-                    .ok_or(StatusCode::NotFound)?;
+                // visits are indexed by country, so changing it needs the index
+                let mut index = match update.country {
+                    Something(_) => Some(self.database.index.write().unwrap_or_else(PoisonError::into_inner)),
+                    Optional::Nothing => None
+                };
+
+                let mut locations = self.database.locations.write(&id);
+                let version = locations.version(&id);
+                let location = locations.get_mut(&id)
+                    .ok_or_else(ApiError::not_found)?;
+                check_version(version)?;
                 
                 if let Something(place) = update.place {
-                    location.place = place;
+                    let place = self.database.intern(place.into());
+                    self.database.release(mem::replace(&mut location.place, place));
                 }
 
                 if let Something(country) = update.country {
-                    location.country = country;
+                    if let Some(ref mut index) = index {
+                        index.change_country(id, &location.country, &country);
+                    }
+                    let country = self.database.intern(country.into());
+                    self.database.release(mem::replace(&mut location.country, country));
                 }
 
                 if let Something(city) = update.city {
-                    location.city = city;
+                    let city = self.database.intern(city.into());
+                    self.database.release(mem::replace(&mut location.city, city));
                 }
 
                 if let Something(distance) = update.distance {
                     location.distance = distance;
                 }
+                locations.bump_version(id);
             },
             UpdateEntity::Visit(id, update) => {
-                let visit = self.database.visits.get_mut(&id)
-                    .ok_or(StatusCode::NotFound)?;
+                let mut index = self.database.index.write().unwrap_or_else(PoisonError::into_inner);
+
+                // visit updates are serialized by the index lock, so the
+                // version doesn't change until the visit is written back
+                let (old_visit, version) = {
+                    let visits = self.database.visits.read(&id);
+                    let visit = visits.get(&id).cloned()
+                        .ok_or_else(ApiError::not_found)?;
+                    (visit, visits.version(&id))
+                };
+                check_version(version)?;
+
+                if let Something(mark) = update.mark {
+                    if !is_valid_mark(mark) {
+                        return Err(ApiError::bad_request("invalid mark"));
+                    }
+                }
+
+                if let Something(visited_at) = update.visited_at {
+                    if !self.is_valid_visited_at(visited_at) {
+                        return Err(ApiError::bad_request("invalid visit time"));
+                    }
+                }
 
                 if let Something(ref location) = update.location {
                     if !self.database.locations.contains_key(location) {
-                        return Err(StatusCode::BadRequest);
+                        return Err(ApiError::bad_request("unknown location"));
                     }
                 }
 
                 if let Something(ref user) = update.user {
                     if !self.database.users.contains_key(user) {
-                        return Err(StatusCode::BadRequest);
+                        return Err(ApiError::bad_request("unknown user"));
                     }
                 }
 
+                let mut visit = old_visit.clone();
                 if let Something(location) = update.location {
-                    self.database.visits_by_location
-                        .get_mut(&visit.location)
-                        .ok_or(StatusCode::InternalServerError)?
-                        .remove(&visit.visited_at);
-
                     visit.location = location;
                 }
 
                 if let Something(user) = update.user {
-                    self.database.visits_by_user
-                        .get_mut(&visit.user)
-                        .ok_or(StatusCode::InternalServerError)?
-                        .remove(&visit.visited_at);
-
                     visit.user = user;
                 }
 
                 if let Something(visited_at) = update.visited_at {
-                    // possibly deleted in previous branch
-                    self.database.visits_by_location
-                        .get_mut(&visit.location)
-                        .map(|visits| visits.remove(&visit.visited_at));
-
-                    self.database.visits_by_user
-                        .get_mut(&visit.user)
-                        .map(|visits| visits.remove(&visit.visited_at));
-                    
                     visit.visited_at = visited_at;
                 }
 
@@ -288,15 +1272,22 @@ impl Api {
                     visit.mark = mark;
                 }
 
-                self.database.visits_by_location
-                    .entry(visit.location)
-                    .or_insert_with(Default::default)
-                    .insert(visit.visited_at, visit.clone());
+                // the visit itself is at the old user and time
+                if self.unique_visit_time && (visit.user, visit.visited_at) != (old_visit.user, old_visit.visited_at)
+                && index.has_visit_at(visit.user, visit.visited_at) {
+                    return Err(ApiError::bad_request("duplicate visit time"));
+                }
 
-                self.database.visits_by_user
-                    .entry(visit.user)
-                    .or_insert_with(Default::default)
-                    .insert(visit.visited_at, visit.clone());
+                // the index is keyed by user, location and time, so the old
+                // entries are found by the old values of all of them
+                let is_indexed = index.remove(&old_visit, &self.country(&old_visit.location), self.rater(&old_visit.user));
+                debug_assert!(is_indexed, "Visit {} is not indexed", id.0);
+                index.insert(&visit, &self.country(&visit.location), self.rater(&visit.user));
+                self.invalidate_average(old_visit.location);
+                self.invalidate_average(visit.location);
+                let mut visits = self.database.visits.write(&id);
+                visits.insert(id, visit);
+                visits.bump_version(id);
             }
         };
 
@@ -304,46 +1295,1869 @@ impl Api {
     }
 
     #[inline]
-    fn create_entity(&mut self, request: CreateEntity) -> Result<Bytes, StatusCode> {
+    fn create_entity(&self, request: CreateEntity) -> Result<Bytes, ApiError> {
         use std::collections::hash_map::Entry;
 
         match request {
             CreateEntity::User(user) => {
-                match self.database.users.entry(user.id) {
-                    Entry::Occupied(_) => return Err(StatusCode::BadRequest),
+                if !is_valid_email(&user.email) {
+                    return Err(ApiError::bad_request("invalid email"));
+                }
+
+                if !is_valid_birth_date(user.birth_date, self.now) {
+                    return Err(ApiError::bad_request("invalid birth date"));
+                }
+
+                let mut emails = self.database.emails.write().unwrap_or_else(PoisonError::into_inner);
+                if emails.contains_key(&user.email) {
+                    return Err(ApiError::bad_request("duplicate email"));
+                }
+
+                let (id, email) = (user.id, user.email.clone());
+                match self.database.users.write(&id).entry(id) {
+                    Entry::Occupied(_) => return Err(ApiError::bad_request("duplicate id")),
                     Entry::Vacant(v) => v.insert(user)
                 };
+                emails.insert(email, id);
             },
-            CreateEntity::Location(location) => {
-                match self.database.locations.entry(location.id) {
-                    Entry::Occupied(_) => return Err(StatusCode::BadRequest),
+            CreateEntity::Location(mut location) => {
+                self.database.intern_location(&mut location);
+                match self.database.locations.write(&location.id).entry(location.id) {
+                    Entry::Occupied(_) => {
+                        self.database.release_location(location);
+                        return Err(ApiError::bad_request("duplicate id"));
+                    },
                     Entry::Vacant(v) => v.insert(location)
                 };
             },
             CreateEntity::Visit(visit) => {
+                if !is_valid_mark(visit.mark) {
+                    return Err(ApiError::bad_request("invalid mark"));
+                }
+
+                if !self.is_valid_visited_at(visit.visited_at) {
+                    return Err(ApiError::bad_request("invalid visit time"));
+                }
+
+                let mut index = self.database.index.write().unwrap_or_else(PoisonError::into_inner);
+
                 if !self.database.users.contains_key(&visit.user) {
-                    return Err(StatusCode::BadRequest);
+                    return Err(ApiError::bad_request("unknown user"));
                 }
 
                 if !self.database.locations.contains_key(&visit.location) {
-                    return Err(StatusCode::BadRequest);
+                    return Err(ApiError::bad_request("unknown location"));
                 }
 
-                match self.database.visits.entry(visit.id) {
-                    Entry::Occupied(_) => return Err(StatusCode::BadRequest),
+                if self.unique_visit_time && index.has_visit_at(visit.user, visit.visited_at) {
+                    return Err(ApiError::bad_request("duplicate visit time"));
+                }
+
+                match self.database.visits.write(&visit.id).entry(visit.id) {
+                    Entry::Occupied(_) => return Err(ApiError::bad_request("duplicate id")),
                     Entry::Vacant(v) => v.insert(visit.clone())
                 };
 
-                self.database.visits_by_location.entry(visit.location)
-                    .or_insert_with(Default::default)
-                    .insert(visit.visited_at, visit.clone());
+                index.insert(&visit, &self.country(&visit.location), self.rater(&visit.user));
+                self.invalidate_average(visit.location);
+            },
+            CreateEntity::UserBatch(users) => {
+                let mut ids = HashSet::with_capacity(users.len());
+                let mut batch_emails = HashSet::with_capacity(users.len());
+                for user in &users {
+                    if !is_valid_email(&user.email) {
+                        return Err(ApiError::bad_request("invalid email"));
+                    }
+
+                    if !is_valid_birth_date(user.birth_date, self.now) {
+                        return Err(ApiError::bad_request("invalid birth date"));
+                    }
+
+                    if !ids.insert(user.id) {
+                        return Err(ApiError::bad_request("duplicate id"));
+                    }
+
+                    if !batch_emails.insert(user.email.as_str()) {
+                        return Err(ApiError::bad_request("duplicate email"));
+                    }
+                }
+
+                let mut emails = self.database.emails.write().unwrap_or_else(PoisonError::into_inner);
+                if users.iter().any(|user| emails.contains_key(&user.email)) {
+                    return Err(ApiError::bad_request("duplicate email"));
+                }
+
+                let mut shards = self.database.users.write_all();
+                if users.iter().any(|user| shards.contains_key(&user.id)) {
+                    return Err(ApiError::bad_request("duplicate id"));
+                }
+
+                for user in users {
+                    emails.insert(user.email.clone(), user.id);
+                    shards.insert(user.id, user);
+                }
+            },
+            CreateEntity::LocationBatch(mut locations) => {
+                let mut ids = HashSet::with_capacity(locations.len());
+                if !locations.iter().all(|location| ids.insert(location.id)) {
+                    return Err(ApiError::bad_request("duplicate id"));
+                }
+
+                // interned before the shards are locked
+                for location in &mut locations {
+                    self.database.intern_location(location);
+                }
+
+                let mut shards = self.database.locations.write_all();
+                if locations.iter().any(|location| shards.contains_key(&location.id)) {
+                    drop(shards);
+                    for location in locations {
+                        self.database.release_location(location);
+                    }
+                    return Err(ApiError::bad_request("duplicate id"));
+                }
+
+                for location in locations {
+                    shards.insert(location.id, location);
+                }
+            },
+            CreateEntity::VisitBatch(visits) => {
+                let mut ids = HashSet::with_capacity(visits.len());
+                let mut times = HashSet::new();
+                for visit in &visits {
+                    if !is_valid_mark(visit.mark) {
+                        return Err(ApiError::bad_request("invalid mark"));
+                    }
+
+                    if !self.is_valid_visited_at(visit.visited_at) {
+                        return Err(ApiError::bad_request("invalid visit time"));
+                    }
+
+                    if !ids.insert(visit.id) {
+                        return Err(ApiError::bad_request("duplicate id"));
+                    }
+
+                    if self.unique_visit_time && !times.insert((visit.user, visit.visited_at)) {
+                        return Err(ApiError::bad_request("duplicate visit time"));
+                    }
+                }
+
+                let mut index = self.database.index.write().unwrap_or_else(PoisonError::into_inner);
+                if self.unique_visit_time && visits.iter().any(|visit| index.has_visit_at(visit.user, visit.visited_at)) {
+                    return Err(ApiError::bad_request("duplicate visit time"));
+                }
+
+                let countries = visits.iter()
+                    .map(|visit| {
+                        let rater = self.rater(&visit.user)
+                            .ok_or_else(|| ApiError::bad_request("unknown user"))?;
+
+                        self.database.locations.read(&visit.location)
+                            .get(&visit.location)
+                            .map(|location| (location.country.clone(), rater))
+                            .ok_or_else(|| ApiError::bad_request("unknown location"))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                {
+                    let mut shards = self.database.visits.write_all();
+                    if visits.iter().any(|visit| shards.contains_key(&visit.id)) {
+                        return Err(ApiError::bad_request("duplicate id"));
+                    }
+
+                    for visit in &visits {
+                        shards.insert(visit.id, visit.clone());
+                    }
+                }
+
+                for (visit, &(ref country, rater)) in visits.iter().zip(&countries) {
+                    index.insert(visit, country, Some(rater));
+                    self.invalidate_average(visit.location);
+                }
+            }
+        };
+
+        Ok(Bytes::from_static(POST_RESPONSE))
+    }
+
+    #[inline]
+    fn delete_entity(&self, request: DeleteEntity, cascade: bool) -> Result<Bytes, ApiError> {
+        let mut index = self.database.index.write().unwrap_or_else(PoisonError::into_inner);
+
+        match request {
+            DeleteEntity::User(id) => {
+                if !self.database.users.contains_key(&id) {
+                    return Err(ApiError::not_found());
+                }
 
-                self.database.visits_by_user.entry(visit.user)
-                    .or_insert_with(Default::default)
-                    .insert(visit.visited_at, visit);
+                let visits: Vec<Visit> = index.visits_by_user.get(&id)
+                    .map_or_else(Vec::new, |visits| visits.values().cloned().collect());
+                if !visits.is_empty() && !cascade {
+                    return Err(ApiError::bad_request("has visits"));
+                }
+
+                let rater = self.rater(&id);
+                {
+                    let mut emails = self.database.emails.write().unwrap_or_else(PoisonError::into_inner);
+                    if let Some(user) = self.database.users.write(&id).remove(&id) {
+                        emails.remove(&user.email);
+                    }
+                }
+                for visit in visits {
+                    self.database.visits.write(&visit.id).remove(&visit.id);
+                    index.remove(&visit, &self.country(&visit.location), rater);
+                    self.invalidate_average(visit.location);
+                }
+                index.visits_by_user.remove(&id);
+                index.visits_by_user_country.remove(&id);
+            },
+            DeleteEntity::Location(id) => {
+                if !self.database.locations.contains_key(&id) {
+                    return Err(ApiError::not_found());
+                }
+
+                let visits: Vec<Visit> = index.visits_by_location.get(&id)
+                    .map_or_else(Vec::new, |visits| visits.values().cloned().collect());
+                if !visits.is_empty() && !cascade {
+                    return Err(ApiError::bad_request("has visits"));
+                }
+
+                {
+                    // a copy of the name, which would keep it in the pool
+                    let country = self.country(&id);
+                    for visit in visits {
+                        self.database.visits.write(&visit.id).remove(&visit.id);
+                        index.remove(&visit, &country, self.rater(&visit.user));
+                    }
+                }
+                index.visits_by_location.remove(&id);
+                let location = self.database.locations.write(&id).remove(&id);
+                if let Some(location) = location {
+                    self.database.release_location(location);
+                }
+                self.invalidate_average(id);
+            },
+            DeleteEntity::Visit(id) => {
+                let visit = self.database.visits.write(&id).remove(&id)
+                    .ok_or_else(ApiError::not_found)?;
+
+                index.remove(&visit, &self.country(&visit.location), self.rater(&visit.user));
+                self.invalidate_average(visit.location);
             }
         };
 
         Ok(Bytes::from_static(POST_RESPONSE))
     }
+
+    #[inline]
+    fn is_valid_visited_at(&self, visited_at: Timestamp) -> bool {
+        visited_at >= self.min_visited_at && visited_at <= self.max_visited_at
+    }
+
+    // to be called with the index locked for writing and no shard locked
+    #[inline]
+    fn invalidate_average(&self, location: LocationId) {
+        if let Some(ref cache) = self.avg_cache {
+            cache.invalidate(location);
+        }
+    }
+
+    #[inline]
+    fn rater(&self, id: &UserId) -> Option<Rater> {
+        self.database.users.read(id).get(id).map(Rater::from)
+    }
+
+    // country of the location or an empty string if there is no such location
+    #[inline]
+    fn country(&self, id: &LocationId) -> Name {
+        self.database.locations.read(id).get(id)
+            .map_or_else(|| Name::from(""), |location| location.country.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::Database;
+
+    fn api() -> Api {
+        let api = Api::new(Database::new(4));
+
+        let user = User {
+            id: UserId(1),
+            email: "robosen@icloud.com".to_string(),
+            first_name: "Данила".to_string(),
+            last_name: "Стамленский".to_string(),
+            gender: Gender::Male,
+            birth_date: 345081600
+        };
+        api.do_post(PostRequest::CreateEntity(CreateEntity::User(user))).unwrap();
+
+        let location = Location {
+            id: LocationId(1),
+            place: "Набережная".into(),
+            country: "Россия".into(),
+            city: "Москва".into(),
+            distance: 10
+        };
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location))).unwrap();
+
+        api
+    }
+
+    fn visit(id: u32, visited_at: Timestamp, mark: u8) -> Visit {
+        Visit { id: VisitId(id), location: LocationId(1), user: UserId(1), visited_at, mark }
+    }
+
+    fn get(api: &Api, request: GetRequest) -> Result<String, StatusCode> {
+        api.do_get(request)
+            .map(|bytes| String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn visits_with_equal_timestamps() {
+        let api = api();
+        for v in [visit(1, 1000, 5), visit(2, 1000, 2)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let visits = get(&api, GetRequest::GetVisits(UserId(1), Default::default()));
+        assert_eq!(visits.unwrap(), 
+            r#"{"visits":[{"mark":5,"visited_at":1000,"place":"Набережная"},{"mark":2,"visited_at":1000,"place":"Набережная"}]}"#);
+
+        let avg = get(&api, GetRequest::GetAverageLocationRating(LocationId(1), Default::default()));
+        assert_eq!(avg.unwrap(), r#"{"avg":3.50000}"#);
+    }
+
+    #[test]
+    fn visits_on_date_boundaries_are_excluded() {
+        let api = api();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4), visit(3, 3000, 3)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let parameters = GetVisits { from_date: Some(1000), to_date: Some(3000), ..Default::default() };
+        let visits = get(&api, GetRequest::GetVisits(UserId(1), parameters));
+        assert_eq!(visits.unwrap(), 
+            r#"{"visits":[{"mark":4,"visited_at":2000,"place":"Набережная"}]}"#);
+    }
+
+    #[test]
+    fn delete_visit() {
+        let api = api();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 1)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let request = DeleteRequest { entity: DeleteEntity::Visit(VisitId(2)), cascade: false };
+        assert!(api.do_delete(request).is_ok());
+        assert!(!api.database.visits.contains_key(&VisitId(2)));
+
+        let avg = get(&api, GetRequest::GetAverageLocationRating(LocationId(1), Default::default()));
+        assert_eq!(avg.unwrap(), r#"{"avg":5.00000}"#);
+
+        let request = DeleteRequest { entity: DeleteEntity::Visit(VisitId(2)), cascade: false };
+        assert_eq!(api.do_delete(request), Err(ApiError::not_found()));
+    }
+
+    #[test]
+    fn delete_user_with_visits() {
+        let api = api();
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit(1, 1000, 5)))).unwrap();
+
+        let request = DeleteRequest { entity: DeleteEntity::User(UserId(1)), cascade: false };
+        assert_eq!(api.do_delete(request), Err(ApiError::bad_request("has visits")));
+        assert!(api.database.users.contains_key(&UserId(1)));
+
+        let request = DeleteRequest { entity: DeleteEntity::User(UserId(1)), cascade: true };
+        assert!(api.do_delete(request).is_ok());
+        assert!(api.database.users.is_empty());
+        assert!(api.database.visits.is_empty());
+
+        let avg = get(&api, GetRequest::GetAverageLocationRating(LocationId(1), Default::default()));
+        assert_eq!(avg.unwrap(), r#"{"avg":0}"#);
+
+        let request = DeleteRequest { entity: DeleteEntity::Location(LocationId(1)), cascade: false };
+        assert!(api.do_delete(request).is_ok());
+        assert!(api.database.locations.is_empty());
+    }
+
+    #[test]
+    fn visit_mark_range() {
+        let api = api();
+        let create = |visit| PostRequest::CreateEntity(CreateEntity::Visit(visit));
+        assert_eq!(api.do_post(create(visit(1, 1000, 6))), Err(ApiError::bad_request("invalid mark")));
+        assert!(api.database.visits.is_empty());
+        assert_eq!(api.do_post(create(visit(1, 1000, 5))), Ok(Bytes::from_static(b"{}")));
+
+        let update = |mark| {
+            let update = VisitUpdate {
+                location: Optional::Nothing,
+                user: Optional::Nothing,
+                visited_at: Optional::Nothing,
+                mark: Optional::Something(mark)
+            };
+            PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(1), update))
+        };
+        assert_eq!(api.do_post(update(6)), Err(ApiError::bad_request("invalid mark")));
+        assert_eq!(api.database.visits.read(&VisitId(1))[&VisitId(1)].mark, 5);
+        assert_eq!(api.do_post(update(0)), Ok(Bytes::from_static(b"{}")));
+        assert_eq!(api.database.visits.read(&VisitId(1))[&VisitId(1)].mark, 0);
+    }
+
+    // the index as loading would build it from the stored visits, ignoring
+    // the empty maps removals leave behind
+    fn assert_index_is_rebuilt(api: &Api) {
+        use std::collections::HashMap;
+        use database::Index;
+
+        let mut rebuilt = Index::default();
+        for visit in api.database.visits.read_all().values() {
+            rebuilt.insert(visit, &api.country(&visit.location), api.rater(&visit.user));
+        }
+
+        let index = api.database.index.read().unwrap();
+        let by_user: HashMap<_, _> = index.visits_by_user.iter().filter(|&(_, visits)| !visits.is_empty()).collect();
+        assert_eq!(by_user, rebuilt.visits_by_user.iter().collect());
+        let by_location: HashMap<_, _> = index.visits_by_location.iter().filter(|&(_, visits)| !visits.is_empty()).collect();
+        assert_eq!(by_location, rebuilt.visits_by_location.iter().collect());
+        let marks: HashMap<_, _> = index.marks_by_location.iter().filter(|&(_, marks)| marks.count != 0).collect();
+        assert_eq!(marks, rebuilt.marks_by_location.iter().collect());
+        let countries: HashMap<_, _> = index.visits_by_user_country.iter().filter(|&(_, countries)| !countries.is_empty()).collect();
+        assert_eq!(countries, rebuilt.visits_by_user_country.iter().collect());
+        let aged = |index: &Index| -> Vec<_> {
+            let mut aged: Vec<_> = index.visits_by_location_age.iter()
+                .flat_map(|(&location, buckets)| buckets.iter()
+                    .flat_map(move |(&bucket, visits)| visits.iter().map(move |(&key, &value)| (location, bucket, key, value))))
+                .collect();
+            aged.sort_by_key(|&(location, bucket, key, _)| (location, bucket, key));
+            aged
+        };
+        assert_eq!(aged(&index), aged(&rebuilt));
+    }
+
+    #[test]
+    fn visit_update_combinations() {
+        // every subset of the fields changed at once
+        for fields in 0..16 {
+            let api = api();
+            let user = User {
+                id: UserId(2),
+                email: "tameerne@yandex.ru".to_string(),
+                first_name: "Аня".to_string(),
+                last_name: "Шишкина".to_string(),
+                gender: Gender::Female,
+                birth_date: 0
+            };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::User(user))).unwrap();
+            let location = Location { id: LocationId(2), place: "Парк".into(), country: "Германия".into(), city: "Берлин".into(), distance: 5 };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location))).unwrap();
+            for v in [visit(1, 1000, 5), visit(2, 2000, 4)] {
+                api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+            }
+
+            fn optional<T>(is_changed: bool, value: T) -> Optional<T> {
+                if is_changed { Optional::Something(value) } else { Optional::Nothing }
+            }
+            let changed = |bit: u32| fields & (1 << bit) != 0;
+            let update = VisitUpdate {
+                location: optional(changed(0), LocationId(2)),
+                user: optional(changed(1), UserId(2)),
+                visited_at: optional(changed(2), 1500),
+                mark: optional(changed(3), 1)
+            };
+            api.do_post(PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(1), update))).unwrap();
+
+            let expected = Visit {
+                id: VisitId(1),
+                location: if changed(0) { LocationId(2) } else { LocationId(1) },
+                user: if changed(1) { UserId(2) } else { UserId(1) },
+                visited_at: if changed(2) { 1500 } else { 1000 },
+                mark: if changed(3) { 1 } else { 5 }
+            };
+            assert_eq!(api.database.visits.read(&VisitId(1))[&VisitId(1)], expected, "fields {:04b}", fields);
+            assert_index_is_rebuilt(&api);
+
+            // the other visit is where it was
+            let visits = get(&api, GetRequest::GetVisits(UserId(1), Default::default())).unwrap();
+            assert!(visits.contains(r#"{"mark":4,"visited_at":2000,"place":"Набережная"}"#));
+            let female = GetAverageLocationRating { gender: Some(Gender::Female), ..Default::default() };
+            let avg = get(&api, GetRequest::GetAverageLocationRating(expected.location, female)).unwrap();
+            if changed(1) {
+                assert_eq!(avg, format!(r#"{{"avg":{}.00000}}"#, expected.mark), "fields {:04b}", fields);
+            } else {
+                assert_eq!(avg, r#"{"avg":0}"#, "fields {:04b}", fields);
+            }
+        }
+    }
+
+    #[test]
+    fn user_email_format() {
+        let api = api();
+        let user = User {
+            id: UserId(2),
+            email: "not-an-email".to_string(),
+            first_name: "Аня".to_string(),
+            last_name: "Шишкина".to_string(),
+            gender: Gender::Female,
+            birth_date: -1571356800
+        };
+        let create = |user| PostRequest::CreateEntity(CreateEntity::User(user));
+        assert_eq!(api.do_post(create(user.clone())), Err(ApiError::bad_request("invalid email")));
+        assert_eq!(api.do_post(create(User { email: "".to_string(), ..user.clone() })), 
+                   Err(ApiError::bad_request("invalid email")));
+        assert!(api.do_post(create(User { email: "tameerne@yandex.ru".to_string(), ..user })).is_ok());
+
+        let update = |email: &str| {
+            let update = UserUpdate {
+                email: Optional::Something(email.to_string()),
+                first_name: Optional::Nothing,
+                last_name: Optional::Nothing,
+                gender: Optional::Nothing,
+                birth_date: Optional::Nothing
+            };
+            PostRequest::UpdateEntity(UpdateEntity::User(UserId(2), update))
+        };
+        assert_eq!(api.do_post(update("tameerne")), Err(ApiError::bad_request("invalid email")));
+        assert_eq!(api.database.users.read(&UserId(2))[&UserId(2)].email, "tameerne@yandex.ru");
+        assert!(api.do_post(update("tameerne@mail.ru")).is_ok());
+        assert_eq!(api.database.users.read(&UserId(2))[&UserId(2)].email, "tameerne@mail.ru");
+    }
+
+    #[test]
+    fn empty_update() {
+        let api = api();
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit(1, 1000, 5)))).unwrap();
+        let before = (get(&api, GetRequest::GetEntity(GetEntity::User(UserId(1)))),
+                      get(&api, GetRequest::GetEntity(GetEntity::Visit(VisitId(1)))));
+
+        let user = serde_json::from_str("{}").unwrap();
+        let visit = serde_json::from_str("{}").unwrap();
+        for update in [UpdateEntity::User(UserId(1), user), UpdateEntity::Visit(VisitId(1), visit)] {
+            assert_eq!(api.do_post(PostRequest::UpdateEntity(update)), Ok(Bytes::from_static(POST_RESPONSE)));
+        }
+        assert_eq!((get(&api, GetRequest::GetEntity(GetEntity::User(UserId(1)))),
+                    get(&api, GetRequest::GetEntity(GetEntity::Visit(VisitId(1))))), before);
+        assert_index_is_rebuilt(&api);
+
+        // a missing entity is still an error
+        let user = serde_json::from_str("{}").unwrap();
+        let missing = api.do_post(PostRequest::UpdateEntity(UpdateEntity::User(UserId(2), user)));
+        assert_eq!(missing.unwrap_err().code, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn user_with_visits() {
+        let api = api();
+        for &(id, visited_at, mark) in &[(1, 300, 5), (2, 100, 3), (3, 200, 4)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit(id, visited_at, mark)))).unwrap();
+        }
+        let user = |request| get(&api, GetRequest::GetEntity(request));
+
+        let plain = r#"{"id":1,"email":"robosen@icloud.com","first_name":"Данила","last_name":"Стамленский","gender":"m","birth_date":345081600}"#;
+        assert_eq!(user(GetEntity::User(UserId(1))).unwrap(), plain);
+        assert_eq!(user(GetEntity::UserWithVisits(UserId(1), None)).unwrap(), 
+                   format!("{},{}", &plain[..plain.len() - 1], concat!(r#""visits":["#,
+                           r#"{"mark":3,"visited_at":100,"place":"Набережная"},"#,
+                           r#"{"mark":4,"visited_at":200,"place":"Набережная"},"#,
+                           r#"{"mark":5,"visited_at":300,"place":"Набережная"}]}"#)));
+        assert!(user(GetEntity::UserWithVisits(UserId(1), Some(1))).unwrap()
+            .ends_with(r#""visits":[{"mark":3,"visited_at":100,"place":"Набережная"}]}"#));
+        assert!(user(GetEntity::UserWithVisits(UserId(1), Some(0))).unwrap().ends_with(r#""visits":[]}"#));
+        assert_eq!(user(GetEntity::UserWithVisits(UserId(2), None)), Err(StatusCode::NotFound));
+    }
+
+    #[test]
+    fn conditional_updates() {
+        let api = api();
+        let update = |id: u32, first_name: &str| UpdateEntity::User(UserId(id), UserUpdate {
+            email: Optional::Nothing,
+            first_name: Optional::Something(first_name.to_string()),
+            last_name: Optional::Nothing,
+            gender: Optional::Nothing,
+            birth_date: Optional::Nothing
+        });
+        let version = || api.do_get_versioned(GetEntity::User(UserId(1))).unwrap().1;
+
+        assert_eq!(version(), 0);
+        assert!(api.do_post(PostRequest::UpdateEntityIfVersion(update(1, "Аня"), 0)).is_ok());
+        assert_eq!(version(), 1);
+        // unconditional updates bump the version too
+        assert!(api.do_post(PostRequest::UpdateEntity(update(1, "Катя"))).is_ok());
+        assert_eq!(version(), 2);
+
+        assert_eq!(api.do_post(PostRequest::UpdateEntityIfVersion(update(1, "Оля"), 1)),
+                   Err(ApiError { code: StatusCode::Conflict, message: "version mismatch" }));
+        assert_eq!(api.database.users.read(&UserId(1))[&UserId(1)].first_name, "Катя");
+        assert_eq!(version(), 2);
+
+        assert_eq!(api.do_post(PostRequest::UpdateEntityIfVersion(update(2, "Оля"), 5)), Err(ApiError::not_found()));
+    }
+
+    #[test]
+    fn unused_names_are_released() {
+        let api = api();
+        let is_pooled = |name: &str| api.database.names.lock().unwrap().contains(name);
+        let location = |id, place: &str| Location {
+            id: LocationId(id),
+            place: place.into(),
+            country: "Россия".into(),
+            city: "Москва".into(),
+            distance: 1
+        };
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location(2, "Парк")))).unwrap();
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location(3, "Парк")))).unwrap();
+
+        let update = serde_json::from_str(r#"{"place":"Сквер","city":"Тверь"}"#).unwrap();
+        api.do_post(PostRequest::UpdateEntity(UpdateEntity::Location(LocationId(2), update))).unwrap();
+        // still the place of location 3
+        assert!(is_pooled("Парк"));
+        assert!(is_pooled("Сквер"));
+
+        let update = serde_json::from_str(r#"{"place":"Сквер"}"#).unwrap();
+        api.do_post(PostRequest::UpdateEntity(UpdateEntity::Location(LocationId(3), update))).unwrap();
+        assert!(!is_pooled("Парк"));
+
+        // rejected locations don't stay in the pool either
+        let duplicate = CreateEntity::Location(location(2, "Пляж"));
+        assert!(api.do_post(PostRequest::CreateEntity(duplicate)).is_err());
+        assert!(!is_pooled("Пляж"));
+
+        api.do_delete(DeleteRequest { entity: DeleteEntity::Location(LocationId(2)), cascade: false }).unwrap();
+        assert!(!is_pooled("Тверь"));
+        assert!(is_pooled("Сквер"));
+        api.do_delete(DeleteRequest { entity: DeleteEntity::Location(LocationId(3)), cascade: false }).unwrap();
+        assert!(!is_pooled("Сквер"));
+        assert!(is_pooled("Россия"));
+    }
+
+    #[test]
+    fn updates_lock_only_their_shard() {
+        use std::sync::Arc;
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let api = Arc::new(api());
+        let (done, is_done) = mpsc::channel();
+        let users = api.database.users.write(&UserId(1));
+
+        // blocked on the shard of the user until it's released
+        let user_update = {
+            let api = api.clone();
+            thread::spawn(move || {
+                let update = serde_json::from_str(r#"{"first_name":"Аня"}"#).unwrap();
+                api.do_post(PostRequest::UpdateEntityIfVersion(UpdateEntity::User(UserId(1), update), 0)).unwrap();
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        let location_update = {
+            let api = api.clone();
+            thread::spawn(move || {
+                let update = serde_json::from_str(r#"{"distance":5}"#).unwrap();
+                api.do_post(PostRequest::UpdateEntity(UpdateEntity::Location(LocationId(1), update))).unwrap();
+                done.send(()).unwrap();
+            })
+        };
+        assert!(is_done.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        drop(users);
+        user_update.join().unwrap();
+        location_update.join().unwrap();
+        assert_eq!(api.do_get_versioned(GetEntity::User(UserId(1))).unwrap().1, 1);
+        assert_eq!(api.do_get_versioned(GetEntity::Location(LocationId(1))).unwrap().1, 1);
+    }
+
+    #[test]
+    fn unique_emails() {
+        let api = api();
+        let user = |id: u32, email: &str| User {
+            id: UserId(id),
+            email: email.to_string(),
+            first_name: "Аня".to_string(),
+            last_name: "Шишкина".to_string(),
+            gender: Gender::Female,
+            birth_date: -1571356800
+        };
+        let create = |user| api.do_post(PostRequest::CreateEntity(CreateEntity::User(user)));
+        let update = |id: u32, email: &str| {
+            let update = UserUpdate {
+                email: Optional::Something(email.to_string()),
+                first_name: Optional::Nothing,
+                last_name: Optional::Nothing,
+                gender: Optional::Nothing,
+                birth_date: Optional::Nothing
+            };
+            api.do_post(PostRequest::UpdateEntity(UpdateEntity::User(UserId(id), update)))
+        };
+
+        assert_eq!(create(user(2, "robosen@icloud.com")), Err(ApiError::bad_request("duplicate email")));
+        assert!(create(user(2, "tameerne@yandex.ru")).is_ok());
+        // a failed create doesn't take the email
+        assert_eq!(create(user(2, "tameerne@mail.ru")), Err(ApiError::bad_request("duplicate id")));
+        assert!(create(user(3, "tameerne@mail.ru")).is_ok());
+
+        assert_eq!(update(2, "robosen@icloud.com"), Err(ApiError::bad_request("duplicate email")));
+        assert_eq!(api.database.users.read(&UserId(2))[&UserId(2)].email, "tameerne@yandex.ru");
+        assert!(update(2, "tameerne@yandex.ru").is_ok());
+
+        // the old email is released
+        assert!(update(1, "robosen@gmail.com").is_ok());
+        assert!(update(2, "robosen@icloud.com").is_ok());
+        assert_eq!(create(user(4, "robosen@gmail.com")), Err(ApiError::bad_request("duplicate email")));
+
+        let request = DeleteRequest { entity: DeleteEntity::User(UserId(3)), cascade: false };
+        api.do_delete(request).unwrap();
+        assert!(create(user(4, "tameerne@mail.ru")).is_ok());
+
+        let batch = |users| api.do_post(PostRequest::CreateEntity(CreateEntity::UserBatch(users)));
+        assert_eq!(batch(vec![user(5, "a@mail.ru"), user(6, "a@mail.ru")]), Err(ApiError::bad_request("duplicate email")));
+        assert_eq!(batch(vec![user(5, "a@mail.ru"), user(6, "tameerne@mail.ru")]), Err(ApiError::bad_request("duplicate email")));
+        assert!(api.database.emails.read().unwrap().get("a@mail.ru").is_none());
+    }
+
+    #[test]
+    fn user_birth_date_range() {
+        let api = api();
+        let user = |id: u32, birth_date: Timestamp| User {
+            id: UserId(id),
+            email: format!("user{}@mail.ru", id),
+            first_name: "Иван".to_string(),
+            last_name: "Петров".to_string(),
+            gender: Gender::Male,
+            birth_date
+        };
+        let create = |user: User| api.do_post(PostRequest::CreateEntity(CreateEntity::User(user)));
+
+        assert_eq!(create(user(2, api.now + 86400)), Err(ApiError::bad_request("invalid birth date")));
+        assert_eq!(create(user(3, MIN_BIRTH_DATE - 1)), Err(ApiError::bad_request("invalid birth date")));
+        assert!(create(user(4, -1000000000)).is_ok());
+
+        let update = |birth_date: Timestamp| UserUpdate {
+            email: Optional::Nothing,
+            first_name: Optional::Nothing,
+            last_name: Optional::Nothing,
+            gender: Optional::Nothing,
+            birth_date: Optional::Something(birth_date)
+        };
+        let update_user = |birth_date| 
+            api.do_post(PostRequest::UpdateEntity(UpdateEntity::User(UserId(1), update(birth_date))));
+
+        assert_eq!(update_user(api.now + 86400), Err(ApiError::bad_request("invalid birth date")));
+        assert!(update_user(500000000).is_ok());
+        assert!(get(&api, GetRequest::GetEntity(GetEntity::User(UserId(1)))).unwrap()
+            .contains("\"birth_date\":500000000"));
+    }
+
+    #[test]
+    fn bulk_create_users() {
+        let api = api();
+        let user = |id: u32, email: &str| User {
+            id: UserId(id),
+            email: email.to_string(),
+            first_name: "Иван".to_string(),
+            last_name: "Петров".to_string(),
+            gender: Gender::Male,
+            birth_date: 0
+        };
+        let create = |users: Vec<User>| 
+            api.do_post(PostRequest::CreateEntity(CreateEntity::UserBatch(users)));
+        let exists = |id: u32| get(&api, GetRequest::GetEntity(GetEntity::User(UserId(id)))).is_ok();
+
+        assert_eq!(create(vec![user(2, "a@mail.ru"), user(3, "invalid")]), 
+                   Err(ApiError::bad_request("invalid email")));
+        assert_eq!(create(vec![user(2, "a@mail.ru"), user(2, "b@mail.ru")]), 
+                   Err(ApiError::bad_request("duplicate id")));
+        assert_eq!(create(vec![user(2, "a@mail.ru"), user(1, "b@mail.ru")]), 
+                   Err(ApiError::bad_request("duplicate id")));
+        assert!(!exists(2));
+
+        assert!(create(vec![user(2, "a@mail.ru"), user(3, "b@mail.ru")]).is_ok());
+        assert!(exists(2) && exists(3));
+    }
+
+    #[test]
+    fn bulk_create_visits() {
+        let api = api();
+        let create = |visits: Vec<Visit>| 
+            api.do_post(PostRequest::CreateEntity(CreateEntity::VisitBatch(visits)));
+
+        let bad_location = Visit { location: LocationId(2), ..visit(3, 30, 1) };
+        assert_eq!(create(vec![visit(1, 10, 5), visit(2, 20, 4), bad_location]), 
+                   Err(ApiError::bad_request("unknown location")));
+        assert_eq!(create(vec![visit(1, 10, 5), visit(2, 20, 6)]), Err(ApiError::bad_request("invalid mark")));
+        assert_eq!(get(&api, GetRequest::GetVisits(UserId(1), Default::default())).unwrap(),
+                   r#"{"visits":[]}"#);
+
+        assert!(create(vec![visit(1, 10, 5), visit(2, 20, 4)]).is_ok());
+        assert_eq!(get(&api, GetRequest::GetVisits(UserId(1), Default::default())).unwrap(),
+                   r#"{"visits":[{"mark":5,"visited_at":10,"place":"Набережная"},{"mark":4,"visited_at":20,"place":"Набережная"}]}"#);
+        assert_eq!(get(&api, GetRequest::GetAverageLocationRating(LocationId(1), Default::default())).unwrap(),
+                   r#"{"avg":4.50000}"#);
+    }
+
+    #[test]
+    fn visits_limit() {
+        let api = api();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4), visit(3, 3000, 3)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let visits = |limit| {
+            let parameters = GetVisits { limit: Some(limit), ..Default::default() };
+            get(&api, GetRequest::GetVisits(UserId(1), parameters)).unwrap()
+        };
+
+        let all = r#"{"visits":[{"mark":5,"visited_at":1000,"place":"Набережная"},{"mark":4,"visited_at":2000,"place":"Набережная"},{"mark":3,"visited_at":3000,"place":"Набережная"}]}"#;
+        assert_eq!(visits(0), r#"{"visits":[]}"#);
+        assert_eq!(visits(2), r#"{"visits":[{"mark":5,"visited_at":1000,"place":"Набережная"},{"mark":4,"visited_at":2000,"place":"Набережная"}],"next":"2000:2"}"#);
+        assert_eq!(visits(3), all);
+        assert_eq!(visits(10), all);
+    }
+
+    #[test]
+    fn visited_at_bounds() {
+        let mut api = api();
+        api.min_visited_at = DEFAULT_MIN_VISITED_AT;
+        api.max_visited_at = DEFAULT_MAX_VISITED_AT;
+        let create = |v| PostRequest::CreateEntity(CreateEntity::Visit(v));
+        let invalid = Err(ApiError::bad_request("invalid visit time"));
+
+        assert!(api.do_post(create(visit(1, 1223268286, 5))).is_ok());
+        assert!(api.do_post(create(visit(2, DEFAULT_MAX_VISITED_AT, 5))).is_ok());
+        assert_eq!(api.do_post(create(visit(3, DEFAULT_MAX_VISITED_AT + 1, 5))), invalid);
+        assert_eq!(api.do_post(create(visit(3, Timestamp::MAX, 5))), invalid);
+        assert_eq!(api.do_post(create(visit(3, DEFAULT_MIN_VISITED_AT - 1, 5))), invalid);
+        let batch = vec![visit(3, 1223268286, 5), visit(4, Timestamp::MAX, 5)];
+        assert_eq!(api.do_post(PostRequest::CreateEntity(CreateEntity::VisitBatch(batch))), invalid);
+        assert!(!api.database.visits.contains_key(&VisitId(3)));
+
+        let update = |visited_at| {
+            let update = VisitUpdate {
+                location: Optional::Nothing,
+                user: Optional::Nothing,
+                visited_at: Optional::Something(visited_at),
+                mark: Optional::Nothing
+            };
+            PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(1), update))
+        };
+        assert_eq!(api.do_post(update(Timestamp::MAX)), invalid);
+        assert_eq!(api.database.visits.read(&VisitId(1))[&VisitId(1)].visited_at, 1223268286);
+        assert!(api.do_post(update(1300000000)).is_ok());
+        assert_index_is_rebuilt(&api);
+    }
+
+    #[test]
+    fn unique_visit_time() {
+        let create = |api: &Api, v: Visit| api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v)));
+        let update = |api: &Api, id, user: Optional<UserId>, visited_at: Optional<Timestamp>| {
+            let update = VisitUpdate { location: Optional::Nothing, user, visited_at, mark: Optional::Something(1) };
+            api.do_post(PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(id), update)))
+        };
+        let duplicate = Err(ApiError::bad_request("duplicate visit time"));
+
+        for &unique_visit_time in &[false, true] {
+            let mut api = api();
+            api.unique_visit_time = unique_visit_time;
+            let user = User { id: UserId(2), email: "tameerne@mail.ru".to_string(), ..api.database.users.read(&UserId(1))[&UserId(1)].clone() };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::User(user))).unwrap();
+            create(&api, visit(1, 1000, 5)).unwrap();
+            create(&api, visit(2, 2000, 5)).unwrap();
+            create(&api, Visit { user: UserId(2), ..visit(3, 3000, 5) }).unwrap();
+
+            let expected = |result: Result<Bytes, ApiError>| if unique_visit_time {
+                assert_eq!(result, duplicate);
+            } else {
+                assert!(result.is_ok());
+            };
+            expected(create(&api, visit(4, 1000, 3)));
+            expected(update(&api, 2, Optional::Nothing, Optional::Something(1000)));
+            expected(update(&api, 3, Optional::Something(UserId(1)), Optional::Something(2000)));
+            let batch = vec![visit(5, 5000, 1), Visit { user: UserId(2), ..visit(6, 5000, 1) }, visit(7, 5000, 1)];
+            expected(api.do_post(PostRequest::CreateEntity(CreateEntity::VisitBatch(batch))));
+
+            // the same time of another user, or the visit's own time
+            assert!(create(&api, Visit { user: UserId(2), ..visit(8, 1000, 3) }).is_ok());
+            assert!(update(&api, 1, Optional::Nothing, Optional::Something(1000)).is_ok());
+            assert!(update(&api, 3, Optional::Something(UserId(1)), Optional::Nothing).is_ok());
+        }
+    }
+
+    #[test]
+    fn visits_cursor() {
+        let api = api();
+        // two visits at 2000, the id breaks the tie
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4), visit(3, 2000, 3), visit(4, 4000, 2), visit(5, 5000, 1)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let page = |after, order| {
+            let parameters = GetVisits { limit: Some(3), after, order, ..Default::default() };
+            let chunks = Api::visit_chunks(&api, UserId(1), &parameters).unwrap().collect::<Vec<_>>().concat();
+            let page = get(&api, GetRequest::GetVisits(UserId(1), parameters)).unwrap();
+            assert_eq!(page.as_bytes(), &chunks[..]);
+            let marks: Vec<u8> = serde_json::from_str::<serde_json::Value>(&page).unwrap()["visits"]
+                .as_array().unwrap()
+                .iter()
+                .map(|visit| visit["mark"].as_u64().unwrap() as u8)
+                .collect();
+            (marks, page)
+        };
+
+        let (marks, first) = page(None, Order::Ascending);
+        assert_eq!(marks, [5, 4, 3]);
+        assert!(first.ends_with(r#"],"next":"2000:3"}"#), "{}", first);
+        let (marks, second) = page(Some((2000, VisitId(3))), Order::Ascending);
+        assert_eq!(marks, [2, 1]);
+        assert!(second.ends_with("}]}"), "{}", second);
+        assert_eq!(page(Some((5000, VisitId(5))), Order::Ascending).1, r#"{"visits":[]}"#);
+
+        let (marks, first) = page(None, Order::Descending);
+        assert_eq!(marks, [1, 2, 3]);
+        assert!(first.ends_with(r#"],"next":"2000:3"}"#), "{}", first);
+        assert_eq!(page(Some((2000, VisitId(3))), Order::Descending).0, [4, 5]);
+        assert_eq!(page(Some((1000, VisitId(1))), Order::Descending).1, r#"{"visits":[]}"#);
+
+        // the cursor and the dates narrow the range together
+        let parameters = GetVisits { after: Some((1000, VisitId(1))), to_date: Some(2000), limit: Some(1), ..Default::default() };
+        assert_eq!(get(&api, GetRequest::GetVisits(UserId(1), parameters)), Ok(r#"{"visits":[]}"#.to_string()));
+        let parameters = GetVisits { after: Some((4000, VisitId(4))), from_date: Some(4000), ..Default::default() };
+        assert_eq!(get(&api, GetRequest::GetVisits(UserId(1), parameters)).unwrap().matches("mark").count(), 1);
+        let parameters = GetVisits { after: Some((0, VisitId(0))), from_date: Some(4000), ..Default::default() };
+        assert_eq!(get(&api, GetRequest::GetVisits(UserId(1), parameters)).unwrap().matches("mark").count(), 1);
+
+        // no 'next' when only visits not matching the filters are left
+        let parameters = GetVisits { limit: Some(3), to_mark: Some(3), ..Default::default() };
+        assert!(get(&api, GetRequest::GetVisits(UserId(1), parameters)).unwrap().ends_with("}]}"));
+    }
+
+    #[test]
+    fn cached_average_follows_updates() {
+        let api = api();
+        let location = Location {
+            id: LocationId(2),
+            place: "Парк".into(),
+            country: "Россия".into(),
+            city: "Москва".into(),
+            distance: 20
+        };
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location))).unwrap();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4), visit(3, 3000, 0)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let update = |id, location: Option<LocationId>, mark: Option<u8>| {
+            let update = VisitUpdate {
+                location: location.map_or(Optional::Nothing, Optional::Something),
+                user: Optional::Nothing,
+                visited_at: Optional::Nothing,
+                mark: mark.map_or(Optional::Nothing, Optional::Something)
+            };
+            api.do_post(PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(id), update))).unwrap();
+        };
+
+        // the cache is only used without parameters, 'fromDate' forces the scan
+        let averages = |id| {
+            let cached = get(&api, GetRequest::GetAverageLocationRating(LocationId(id), Default::default()));
+            let parameters = GetAverageLocationRating { from_date: Some(0), ..Default::default() };
+            let scanned = get(&api, GetRequest::GetAverageLocationRating(LocationId(id), parameters));
+            assert_eq!(cached, scanned);
+            cached.unwrap()
+        };
+
+        assert_eq!(averages(1), r#"{"avg":3.00000}"#);
+        assert_eq!(averages(2), r#"{"avg":0}"#);
+
+        update(3, None, Some(3));
+        assert_eq!(averages(1), r#"{"avg":4.00000}"#);
+
+        update(1, Some(LocationId(2)), None);
+        assert_eq!(averages(1), r#"{"avg":3.50000}"#);
+        assert_eq!(averages(2), r#"{"avg":5.00000}"#);
+
+        update(1, Some(LocationId(1)), Some(1));
+        assert_eq!(averages(1), r#"{"avg":2.66667}"#);
+        assert_eq!(averages(2), r#"{"avg":0}"#);
+
+        let request = DeleteRequest { entity: DeleteEntity::Visit(VisitId(2)), cascade: false };
+        api.do_delete(request).unwrap();
+        assert_eq!(averages(1), r#"{"avg":2.00000}"#);
+    }
+
+    #[test]
+    fn distance_band() {
+        let api = api();
+        for (id, distance) in [(2, 20), (3, 30)] {
+            let location = Location {
+                id: LocationId(id),
+                place: "Парк".into(),
+                country: "Россия".into(),
+                city: "Москва".into(),
+                distance
+            };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location))).unwrap();
+        }
+        for (id, visited_at) in [(1, 1000), (2, 2000), (3, 3000)] {
+            let v = Visit { location: LocationId(id), ..visit(id, visited_at, 5) };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let count = |from_distance, to_distance| {
+            let parameters = GetVisits { from_distance, to_distance, count: true, ..Default::default() };
+            get(&api, GetRequest::GetVisits(UserId(1), parameters)).unwrap()
+        };
+        assert_eq!(count(Some(10), Some(30)), r#"{"count":2}"#);
+        assert_eq!(count(Some(11), Some(30)), r#"{"count":1}"#);
+        assert_eq!(count(Some(20), Some(20)), r#"{"count":0}"#);
+        assert_eq!(count(Some(20), None), r#"{"count":2}"#);
+        assert_eq!(count(Some(31), None), r#"{"count":0}"#);
+    }
+
+    #[test]
+    fn visits_count() {
+        let api = api();
+        for i in 1..6 {
+            let visit = visit(i, i as Timestamp * 10, 3);
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit))).unwrap();
+        }
+
+        let visits = |parameters: GetVisits| {
+            let response: serde_json::Value = get(&api, GetRequest::GetVisits(UserId(1), parameters))
+                .map(|body| serde_json::from_str(&body).unwrap())
+                .unwrap();
+            response["visits"].as_array().unwrap().len() as u64
+        };
+        let count = |parameters: GetVisits| {
+            let parameters = GetVisits { count: true, ..parameters };
+            let response: serde_json::Value = get(&api, GetRequest::GetVisits(UserId(1), parameters))
+                .map(|body| serde_json::from_str(&body).unwrap())
+                .unwrap();
+            response["count"].as_u64().unwrap()
+        };
+
+        let filters = || vec![
+            GetVisits::default(),
+            GetVisits { from_date: Some(15), ..Default::default() },
+            GetVisits { from_date: Some(15), to_date: Some(40), ..Default::default() },
+            GetVisits { limit: Some(2), ..Default::default() },
+            GetVisits { country: Some("Россия".to_string()), ..Default::default() },
+            GetVisits { to_distance: Some(5), ..Default::default() },
+        ];
+        let counts: Vec<u64> = filters().into_iter().map(count).collect();
+        let lengths: Vec<u64> = filters().into_iter().map(visits).collect();
+        assert_eq!(counts, vec![5, 4, 2, 2, 5, 0]);
+        assert_eq!(counts, lengths);
+
+        assert_eq!(get(&api, GetRequest::GetVisits(UserId(1), GetVisits { 
+            from_date: Some(100), count: true, ..Default::default() 
+        })).unwrap(), r#"{"count":0}"#);
+    }
+
+    #[test]
+    fn inclusive_date_bounds() {
+        let api = api();
+        for &(id, visited_at, mark) in &[(1, 10, 1), (2, 20, 2), (3, 30, 3)] {
+            let visit = visit(id, visited_at, mark);
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit))).unwrap();
+        }
+
+        let visits = |from_date_inclusive, to_date_inclusive| {
+            let parameters = GetVisits { 
+                from_date: Some(10), 
+                from_date_inclusive, 
+                to_date: Some(30), 
+                to_date_inclusive,
+                ..Default::default() 
+            };
+            get(&api, GetRequest::GetVisits(UserId(1), parameters)).unwrap()
+        };
+        let item = |visited_at, mark| format!(r#"{{"mark":{},"visited_at":{},"place":"Набережная"}}"#, mark, visited_at);
+
+        assert_eq!(visits(false, false), format!(r#"{{"visits":[{}]}}"#, item(20, 2)));
+        assert_eq!(visits(true, false), format!(r#"{{"visits":[{},{}]}}"#, item(10, 1), item(20, 2)));
+        assert_eq!(visits(false, true), format!(r#"{{"visits":[{},{}]}}"#, item(20, 2), item(30, 3)));
+        assert_eq!(visits(true, true), format!(r#"{{"visits":[{},{},{}]}}"#, item(10, 1), item(20, 2), item(30, 3)));
+
+        let average = |from_date_inclusive, to_date_inclusive| {
+            let parameters = GetAverageLocationRating { 
+                from_date: Some(10), 
+                from_date_inclusive, 
+                to_date: Some(30), 
+                to_date_inclusive,
+                ..Default::default() 
+            };
+            get(&api, GetRequest::GetAverageLocationRating(LocationId(1), parameters)).unwrap()
+        };
+
+        assert_eq!(average(false, false), r#"{"avg":2.00000}"#);
+        assert_eq!(average(true, false), r#"{"avg":1.50000}"#);
+        assert_eq!(average(false, true), r#"{"avg":2.50000}"#);
+        assert_eq!(average(true, true), r#"{"avg":2.00000}"#);
+
+        // a single instant is empty unless both bounds include it
+        let parameters = |from_date_inclusive, to_date_inclusive| GetVisits {
+            from_date: Some(20), 
+            from_date_inclusive, 
+            to_date: Some(20), 
+            to_date_inclusive,
+            ..Default::default()
+        };
+        assert_eq!(get(&api, GetRequest::GetVisits(UserId(1), parameters(true, false))).unwrap(), 
+                   r#"{"visits":[]}"#);
+        assert_eq!(get(&api, GetRequest::GetVisits(UserId(1), parameters(true, true))).unwrap(), 
+                   format!(r#"{{"visits":[{}]}}"#, item(20, 2)));
+    }
+
+    #[test]
+    fn visits_by_mark() {
+        let api = api();
+        for mark in 0..6 {
+            let visit = visit(mark as u32 + 1, mark as Timestamp, mark);
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit))).unwrap();
+        }
+
+        let marks = |from_mark, to_mark| {
+            let parameters = GetVisits { from_mark, to_mark, ..Default::default() };
+            let response: serde_json::Value = get(&api, GetRequest::GetVisits(UserId(1), parameters))
+                .map(|body| serde_json::from_str(&body).unwrap())
+                .unwrap();
+            response["visits"].as_array().unwrap().iter()
+                .map(|visit| visit["mark"].as_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(marks(Some(4), None), vec![4, 5]);
+        assert_eq!(marks(None, Some(1)), vec![0, 1]);
+        assert_eq!(marks(Some(2), Some(3)), vec![2, 3]);
+        assert_eq!(marks(Some(4), Some(3)), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn visits_order() {
+        let api = api();
+        for v in [visit(1, 3000, 5), visit(2, 1000, 4), visit(3, 2000, 3), visit(4, 4000, 2)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        #[derive(Deserialize)]
+        struct Item { visited_at: Timestamp }
+        #[derive(Deserialize)]
+        struct Response { visits: Vec<Item> }
+
+        let visited_at = |order| {
+            let parameters = GetVisits { to_date: Some(4000), order, ..Default::default() };
+            let response = get(&api, GetRequest::GetVisits(UserId(1), parameters)).unwrap();
+            let response: Response = serde_json::from_str(&response).unwrap();
+            response.visits.iter().map(|item| item.visited_at).collect::<Vec<_>>()
+        };
+
+        assert_eq!(visited_at(Order::Ascending), vec![1000, 2000, 3000]);
+        assert_eq!(visited_at(Order::Descending), vec![3000, 2000, 1000]);
+    }
+
+    #[test]
+    fn warmup_keeps_results() {
+        let api = api();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4), visit(3, 3000, 1)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let results = || (
+            get(&api, GetRequest::GetVisits(UserId(1), Default::default())).unwrap(),
+            get(&api, GetRequest::GetAverageLocationRating(LocationId(1), Default::default())).unwrap()
+        );
+        let before = results();
+        // each visit is read by user and by location
+        assert_eq!(api.database.index.read().unwrap().warmup(), 2 * (5 + 4 + 1));
+        assert_eq!(results(), before);
+    }
+
+    #[test]
+    fn location_with_visit_count() {
+        let api = api();
+        let location = || get(&api, GetRequest::GetEntity(GetEntity::Location(LocationId(1)))).unwrap();
+        let with_count = || get(&api, GetRequest::GetEntity(GetEntity::LocationWithVisitCount(LocationId(1))));
+
+        let plain = r#"{"id":1,"place":"Набережная","country":"Россия","city":"Москва","distance":10}"#;
+        assert_eq!(location(), plain);
+        assert_eq!(with_count().unwrap(), 
+            r#"{"id":1,"place":"Набережная","country":"Россия","city":"Москва","distance":10,"visit_count":0}"#);
+
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+        assert!(with_count().unwrap().ends_with(r#""visit_count":2}"#));
+        assert_eq!(location(), plain);
+
+        let missing = GetEntity::LocationWithVisitCount(LocationId(2));
+        assert_eq!(get(&api, GetRequest::GetEntity(missing)), Err(StatusCode::NotFound));
+    }
+
+    #[test]
+    fn visit_range() {
+        let api = api();
+        for id in &[1, 2, 3, 5, 8, 13] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit(*id, 1000, 5)))).unwrap();
+        }
+
+        let ids = |from_id, to_id| {
+            let response: serde_json::Value = get(&api, GetRequest::GetVisitRange(VisitId(from_id), VisitId(to_id)))
+                .map(|body| serde_json::from_str(&body).unwrap())
+                .unwrap();
+            response["visits"].as_array().unwrap().iter()
+                .map(|visit| visit["id"].as_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // looked up id by id
+        assert_eq!(ids(2, 6), vec![2, 3, 5]);
+        // scanned
+        assert_eq!(ids(3, 100), vec![3, 5, 8, 13]);
+        assert_eq!(ids(0, u32::MAX), vec![1, 2, 3, 5, 8, 13]);
+        assert_eq!(ids(5, 5), Vec::<u64>::new());
+        assert_eq!(ids(8, 2), Vec::<u64>::new());
+
+        assert_eq!(get(&api, GetRequest::GetVisitRange(VisitId(1), VisitId(2))).unwrap(),
+                   r#"{"visits":[{"id":1,"location":1,"user":1,"visited_at":1000,"mark":5}]}"#);
+    }
+
+    #[test]
+    fn average_rounding() {
+        let api = api();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4), visit(3, 3000, 4)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let avg = |round, from_date| {
+            let parameters = GetAverageLocationRating { round, from_date, ..Default::default() };
+            get(&api, GetRequest::GetAverageLocationRating(LocationId(1), parameters)).unwrap()
+        };
+
+        assert_eq!(avg(None, None), r#"{"avg":4.33333}"#);
+        assert_eq!(avg(Some(2), None), r#"{"avg":4.33}"#);
+        assert_eq!(avg(Some(0), None), r#"{"avg":4}"#);
+        // filtered averages are rounded the same way
+        assert_eq!(avg(Some(2), Some(1000)), r#"{"avg":4.00}"#);
+        assert_eq!(avg(Some(1), Some(0)), r#"{"avg":4.3}"#);
+    }
+
+    #[test]
+    fn location_visits() {
+        let api = api();
+        let user = User {
+            id: UserId(2),
+            email: "tameerne@yandex.ru".to_string(),
+            first_name: "Анна".to_string(),
+            last_name: "Пенушувич".to_string(),
+            gender: Gender::Female,
+            birth_date: 545081600
+        };
+        api.do_post(PostRequest::CreateEntity(CreateEntity::User(user))).unwrap();
+        for v in [visit(1, 3000, 5), visit(2, 1000, 2), Visit { user: UserId(2), ..visit(3, 2000, 4) }] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let visits = |id, parameters| get(&api, GetRequest::GetLocationVisits(LocationId(id), parameters));
+        assert_eq!(visits(1, Default::default()).unwrap(), concat!(r#"{"visits":["#,
+                   r#"{"user":1,"mark":2,"visited_at":1000},"#,
+                   r#"{"user":2,"mark":4,"visited_at":2000},"#,
+                   r#"{"user":1,"mark":5,"visited_at":3000}]}"#));
+        let parameters = GetLocationVisits { from_date: Some(1000), to_date: Some(3000), ..Default::default() };
+        assert_eq!(visits(1, parameters).unwrap(), r#"{"visits":[{"user":2,"mark":4,"visited_at":2000}]}"#);
+        let parameters = GetLocationVisits { from_mark: Some(3), to_mark: Some(4), ..Default::default() };
+        assert_eq!(visits(1, parameters).unwrap(), r#"{"visits":[{"user":2,"mark":4,"visited_at":2000}]}"#);
+        let parameters = GetLocationVisits { from_date: Some(3000), from_date_inclusive: true, ..Default::default() };
+        assert_eq!(visits(1, parameters).unwrap(), r#"{"visits":[{"user":1,"mark":5,"visited_at":3000}]}"#);
+        let parameters = GetLocationVisits { from_date: Some(3000), to_date: Some(1000), ..Default::default() };
+        assert_eq!(visits(1, parameters).unwrap(), r#"{"visits":[]}"#);
+
+        assert_eq!(visits(2, Default::default()), Err(StatusCode::NotFound));
+        api.do_delete(DeleteRequest { entity: DeleteEntity::Location(LocationId(1)), cascade: true }).unwrap();
+        assert_eq!(visits(1, Default::default()), Err(StatusCode::NotFound));
+    }
+
+    #[test]
+    fn average_with_count() {
+        let api = api();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4), visit(3, 3000, 3), visit(4, 4000, 4)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let avg = |with_count, from_date, to_date| {
+            let parameters = GetAverageLocationRating { with_count, from_date, to_date, ..Default::default() };
+            get(&api, GetRequest::GetAverageLocationRating(LocationId(1), parameters)).unwrap()
+        };
+
+        assert_eq!(avg(false, None, None), r#"{"avg":4.00000}"#);
+        assert_eq!(avg(true, None, None), r#"{"avg":4.00000,"count":4}"#);
+        // the visits at 2000 and 3000
+        assert_eq!(avg(false, Some(1000), Some(4000)), r#"{"avg":3.50000}"#);
+        assert_eq!(avg(true, Some(1000), Some(4000)), r#"{"avg":3.50000,"count":2}"#);
+        assert_eq!(avg(true, Some(4000), None), r#"{"avg":0,"count":0}"#);
+
+        let parameters = GetAverageLocationRating { with_count: true, gender: Some(Gender::Male), ..Default::default() };
+        let male = get(&api, GetRequest::GetAverageLocationRating(LocationId(1), parameters));
+        assert_eq!(male.unwrap(), r#"{"avg":4.00000,"count":4}"#);
+    }
+
+    #[test]
+    fn cached_average() {
+        let mut api = api();
+        api.avg_cache = Some(AverageCache::new(10));
+        let location = Location {
+            id: LocationId(2),
+            place: "Парк".into(),
+            country: "Россия".into(),
+            city: "Москва".into(),
+            distance: 5
+        };
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location))).unwrap();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 3), Visit { location: LocationId(2), ..visit(3, 3000, 1) }] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let avg = |api: &Api, id, gender| {
+            let parameters = GetAverageLocationRating { gender, ..Default::default() };
+            get(api, GetRequest::GetAverageLocationRating(LocationId(id), parameters)).unwrap()
+        };
+        let hits = |api: &Api| api.avg_cache.as_ref().unwrap().stats().hits;
+
+        assert_eq!(avg(&api, 1, None), r#"{"avg":4.00000}"#);
+        assert_eq!(avg(&api, 1, None), r#"{"avg":4.00000}"#);
+        assert_eq!(avg(&api, 2, None), r#"{"avg":1.00000}"#);
+        assert_eq!(hits(&api), 1);
+
+        // a new visit of location 1 drops its responses only
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit(4, 4000, 1)))).unwrap();
+        assert_eq!(avg(&api, 1, None), r#"{"avg":3.00000}"#);
+        assert_eq!(avg(&api, 2, None), r#"{"avg":1.00000}"#);
+        assert_eq!(hits(&api), 2);
+
+        // moved from location 1 to 2
+        let update = VisitUpdate {
+            location: Optional::Something(LocationId(2)),
+            user: Optional::Nothing,
+            visited_at: Optional::Nothing,
+            mark: Optional::Nothing
+        };
+        api.do_post(PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(1), update))).unwrap();
+        assert_eq!(avg(&api, 1, None), r#"{"avg":2.00000}"#);
+        assert_eq!(avg(&api, 2, None), r#"{"avg":3.00000}"#);
+
+        // the gender of the user filters the visits of both locations
+        assert_eq!(avg(&api, 1, Some(Gender::Male)), r#"{"avg":2.00000}"#);
+        let update = UserUpdate {
+            email: Optional::Nothing,
+            first_name: Optional::Nothing,
+            last_name: Optional::Nothing,
+            gender: Optional::Something(Gender::Female),
+            birth_date: Optional::Nothing
+        };
+        api.do_post(PostRequest::UpdateEntity(UpdateEntity::User(UserId(1), update))).unwrap();
+        assert_eq!(avg(&api, 1, Some(Gender::Male)), r#"{"avg":0}"#);
+
+        api.do_delete(DeleteRequest { entity: DeleteEntity::Visit(VisitId(4)), cascade: false }).unwrap();
+        assert_eq!(avg(&api, 1, None), r#"{"avg":3.00000}"#);
+
+        // a reloaded api starts empty
+        let reloaded = api.with_database(Database::new(1));
+        assert_eq!(reloaded.avg_cache.as_ref().map(|cache| (cache.capacity(), cache.stats().entries)), Some((10, 0)));
+    }
+
+    #[test]
+    fn average_without_trailing_zeros() {
+        let mut api = api();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4), visit(3, 3000, 3), visit(4, 4000, 4)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let avg = |api: &Api, round, from_date| {
+            let parameters = GetAverageLocationRating { round, from_date, ..Default::default() };
+            get(api, GetRequest::GetAverageLocationRating(LocationId(1), parameters)).unwrap()
+        };
+
+        assert_eq!(avg(&api, None, None), r#"{"avg":4.00000}"#);
+        assert_eq!(avg(&api, None, Some(1000)), r#"{"avg":3.66667}"#);
+        assert_eq!(avg(&api, None, Some(2000)), r#"{"avg":3.50000}"#);
+
+        api.avg_trailing_zeros = false;
+        assert_eq!(avg(&api, None, None), r#"{"avg":4}"#);
+        assert_eq!(avg(&api, Some(0), None), r#"{"avg":4}"#);
+        assert_eq!(avg(&api, None, Some(1000)), r#"{"avg":3.66667}"#);
+        assert_eq!(avg(&api, None, Some(2000)), r#"{"avg":3.5}"#);
+        // rounded before trimming
+        assert_eq!(avg(&api, Some(1), Some(1000)), r#"{"avg":3.7}"#);
+        assert_eq!(avg(&api, Some(0), Some(2000)), r#"{"avg":4}"#);
+        assert_eq!(avg(&api, None, Some(4000)), r#"{"avg":0}"#);
+
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit(5, 5000, 0)))).unwrap();
+        assert_eq!(avg(&api, None, Some(4000)), r#"{"avg":0}"#);
+        assert_eq!(avg(&api, Some(2), Some(4500)), r#"{"avg":0}"#);
+        assert_eq!(average_response(10, 1, 0, false, false), Bytes::from_static(b"{\"avg\":10}"));
+        assert_eq!(average_response(100, 1, 5, false, false), Bytes::from_static(b"{\"avg\":100}"));
+    }
+
+    #[test]
+    fn age_with_fixed_now() {
+        const SECONDS_IN_YEAR: i64 = 31557600;
+
+        let mut api = api();
+        // user 1 has turned 30 a bit earlier
+        api.now = 345081600 + 30 * SECONDS_IN_YEAR + 1000;
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit(1, 1000, 4)))).unwrap();
+
+        let avg = |from_age, to_age| {
+            let parameters = GetAverageLocationRating { from_age, to_age, ..Default::default() };
+            get(&api, GetRequest::GetAverageLocationRating(LocationId(1), parameters)).unwrap()
+        };
+
+        assert_eq!(avg(Some(30), None), r#"{"avg":4.00000}"#);
+        assert_eq!(avg(Some(31), None), r#"{"avg":0}"#);
+        assert_eq!(avg(None, Some(30)), r#"{"avg":0}"#);
+        assert_eq!(avg(None, Some(31)), r#"{"avg":4.00000}"#);
+    }
+
+    #[test]
+    fn age_buckets_match_scan() {
+        const SECONDS_IN_YEAR: i64 = 31557600;
+
+        let mut api = api();
+        api.now = 1500000000;
+        for id in 2..42 {
+            let user = User {
+                id: UserId(id),
+                email: format!("user{}@mail.ru", id),
+                first_name: "Аня".to_string(),
+                last_name: "Шишкина".to_string(),
+                gender: if id % 3 == 0 { Gender::Male } else { Gender::Female },
+                birth_date: api.now - (id as Timestamp * 37 % 80) * SECONDS_IN_YEAR - id as Timestamp * 1000
+            };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::User(user))).unwrap();
+        }
+        for id in 1..201 {
+            let v = Visit { user: UserId(id % 41 + 1), ..visit(id, id as Timestamp, (id % 6) as u8) };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let scan = |parameters: &GetAverageLocationRating| {
+            let (mut sum, mut count) = (0, 0);
+            for visit in api.database.visits.read_all().values() {
+                let user = api.database.users.read(&visit.user)[&visit.user].clone();
+                let age = |age: Timestamp| api.now - age * SECONDS_IN_YEAR;
+                if parameters.gender.is_some_and(|gender| gender != user.gender)
+                || parameters.from_age.is_some_and(|from_age| user.birth_date >= age(from_age))
+                || parameters.to_age.is_some_and(|to_age| user.birth_date <= age(to_age)) {
+                    continue;
+                }
+                sum += visit.mark as u64;
+                count += 1;
+            }
+            average_response(sum, count, 5, true, false)
+        };
+        let check = || {
+            let buckets = &api.database.index.read().unwrap().visits_by_location_age[&LocationId(1)];
+            assert_eq!(buckets.values().map(|visits| visits.len()).sum::<usize>(), api.database.visits.len());
+
+            for &(from_age, to_age) in &[(None, None), (Some(10), None), (None, Some(30)), (Some(15), Some(45)), 
+                                         (Some(20), Some(21)), (Some(0), Some(100)), (Some(40), Some(20))] {
+                for &gender in &[None, Some(Gender::Male), Some(Gender::Female)] {
+                    let parameters = GetAverageLocationRating { from_age, to_age, gender, ..Default::default() };
+                    let expected = scan(&parameters);
+                    let avg = api.do_get(GetRequest::GetAverageLocationRating(LocationId(1), parameters));
+                    assert_eq!(avg.unwrap(), expected, "{:?} {:?} {:?}", from_age, to_age, gender);
+                }
+            }
+        };
+        check();
+
+        for id in 2..10 {
+            let update = UserUpdate {
+                email: Optional::Nothing,
+                first_name: Optional::Nothing,
+                last_name: Optional::Nothing,
+                gender: Optional::Something(Gender::Male),
+                birth_date: Optional::Something(api.now - id as Timestamp * 5 * SECONDS_IN_YEAR)
+            };
+            api.do_post(PostRequest::UpdateEntity(UpdateEntity::User(UserId(id), update))).unwrap();
+        }
+        api.do_delete(DeleteRequest { entity: DeleteEntity::User(UserId(11)), cascade: true }).unwrap();
+        api.do_delete(DeleteRequest { entity: DeleteEntity::Visit(VisitId(5)), cascade: false }).unwrap();
+        check();
+    }
+
+    #[test]
+    fn single_sided_age_filters() {
+        const SECONDS_IN_YEAR: i64 = 31557600;
+
+        let mut api = api();
+        api.now = 345081600 + 30 * SECONDS_IN_YEAR + 1000;
+        // user 1 is a bit older than 30, user 4 is exactly 25
+        for &(id, birth_date) in &[(2, api.now - 20 * SECONDS_IN_YEAR - 1000), 
+                                   (3, api.now - 40 * SECONDS_IN_YEAR - 1000),
+                                   (4, api.now - 25 * SECONDS_IN_YEAR)] {
+            let user = User {
+                id: UserId(id),
+                email: format!("user{}@mail.ru", id),
+                first_name: "Аня".to_string(),
+                last_name: "Шишкина".to_string(),
+                gender: Gender::Female,
+                birth_date
+            };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::User(user))).unwrap();
+        }
+        for &(id, mark) in &[(1, 1), (2, 2), (3, 3), (4, 4)] {
+            let v = Visit { user: UserId(id), ..visit(id, id as Timestamp * 1000, mark) };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let avg = |from_age, to_age| {
+            let parameters = GetAverageLocationRating { from_age, to_age, ..Default::default() };
+            get(&api, GetRequest::GetAverageLocationRating(LocationId(1), parameters)).unwrap()
+        };
+
+        assert_eq!(avg(None, None), r#"{"avg":2.50000}"#);
+        assert_eq!(avg(Some(25), None), r#"{"avg":2.00000}"#);
+        assert_eq!(avg(Some(0), None), r#"{"avg":2.50000}"#);
+        assert_eq!(avg(Some(40), None), r#"{"avg":3.00000}"#);
+        assert_eq!(avg(Some(41), None), r#"{"avg":0}"#);
+        assert_eq!(avg(None, Some(25)), r#"{"avg":2.00000}"#);
+        assert_eq!(avg(None, Some(26)), r#"{"avg":3.00000}"#);
+        assert_eq!(avg(None, Some(20)), r#"{"avg":0}"#);
+        assert_eq!(avg(None, Some(100)), r#"{"avg":2.50000}"#);
+        assert_eq!(avg(Some(25), Some(25)), r#"{"avg":0}"#);
+    }
+
+    #[test]
+    fn age_overflow() {
+        let api = api();
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit(1, 1000, 5)))).unwrap();
+
+        let average = |from_age, to_age| {
+            let parameters = GetAverageLocationRating { from_age, to_age, ..Default::default() };
+            get(&api, GetRequest::GetAverageLocationRating(LocationId(1), parameters))
+        };
+
+        assert_eq!(average(Some(999999999999), None), Err(StatusCode::BadRequest));
+        assert_eq!(average(None, Some(999999999999)), Err(StatusCode::BadRequest));
+        assert_eq!(average(None, Some(1000)).unwrap(), r#"{"avg":5.00000}"#);
+    }
+
+    #[test]
+    fn export() {
+        let api = api();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4), visit(5000, 3000, 3)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let visits = get(&api, GetRequest::Export(EntityKind::Visits)).unwrap();
+        assert_eq!(visits.lines().count(), api.database.visits.len());
+        assert_eq!(visits.lines().last().unwrap(), r#"{"id":5000,"location":1,"user":1,"visited_at":3000,"mark":3}"#);
+        assert_eq!(Api::export_chunks(&api, EntityKind::Visits).count(), 2);
+
+        let users = get(&api, GetRequest::Export(EntityKind::Users)).unwrap();
+        assert_eq!(users.lines().count(), 1);
+        assert!(users.starts_with(r#"{"id":1,"email":"robosen@icloud.com""#) && users.ends_with("}\n"));
+        let locations = get(&api, GetRequest::Export(EntityKind::Locations)).unwrap();
+        assert_eq!(locations, "{\"id\":1,\"place\":\"Набережная\",\"country\":\"Россия\",\"city\":\"Москва\",\"distance\":10}\n");
+
+        let empty = Api::new(Database::default());
+        assert_eq!(get(&empty, GetRequest::Export(EntityKind::Visits)).unwrap(), "");
+    }
+
+    #[test]
+    fn multi_get() {
+        let mut api = api();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let visits = get(&api, GetRequest::GetEntities(EntityKind::Visits, vec![2, 3, 1])).unwrap();
+        assert_eq!(visits, concat!(r#"{"visits":[{"id":2,"location":1,"user":1,"visited_at":2000,"mark":4},"#,
+                                   r#"{"id":1,"location":1,"user":1,"visited_at":1000,"mark":5}],"missing":[3]}"#));
+        let users = get(&api, GetRequest::GetEntities(EntityKind::Users, vec![1])).unwrap();
+        assert!(users.starts_with(r#"{"users":[{"id":1,"email":"robosen@icloud.com""#) && users.ends_with(r#"}],"missing":[]}"#));
+        assert_eq!(get(&api, GetRequest::GetEntities(EntityKind::Locations, vec![2, 3])),
+                   Ok(r#"{"locations":[],"missing":[2,3]}"#.to_string()));
+
+        api.max_multiget = 2;
+        assert!(get(&api, GetRequest::GetEntities(EntityKind::Visits, vec![1, 2])).is_ok());
+        assert_eq!(get(&api, GetRequest::GetEntities(EntityKind::Visits, vec![1, 2, 3])), Err(StatusCode::BadRequest));
+    }
+
+    #[test]
+    fn stats() {
+        let api = api();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+        api.counters.get_requests.fetch_add(3, Ordering::Relaxed);
+        api.counters.active_connections.fetch_add(2, Ordering::Relaxed);
+
+        let stats = get(&api, GetRequest::Stats(false));
+        assert_eq!(stats.unwrap(), 
+            r#"{"users":1,"locations":1,"visits":2,"get_requests":3,"post_requests":0,"active_connections":2,"accept_errors":0,"rate_limited":0}"#);
+    }
+
+    #[test]
+    fn index_stats() {
+        let api = api();
+        let location = Location { id: LocationId(2), place: "Парк".into(), country: "Германия".into(), city: "Берлин".into(), distance: 5 };
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location))).unwrap();
+        let user = User { id: UserId(2), email: "tameerne@mail.ru".to_string(), ..api.database.users.read(&UserId(1))[&UserId(1)].clone() };
+        api.do_post(PostRequest::CreateEntity(CreateEntity::User(user))).unwrap();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 4), visit(3, 3000, 3)] {
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        // moved to another user, location and time at once, then back partly
+        let update = VisitUpdate {
+            location: Optional::Something(LocationId(2)),
+            user: Optional::Something(UserId(2)),
+            visited_at: Optional::Something(500),
+            mark: Optional::Something(1)
+        };
+        api.do_post(PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(1), update))).unwrap();
+        let update = VisitUpdate {
+            location: Optional::Nothing,
+            user: Optional::Something(UserId(1)),
+            visited_at: Optional::Nothing,
+            mark: Optional::Nothing
+        };
+        api.do_post(PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(1), update))).unwrap();
+        let update = VisitUpdate {
+            location: Optional::Something(LocationId(2)),
+            user: Optional::Nothing,
+            visited_at: Optional::Something(2000),
+            mark: Optional::Nothing
+        };
+        api.do_post(PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(3), update))).unwrap();
+        api.do_delete(DeleteRequest { entity: DeleteEntity::Visit(VisitId(2)), cascade: false }).unwrap();
+        api.do_delete(DeleteRequest { entity: DeleteEntity::User(UserId(2)), cascade: true }).unwrap();
+
+        let stats = api.database.stats();
+        assert_eq!(stats, IndexStats { indexed_visits: 4, ..Default::default() });
+
+        // a visit gone behind the index's back
+        api.database.visits.write(&VisitId(3)).remove(&VisitId(3));
+        assert_eq!(api.database.stats(), IndexStats { indexed_visits: 4, missing_visits: 2, ..Default::default() });
+        assert_eq!(api.database.stats().orphans(), 2);
+
+        let stats = get(&api, GetRequest::Stats(true)).unwrap();
+        assert!(stats.ends_with(r#","index":{"indexed_visits":4,"missing_visits":2,"stale_visits":0,"unknown_users":0,"unknown_locations":0}}"#), "{}", stats);
+    }
+
+    #[test]
+    fn visits_by_country() {
+        let api = api();
+        for &(id, country) in &[(2, "Германия"), (3, "Франция")] {
+            let location = Location {
+                id: LocationId(id),
+                place: "Парк".into(),
+                country: country.into(),
+                city: "Город".into(),
+                distance: 20
+            };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location))).unwrap();
+        }
+
+        for location in 1..4 {
+            let visit = Visit { location: LocationId(location), ..visit(location, location as Timestamp, 3) };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit))).unwrap();
+        }
+
+        let visits = |country: &str| {
+            let parameters = GetVisits { country: Some(country.to_string()), ..Default::default() };
+            get(&api, GetRequest::GetVisits(UserId(1), parameters)).unwrap()
+        };
+
+        assert_eq!(visits("Германия"), r#"{"visits":[{"mark":3,"visited_at":2,"place":"Парк"}]}"#);
+        assert_eq!(visits("Испания"), r#"{"visits":[]}"#);
+
+        let update = LocationUpdate {
+            place: Optional::Nothing,
+            country: Optional::Something("Испания".to_string()),
+            city: Optional::Nothing,
+            distance: Optional::Nothing
+        };
+        api.do_post(PostRequest::UpdateEntity(UpdateEntity::Location(LocationId(2), update))).unwrap();
+        assert_eq!(visits("Германия"), r#"{"visits":[]}"#);
+        assert_eq!(visits("Испания"), r#"{"visits":[{"mark":3,"visited_at":2,"place":"Парк"}]}"#);
+
+        let update = VisitUpdate {
+            location: Optional::Something(LocationId(3)),
+            user: Optional::Nothing,
+            visited_at: Optional::Nothing,
+            mark: Optional::Nothing
+        };
+        api.do_post(PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(2), update))).unwrap();
+        assert_eq!(visits("Испания"), r#"{"visits":[]}"#);
+        assert_eq!(visits("Франция"), r#"{"visits":[{"mark":3,"visited_at":2,"place":"Парк"},{"mark":3,"visited_at":3,"place":"Парк"}]}"#);
+    }
+
+    #[test]
+    fn case_insensitive_country() {
+        let mut api = api();
+        for &(id, country) in &[(2, "РОССИЯ"), (3, "Russia"), (4, "Германия")] {
+            let location = Location { id: LocationId(id), place: "Парк".into(), country: country.into(), city: "Город".into(), distance: 20 };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location))).unwrap();
+        }
+        for location in 1..5 {
+            let visit = Visit { location: LocationId(location), ..visit(location, 5 - location as Timestamp, location as u8) };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit))).unwrap();
+        }
+
+        let marks = |api: &Api, country: &str, order: Order| {
+            let parameters = GetVisits { country: Some(country.to_string()), order, ..Default::default() };
+            let visits = get(api, GetRequest::GetVisits(UserId(1), parameters)).unwrap();
+            serde_json::from_str::<serde_json::Value>(&visits).unwrap()["visits"].as_array().unwrap()
+                .iter()
+                .map(|visit| visit["mark"].as_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(marks(&api, "россия", Order::Ascending), Vec::<u64>::new());
+        assert_eq!(marks(&api, "Россия", Order::Ascending), [1]);
+        assert_eq!(marks(&api, "russia", Order::Ascending), Vec::<u64>::new());
+
+        api.case_insensitive_country = true;
+        // both Cyrillic spellings, merged in the order of the dates
+        assert_eq!(marks(&api, "россия", Order::Ascending), [2, 1]);
+        assert_eq!(marks(&api, "россия", Order::Descending), [1, 2]);
+        assert_eq!(marks(&api, "RUSSIA", Order::Ascending), [3]);
+        assert_eq!(marks(&api, "германия", Order::Ascending), [4]);
+        assert_eq!(marks(&api, "Франция", Order::Ascending), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn visits_by_city() {
+        let api = api();
+        let location = Location {
+            id: LocationId(2),
+            place: "Площадь".into(),
+            country: "Россия".into(),
+            city: "Нижний Новгород".into(),
+            distance: 20
+        };
+        api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location))).unwrap();
+
+        for location in 1..3 {
+            let visit = Visit { location: LocationId(location), ..visit(location, location as Timestamp, 4) };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit))).unwrap();
+        }
+
+        let visits = |city: &str| {
+            let parameters = GetVisits { city: Some(city.to_string()), ..Default::default() };
+            get(&api, GetRequest::GetVisits(UserId(1), parameters)).unwrap()
+        };
+
+        assert_eq!(visits("Москва"), r#"{"visits":[{"mark":4,"visited_at":1,"place":"Набережная"}]}"#);
+        assert_eq!(visits("Нижний Новгород"), r#"{"visits":[{"mark":4,"visited_at":2,"place":"Площадь"}]}"#);
+        assert_eq!(visits("Казань"), r#"{"visits":[]}"#);
+    }
+
+    #[test]
+    fn concurrent_reads_and_writes() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let api = Arc::new(api());
+        let writers: Vec<_> = (0..4).map(|thread| {
+            let api = api.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    let id = thread * 100 + i + 2;
+                    let location = Location {
+                        id: LocationId(id),
+                        place: "Парк".into(),
+                        country: "Россия".into(),
+                        city: "Москва".into(),
+                        distance: i
+                    };
+                    api.do_post(PostRequest::CreateEntity(CreateEntity::Location(location))).unwrap();
+
+                    let visit = Visit { location: LocationId(id), ..visit(id, id as Timestamp, 3) };
+                    api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit))).unwrap();
+                    get(&api, GetRequest::GetVisits(UserId(1), Default::default())).unwrap();
+                }
+            })
+        }).collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(api.database.locations.len(), 401);
+        assert_eq!(api.database.visits.len(), 400);
+        let avg = get(&api, GetRequest::GetAverageLocationRating(LocationId(401), Default::default()));
+        assert_eq!(avg.unwrap(), r#"{"avg":3.00000}"#);
+    }
+
+    #[test]
+    fn concurrent_visit_ranges_and_writes() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let api = Arc::new(api());
+        let writers: Vec<_> = (0..4).map(|thread| {
+            let api = api.clone();
+            thread::spawn(move || {
+                for i in 0..200 {
+                    let id = thread * 200 + i + 1;
+                    api.do_post(PostRequest::CreateEntity(CreateEntity::Visit(visit(id, id as Timestamp, 3)))).unwrap();
+                    let update = VisitUpdate {
+                        location: Optional::Nothing,
+                        user: Optional::Nothing,
+                        visited_at: Optional::Nothing,
+                        mark: Optional::Something(4)
+                    };
+                    api.do_post(PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(id), update))).unwrap();
+                }
+            })
+        }).collect();
+        let readers: Vec<_> = (0..2).map(|_| {
+            let api = api.clone();
+            thread::spawn(move || {
+                for i in 0..400 {
+                    // both the lookup by id and the scan of the shards
+                    let (from, to) = if i % 2 == 0 { (1, 10) } else { (1, 100_000) };
+                    get(&api, GetRequest::GetVisitRange(VisitId(from), VisitId(to))).unwrap();
+                }
+            })
+        }).collect();
+
+        for thread in writers.into_iter().chain(readers) {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(api.database.visits.len(), 800);
+    }
+
+    #[test]
+    fn from_entities() {
+        let created = api();
+        for v in [visit(1, 1000, 5), visit(2, 2000, 2)] {
+            created.do_post(PostRequest::CreateEntity(CreateEntity::Visit(v))).unwrap();
+        }
+
+        let users = created.database.users.read_all().values().cloned().collect();
+        let locations = created.database.locations.read_all().values().cloned().collect();
+        let visits = created.database.visits.read_all().values().cloned().collect();
+        let api = Api::new(Database::from_entities(users, locations, visits));
+        assert_index_is_rebuilt(&api);
+
+        let visits = || GetRequest::GetVisits(UserId(1), Default::default());
+        assert_eq!(get(&api, visits()), get(&created, visits()));
+        let average = || GetRequest::GetAverageLocationRating(LocationId(1), Default::default());
+        assert_eq!(get(&api, average()), get(&created, average()));
+
+        let update = VisitUpdate {
+            location: Optional::Nothing,
+            user: Optional::Nothing,
+            visited_at: Optional::Something(3000),
+            mark: Optional::Nothing
+        };
+        api.do_post(PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(1), update))).unwrap();
+        assert_index_is_rebuilt(&api);
+    }
 }
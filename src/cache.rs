@@ -0,0 +1,158 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+
+use data::LocationId;
+use request::GetAverageLocationRating;
+
+// Responses of '/locations/<id>/avg' by location and parameters, the least
+// recently used one is dropped when full. The api drops all the responses
+// of a location when its visits change, under the index lock
+pub struct AverageCache {
+    capacity: usize,
+    state: Mutex<State>,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+#[derive(Default)]
+struct State {
+    // the response and its last use
+    entries: HashMap<LocationId, HashMap<GetAverageLocationRating, (Bytes, u64)>>,
+    // keys by last use, the first one is evicted
+    uses: BTreeMap<u64, (LocationId, GetAverageLocationRating)>,
+    clock: u64
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize
+}
+
+impl AverageCache {
+    pub fn new(capacity: usize) -> AverageCache {
+        AverageCache {
+            capacity,
+            state: Default::default(),
+            hits: Default::default(),
+            misses: Default::default()
+        }
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn get(&self, location: LocationId, parameters: &GetAverageLocationRating) -> Option<Bytes> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        state.clock += 1;
+        let entry = state.entries.get_mut(&location)
+            .and_then(|responses| responses.get_mut(parameters));
+
+        match entry {
+            Some(&mut (ref bytes, ref mut used)) => {
+                let key = state.uses.remove(used).unwrap();
+                *used = state.clock;
+                state.uses.insert(*used, key);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(bytes.clone())
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, location: LocationId, parameters: GetAverageLocationRating, bytes: Bytes) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.clock += 1;
+        let used = state.clock;
+        let replaced = state.entries.entry(location).or_default()
+            .insert(parameters, (bytes, used));
+        if let Some((_, replaced)) = replaced {
+            state.uses.remove(&replaced);
+        }
+        state.uses.insert(used, (location, parameters));
+
+        while state.uses.len() > self.capacity {
+            let (_, (location, parameters)) = state.uses.pop_first().unwrap();
+            let is_empty = {
+                let responses = state.entries.get_mut(&location).unwrap();
+                responses.remove(&parameters);
+                responses.is_empty()
+            };
+            if is_empty {
+                state.entries.remove(&location);
+            }
+        }
+    }
+
+    pub fn invalidate(&self, location: LocationId) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(responses) = state.entries.remove(&location) {
+            for (_, (_, used)) in responses {
+                state.uses.remove(&used);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.state.lock().unwrap_or_else(PoisonError::into_inner).uses.len();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(avg: u32) -> Bytes {
+        format!("{{\"avg\":{}}}", avg).into_bytes().into()
+    }
+
+    #[test]
+    fn least_recently_used() {
+        let cache = AverageCache::new(2);
+        let rounded = |round| GetAverageLocationRating { round: Some(round), ..Default::default() };
+        cache.insert(LocationId(1), rounded(1), response(1));
+        cache.insert(LocationId(2), rounded(1), response(2));
+        assert_eq!(cache.get(LocationId(1), &rounded(1)), Some(response(1)));
+
+        // location 2 is the least recently used one
+        cache.insert(LocationId(1), rounded(2), response(3));
+        assert_eq!(cache.get(LocationId(2), &rounded(1)), None);
+        assert_eq!(cache.get(LocationId(1), &rounded(1)), Some(response(1)));
+        assert_eq!(cache.get(LocationId(1), &rounded(2)), Some(response(3)));
+
+        cache.insert(LocationId(1), rounded(1), response(4));
+        assert_eq!(cache.get(LocationId(1), &rounded(1)), Some(response(4)));
+        assert_eq!(cache.stats(), CacheStats { hits: 4, misses: 1, entries: 2 });
+
+        cache.invalidate(LocationId(1));
+        assert_eq!(cache.get(LocationId(1), &rounded(1)), None);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let cache = AverageCache::new(0);
+        cache.insert(LocationId(1), Default::default(), response(1));
+        assert_eq!(cache.get(LocationId(1), &Default::default()), None);
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
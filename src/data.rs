@@ -1,4 +1,7 @@
 use std::fmt;
+use std::ops::Deref;
+use std::borrow::Borrow;
+use std::sync::Arc;
 
 use serde::{Serialize, Serializer, Deserialize, Deserializer};
 use serde::de::{Visitor, Error};
@@ -11,9 +14,17 @@ pub struct LocationId(pub u32);
 
 #[derive(Hash, Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Serialize, Deserialize)]
 pub struct VisitId(pub u32);
+
+// an entity of any type, e.g. for versions
+#[derive(Hash, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntityId {
+    User(UserId),
+    Location(LocationId),
+    Visit(VisitId)
+}
 pub type Timestamp = i64;
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Hash, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Gender {
     Male,
     Female,
@@ -66,6 +77,103 @@ impl<'de> Deserialize<'de> for Gender {
     }
 }
 
+// Location strings repeat a lot (a few hundred countries for millions of
+// locations), so equal ones share an allocation, see 'Database::intern'.
+// Serialized as a plain string
+#[derive(Hash, Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
+pub struct Name(Arc<str>);
+
+impl Name {
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // same allocation, i.e. interned in the same pool
+    #[inline]
+    pub fn ptr_eq(&self, other: &Name) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    // copies sharing the allocation, this one and the pool's included
+    #[inline]
+    pub fn ref_count(&self) -> usize {
+        Arc::strong_count(&self.0)
+    }
+}
+
+impl Deref for Name {
+    type Target = str;
+
+    #[inline]
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+// lets the pool be looked up by '&str'
+impl Borrow<str> for Name {
+    #[inline]
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Name {
+    #[inline]
+    fn from(name: &str) -> Name {
+        Name(name.into())
+    }
+}
+
+impl From<String> for Name {
+    #[inline]
+    fn from(name: String) -> Name {
+        Name(name.into())
+    }
+}
+
+impl PartialEq<str> for Name {
+    #[inline]
+    fn eq(&self, other: &str) -> bool {
+        *self.0 == *other
+    }
+}
+
+impl PartialEq<String> for Name {
+    #[inline]
+    fn eq(&self, other: &String) -> bool {
+        *self.0 == **other
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(&self.0)
+    }
+}
+
+impl Serialize for Name {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Name {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Name, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer).map(Name::from)
+    }
+}
+
+// Responses have the fields in declaration order, checked by 'tests/golden'
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct User {
     pub id:         UserId,
@@ -79,9 +187,9 @@ pub struct User {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Location {
     pub id:       LocationId,
-    pub place:    String,
-    pub country:  String,
-    pub city:     String,
+    pub place:    Name,
+    pub country:  Name,
+    pub city:     Name,
     pub distance: u32,
 }
 
@@ -91,9 +199,31 @@ pub struct Visit {
     pub location:   LocationId,       
     pub user:       UserId,       
     pub visited_at: Timestamp, 
-    pub mark:       u8,        // in range 0..5
+    pub mark:       u8,        // in range 0..=5
+}
+
+#[inline]
+pub fn is_valid_mark(mark: u8) -> bool {
+    mark <= 5
 }
 
+#[inline]
+pub fn is_valid_email(email: &str) -> bool {
+    let mut parts = email.split('@');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => !local.is_empty() && !domain.is_empty(),
+        _ => false
+    }
+}
+
+// 1900-01-01T00:00:00Z
+pub const MIN_BIRTH_DATE: Timestamp = -2208988800;
+
+// people born in the future or before 1900 are most likely typos
+#[inline]
+pub fn is_valid_birth_date(birth_date: Timestamp, now: Timestamp) -> bool {
+    birth_date >= MIN_BIRTH_DATE && birth_date < now
+}
 
 #[cfg(test)]
 mod tests {
@@ -131,6 +261,15 @@ mod tests {
         assert_eq!(user, expected_user);
     }
 
+    #[test]
+    fn location_round_trip() {
+        let json = r#"{"id":1,"place":"Набережная","country":"Россия \"Север\"","city":"","distance":10}"#;
+        let location: Location = serde_json::from_str(json).unwrap();
+        assert_eq!(location.country, *"Россия \"Север\"");
+        assert_eq!(location.city, *"");
+        assert_eq!(serde_json::to_string(&location).unwrap(), json);
+    }
+
     #[test]
     fn deserialize_user() {
         let data = r#"[
@@ -173,4 +312,24 @@ mod tests {
             birth_date: -1571356800
         });
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn validate_email() {
+        assert!(is_valid_email("robosen@icloud.com"));
+        assert!(!is_valid_email("not-an-email"));
+        assert!(!is_valid_email(""));
+        assert!(!is_valid_email("@icloud.com"));
+        assert!(!is_valid_email("robosen@"));
+        assert!(!is_valid_email("robosen@@icloud.com"));
+    }
+
+    #[test]
+    fn validate_birth_date() {
+        let now = 1503695452;
+        assert!(is_valid_birth_date(345081600, now));
+        assert!(is_valid_birth_date(MIN_BIRTH_DATE, now));
+        assert!(!is_valid_birth_date(MIN_BIRTH_DATE - 1, now));
+        assert!(!is_valid_birth_date(now, now));
+        assert!(!is_valid_birth_date(now + 86400, now));
+    }
+}
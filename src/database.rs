@@ -1,83 +1,1103 @@
-use std::collections::{HashMap, BTreeMap};
+use std::collections::{HashMap, HashSet, BTreeMap};
 use std::error::Error;
 use std::path::Path;
 use std::fs::File;
-use std::fmt::Display;
-use std::io::Read;
+use std::cmp;
+use std::fmt::{self, Display};
+use std::ops::{Deref, DerefMut, Range};
+use std::hash::{Hash, Hasher, BuildHasherDefault};
+use std::io::{Read, BufReader};
+use std::marker::PhantomData;
+use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::Instant;
 
+use serde::de::{self, Deserialize, DeserializeSeed, Deserializer, Visitor, MapAccess, SeqAccess, IgnoredAny};
 use serde_json;
 use zip::ZipArchive;
+use zip::read::ZipFile;
 
 use data::*;
 
+// Ids are trusted dense integers, so a single multiplication (as in FxHash)
+// spreads them well enough and is much cheaper than the default SipHash
+#[derive(Default, Clone, Copy)]
+pub struct IdHasher(u64);
+
+impl Hasher for IdHasher {
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(byte as u64);
+        }
+    }
+
+    #[inline]
+    fn write_u32(&mut self, i: u32) {
+        self.write_u64(i as u64);
+    }
+
+    #[inline]
+    fn write_u64(&mut self, i: u64) {
+        self.0 = (self.0.rotate_left(5) ^ i).wrapping_mul(0x517cc1b727220a95);
+    }
+}
+
+pub type IdMap<K, V> = HashMap<K, V, BuildHasherDefault<IdHasher>>;
+
+pub trait ShardKey {
+    fn shard_key(&self) -> usize;
+}
+
+impl ShardKey for UserId {
+    #[inline]
+    fn shard_key(&self) -> usize { self.0 as usize }
+}
+
+impl ShardKey for LocationId {
+    #[inline]
+    fn shard_key(&self) -> usize { self.0 as usize }
+}
+
+impl ShardKey for VisitId {
+    #[inline]
+    fn shard_key(&self) -> usize { self.0 as usize }
+}
+
+// Map split into 'id % N' parts, each behind its own lock
+pub struct Shards<K, V> {
+    shards: Vec<RwLock<Shard<K, V>>>
+}
+
+// Entities of a shard and their versions, bumped by every update under the
+// shard's lock. Entities never updated are at 0. Deleting keeps the version,
+// so it doesn't repeat
+pub struct Shard<K, V> {
+    entities: IdMap<K, V>,
+    versions: IdMap<K, u64>
+}
+
+impl<K: Hash + Eq, V> Shard<K, V> {
+    #[inline]
+    pub fn version(&self, key: &K) -> u64 {
+        self.versions.get(key).cloned().unwrap_or(0)
+    }
+
+    #[inline]
+    pub fn bump_version(&mut self, key: K) {
+        *self.versions.entry(key).or_insert(0) += 1;
+    }
+}
+
+impl<K, V> Deref for Shard<K, V> {
+    type Target = IdMap<K, V>;
+
+    #[inline]
+    fn deref(&self) -> &IdMap<K, V> {
+        &self.entities
+    }
+}
+
+impl<K, V> DerefMut for Shard<K, V> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut IdMap<K, V> {
+        &mut self.entities
+    }
+}
+
+impl<K: ShardKey + Hash + Eq, V> Shards<K, V> {
+    pub fn new(count: usize) -> Self {
+        assert!(count > 0, "At least one shard is required");
+        Shards {
+            shards: (0..count)
+                .map(|_| RwLock::new(Shard { entities: IdMap::default(), versions: IdMap::default() }))
+                .collect()
+        }
+    }
+
+    #[inline]
+    fn shard(&self, key: &K) -> &RwLock<Shard<K, V>> {
+        &self.shards[key.shard_key() % self.shards.len()]
+    }
+
+    // Locks poisoned by a panicking request are recovered rather than
+    // failing every request after it
+    #[inline]
+    pub fn read(&self, key: &K) -> RwLockReadGuard<'_, Shard<K, V>> {
+        self.shard(key).read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[inline]
+    pub fn write(&self, key: &K) -> RwLockWriteGuard<'_, Shard<K, V>> {
+        self.shard(key).write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[inline]
+    pub fn version(&self, key: &K) -> u64 {
+        self.read(key).version(key)
+    }
+
+    #[inline]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.read(key).contains_key(key)
+    }
+
+    #[inline]
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.write(&key).insert(key, value)
+    }
+
+    // locks every shard (in order) for lookups spanning many keys
+    #[inline]
+    pub fn read_all(&self) -> ReadShards<'_, K, V> {
+        ReadShards {
+            guards: self.shards.iter()
+                .map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner))
+                .collect()
+        }
+    }
+
+    // same order as 'read_all', for modifications that must be atomic
+    #[inline]
+    pub fn write_all(&self) -> WriteShards<'_, K, V> {
+        WriteShards {
+            guards: self.shards.iter()
+                .map(|shard| shard.write().unwrap_or_else(PoisonError::into_inner))
+                .collect()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter()
+            .map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner).len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct ReadShards<'a, K: 'a, V: 'a> {
+    guards: Vec<RwLockReadGuard<'a, Shard<K, V>>>
+}
+
+impl<'a, K: ShardKey + Hash + Eq, V> ReadShards<'a, K, V> {
+    #[inline]
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.guards[key.shard_key() % self.guards.len()].get(key)
+    }
+
+    #[inline]
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.guards.iter().flat_map(|shard| shard.values())
+    }
+}
+
+pub struct WriteShards<'a, K: 'a, V: 'a> {
+    guards: Vec<RwLockWriteGuard<'a, Shard<K, V>>>
+}
+
+impl<'a, K: ShardKey + Hash + Eq, V> WriteShards<'a, K, V> {
+    #[inline]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.guards[key.shard_key() % self.guards.len()].contains_key(key)
+    }
+
+    #[inline]
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let shard = key.shard_key() % self.guards.len();
+        self.guards[shard].insert(key, value)
+    }
+
+    #[inline]
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let shard = key.shard_key() % self.guards.len();
+        self.guards[shard].remove(key)
+    }
+
+    #[inline]
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.guards.iter().flat_map(|shard| shard.values())
+    }
+}
+
+pub type VisitMap = BTreeMap<(Timestamp, VisitId), Visit>;
+
+#[inline]
+fn country_visits<'a>(countries: &'a mut HashMap<String, VisitMap>, country: &str) -> &'a mut VisitMap {
+    // avoids allocating the key when the country is already there
+    if !countries.contains_key(country) {
+        countries.insert(country.to_string(), Default::default());
+    }
+    countries.get_mut(country).expect("Country was just inserted")
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Marks {
+    pub sum:   u64,
+    pub count: u32
+}
+
+// What '/locations/<id>/avg' filters the visits of a user by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rater {
+    pub gender:     Gender,
+    pub birth_date: Timestamp
+}
+
+impl From<&User> for Rater {
+    #[inline]
+    fn from(user: &User) -> Rater {
+        Rater { gender: user.gender, birth_date: user.birth_date }
+    }
+}
+
+// ten years of 365.25 days
+pub const AGE_BUCKET_SECONDS: Timestamp = 315576000;
+
+// Marks of the visits to a location by the first birth date of the decade
+// their users were born in. Costs about 40 bytes per visit and 100 more per
+// decade a location has visitors from
+pub type AgeBuckets = BTreeMap<Timestamp, BTreeMap<(Timestamp, VisitId), (u8, Rater)>>;
+
+#[inline]
+pub fn age_bucket(birth_date: Timestamp) -> Timestamp {
+    birth_date.div_euclid(AGE_BUCKET_SECONDS) * AGE_BUCKET_SECONDS
+}
+
 #[derive(Default)]
-pub struct Database {
-    pub users: HashMap<UserId, User>,
-    pub locations: HashMap<LocationId, Location>,
-    pub visits: HashMap<VisitId, Visit>,
-    
+pub struct Index {
     // for /user/<id>/visits request
-    pub visits_by_user: HashMap<UserId, BTreeMap<Timestamp, Visit>>,
+    pub visits_by_user: IdMap<UserId, VisitMap>,
     
     // for /locations/<id>/avg request
-    pub visits_by_location: HashMap<LocationId, BTreeMap<Timestamp, Visit>>
+    pub visits_by_location: IdMap<LocationId, VisitMap>,
+
+    // for /locations/<id>/avg request without parameters
+    pub marks_by_location: IdMap<LocationId, Marks>,
+
+    // for /locations/<id>/avg request with age or gender parameters, misses
+    // the visits of unknown users
+    pub visits_by_location_age: IdMap<LocationId, AgeBuckets>,
+
+    // for /user/<id>/visits request with 'country' parameter, costs one more
+    // copy of every visit and a country string per visited country of a user
+    pub visits_by_user_country: IdMap<UserId, HashMap<String, VisitMap>>
 }
 
-impl Database {
+impl Index {
+    // 'country' is the country of the visit's location, 'rater' is 'None'
+    // for unknown users
+    pub fn insert(&mut self, visit: &Visit, country: &str, rater: Option<Rater>) {
+        self.insert_by_user(visit, country);
+        self.insert_by_location(visit, rater);
+    }
+
+    // the maps keyed by user
     #[inline]
-    pub fn from_file<P: AsRef<Path> + Display>(path: P) -> Result<Database, Box<Error>> {
-        let mut database = Database::default();
-        
-        // info!("Loading database from {}", path);
-        let zip_file = File::open(path)?;
-        let mut archive = ZipArchive::new(zip_file)?;
-        for i in 0..archive.len() {
-            let mut file = archive.by_index(i).expect("Unable to read zip file");
-            if file.name().starts_with("users") {
-                #[derive(Deserialize)]
-                struct Users {
-                    users: Vec<User>
-                }
+    fn insert_by_user(&mut self, visit: &Visit, country: &str) {
+        let key = (visit.visited_at, visit.id);
+        self.visits_by_user.entry(visit.user)
+            .or_default()
+            .insert(key, visit.clone());
+
+        let countries = self.visits_by_user_country.entry(visit.user).or_default();
+        country_visits(countries, country).insert(key, visit.clone());
+    }
+
+    // the maps keyed by location
+    #[inline]
+    fn insert_by_location(&mut self, visit: &Visit, rater: Option<Rater>) {
+        let key = (visit.visited_at, visit.id);
+        self.visits_by_location.entry(visit.location)
+            .or_default()
+            .insert(key, visit.clone());
 
-                let mut bytes = Vec::new();
-                file.read_to_end(&mut bytes)?;
-                let Users { users } = serde_json::from_slice(&bytes)?;
-                for user in users {
-                    database.users.insert(user.id, user);
+        if let Some(rater) = rater {
+            insert_aged(&mut self.visits_by_location_age, visit.location, key, visit.mark, rater);
+        }
+
+        self.add_mark(visit.location, visit.mark);
+    }
+
+    // Indexes 'visits' on 'threads' threads. A thread takes the users and
+    // the locations with 'id % threads' equal to its number, so the parts
+    // have disjoint keys and are merged without touching the visit maps
+    fn build<'a, C, R>(visits: &[&Visit], threads: usize, country: C, rater: R) -> Index
+    where
+        C: Fn(LocationId) -> &'a str + Sync,
+        R: Fn(UserId) -> Option<Rater> + Sync
+    {
+        let part = |thread: usize| {
+            let mut index = Index::default();
+            for visit in visits {
+                if visit.user.0 as usize % threads == thread {
+                    index.insert_by_user(visit, country(visit.location));
                 }
-            } else if file.name().starts_with("locations") {
-                #[derive(Deserialize)]
-                struct Locations {
-                    locations: Vec<Location>
+                if visit.location.0 as usize % threads == thread {
+                    index.insert_by_location(visit, rater(visit.user));
                 }
+            }
+            index
+        };
+        if threads <= 1 {
+            return part(0);
+        }
+
+        let parts: Vec<Index> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|thread| {
+                    let part = &part;
+                    scope.spawn(move || part(thread))
+                })
+                .collect();
+            workers.into_iter()
+                .map(|worker| worker.join().expect("Indexing thread panic"))
+                .collect()
+        });
+
+        let mut index = Index::default();
+        for part in parts {
+            index.visits_by_user.extend(part.visits_by_user);
+            index.visits_by_location.extend(part.visits_by_location);
+            index.marks_by_location.extend(part.marks_by_location);
+            index.visits_by_location_age.extend(part.visits_by_location_age);
+            index.visits_by_user_country.extend(part.visits_by_user_country);
+        }
+        index
+    }
+
+    // whether the user has a visit at exactly 'visited_at'
+    pub fn has_visit_at(&self, user: UserId, visited_at: Timestamp) -> bool {
+        self.visits_by_user.get(&user).is_some_and(|visits| {
+            visits.range((visited_at, VisitId(0))..=(visited_at, VisitId(u32::MAX))).next().is_some()
+        })
+    }
+
+    // Takes the visit as it was indexed, false if it wasn't there
+    pub fn remove(&mut self, visit: &Visit, country: &str, rater: Option<Rater>) -> bool {
+        let key = (visit.visited_at, visit.id);
+        let by_user = self.visits_by_user.get_mut(&visit.user)
+            .and_then(|visits| visits.remove(&key));
+
+        let by_location = self.visits_by_location.get_mut(&visit.location)
+            .and_then(|visits| visits.remove(&key));
+
+        if let Some(countries) = self.visits_by_user_country.get_mut(&visit.user) {
+            let is_empty = countries.get_mut(country)
+                .is_some_and(|visits| {
+                    visits.remove(&key);
+                    visits.is_empty()
+                });
+
+            if is_empty {
+                countries.remove(country);
+            }
+        }
+
+        if let Some(rater) = rater {
+            remove_aged(&mut self.visits_by_location_age, visit.location, key, rater.birth_date);
+        }
+
+        self.remove_mark(visit.location, visit.mark);
+        by_user.is_some() && by_location.is_some()
+    }
+
+    // moves visits of 'user' between age buckets when the user changes
+    pub fn change_rater(&mut self, user: UserId, from: Rater, to: Rater) {
+        let visits = match self.visits_by_user.get(&user) {
+            Some(visits) => visits,
+            None => return
+        };
+
+        for (key, visit) in visits {
+            remove_aged(&mut self.visits_by_location_age, visit.location, *key, from.birth_date);
+            insert_aged(&mut self.visits_by_location_age, visit.location, *key, visit.mark, to);
+        }
+    }
+
+    // Reads every indexed visit once, faulting their pages in before the
+    // first requests. Returns the sum of the marks, so the reads stay
+    pub fn warmup(&self) -> u64 {
+        let by_user = self.visits_by_user.values().flat_map(|visits| visits.values());
+        let by_location = self.visits_by_location.values().flat_map(|visits| visits.values());
+        by_user.chain(by_location)
+            .map(|visit| visit.mark as u64)
+            .sum()
+    }
+
+    // moves visits to 'location' between countries of their users
+    pub fn change_country(&mut self, location: LocationId, from: &str, to: &str) {
+        let visits = match self.visits_by_location.get(&location) {
+            Some(visits) => visits,
+            None => return
+        };
+
+        for (key, visit) in visits {
+            if let Some(countries) = self.visits_by_user_country.get_mut(&visit.user) {
+                let is_empty = countries.get_mut(from)
+                    .is_some_and(|visits| {
+                        visits.remove(key);
+                        visits.is_empty()
+                    });
 
-                let mut bytes = Vec::new();
-                file.read_to_end(&mut bytes)?;
-                let Locations { locations } = serde_json::from_slice(&bytes)?;
-                for location in locations {
-                    database.locations.insert(location.id, location);
+                if is_empty {
+                    countries.remove(from);
                 }
-            } else if file.name().starts_with("visits") {
-                #[derive(Deserialize)]
-                struct Visits {
-                    visits: Vec<Visit>
+
+                country_visits(countries, to).insert(*key, visit.clone());
+            }
+        }
+    }
+
+    #[inline]
+    fn add_mark(&mut self, location: LocationId, mark: u8) {
+        let marks = self.marks_by_location.entry(location).or_default();
+        marks.sum += mark as u64;
+        marks.count += 1;
+    }
+
+    #[inline]
+    fn remove_mark(&mut self, location: LocationId, mark: u8) {
+        let is_empty = match self.marks_by_location.get_mut(&location) {
+            Some(marks) => {
+                marks.sum -= mark as u64;
+                marks.count -= 1;
+                marks.count == 0
+            }
+            None => false
+        };
+
+        if is_empty {
+            self.marks_by_location.remove(&location);
+        }
+    }
+}
+
+#[inline]
+fn insert_aged(buckets: &mut IdMap<LocationId, AgeBuckets>, location: LocationId,
+               key: (Timestamp, VisitId), mark: u8, rater: Rater) {
+    buckets.entry(location)
+        .or_default()
+        .entry(age_bucket(rater.birth_date))
+        .or_default()
+        .insert(key, (mark, rater));
+}
+
+// drops the buckets and locations left without visits
+fn remove_aged(buckets: &mut IdMap<LocationId, AgeBuckets>, location: LocationId, 
+               key: (Timestamp, VisitId), birth_date: Timestamp) {
+    let is_empty = match buckets.get_mut(&location) {
+        Some(location_buckets) => {
+            let bucket = age_bucket(birth_date);
+            let is_bucket_empty = location_buckets.get_mut(&bucket)
+                .is_some_and(|visits| {
+                    visits.remove(&key);
+                    visits.is_empty()
+                });
+            if is_bucket_empty {
+                location_buckets.remove(&bucket);
+            }
+            location_buckets.is_empty()
+        }
+        None => false
+    };
+
+    if is_empty {
+        buckets.remove(&location);
+    }
+}
+
+// Deserializes '{"<name>": [...]}' passing every element of the array to 
+// 'insert' right away instead of collecting them into a vector first
+struct Entities<T, F> {
+    name: &'static str,
+    insert: F,
+    entity: PhantomData<T>
+}
+
+impl<T, F: FnMut(T)> Entities<T, F> {
+    fn new(name: &'static str, insert: F) -> Self {
+        Entities { name, insert, entity: PhantomData }
+    }
+}
+
+impl<'de, T: Deserialize<'de>, F: FnMut(T)> DeserializeSeed<'de> for Entities<T, F> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, T: Deserialize<'de>, F: FnMut(T)> Visitor<'de> for Entities<T, F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "an object with '{}' array", self.name)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<(), A::Error> {
+        let mut found = false;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.name && !found {
+                map.next_value_seed(Elements(&mut self.insert, PhantomData))?;
+                found = true;
+            } else if key == self.name {
+                return Err(de::Error::duplicate_field(self.name));
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+
+        if found {
+            Ok(())
+        } else {
+            Err(de::Error::missing_field(self.name))
+        }
+    }
+}
+
+struct Elements<'a, T, F: 'a>(&'a mut F, PhantomData<T>);
+
+impl<'de, 'a, T: Deserialize<'de>, F: FnMut(T)> DeserializeSeed<'de> for Elements<'a, T, F> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a, T: Deserialize<'de>, F: FnMut(T)> Visitor<'de> for Elements<'a, T, F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(element) = seq.next_element()? {
+            (self.0)(element);
+        }
+        Ok(())
+    }
+}
+
+// Entries of 'visits_by_user' and 'visits_by_location' not matching the
+// entities, see 'Database::stats'. The index is consistent without orphans
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct IndexStats {
+    // in both maps, twice the visits when consistent
+    pub indexed_visits: usize,
+    // the visit was deleted
+    pub missing_visits: usize,
+    // the visit has changed, or is filed under another user or location
+    pub stale_visits: usize,
+    // the user or location the entry is filed under is gone
+    pub unknown_users: usize,
+    pub unknown_locations: usize
+}
+
+#[inline]
+fn count_stale<F>(stats: &mut IndexStats, entries: &VisitMap, visits: &ReadShards<'_, VisitId, Visit>, is_owner: F)
+where
+    F: Fn(&Visit) -> bool
+{
+    for (&(visited_at, id), indexed) in entries {
+        stats.indexed_visits += 1;
+        match visits.get(&id) {
+            None => stats.missing_visits += 1,
+            Some(visit) if visit != indexed || visit.visited_at != visited_at || !is_owner(visit) 
+                => stats.stale_visits += 1,
+            Some(_) => {}
+        }
+    }
+}
+
+impl IndexStats {
+    pub fn orphans(&self) -> usize {
+        self.missing_visits + self.stale_visits + self.unknown_users + self.unknown_locations
+    }
+}
+
+// Summary of 'Database::check_file', the dataset is clean without problems
+#[derive(Debug, Default, PartialEq)]
+pub struct CheckReport {
+    pub users: usize,
+    pub locations: usize,
+    pub visits: usize,
+    // problems
+    pub duplicate_ids: usize,
+    pub unknown_users: usize,
+    pub unknown_locations: usize,
+    pub invalid_marks: usize
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.duplicate_ids == 0 && self.unknown_users == 0 
+            && self.unknown_locations == 0 && self.invalid_marks == 0
+    }
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Users: {}, Locations: {}, Visits: {}", self.users, self.locations, self.visits)?;
+        writeln!(f, "Duplicate ids: {}", self.duplicate_ids)?;
+        writeln!(f, "Visits of unknown users: {}", self.unknown_users)?;
+        writeln!(f, "Visits of unknown locations: {}", self.unknown_locations)?;
+        write!(f, "Visits with invalid marks: {}", self.invalid_marks)
+    }
+}
+
+// Lock order is 'index', 'emails', shards, then 'names' or the api's cache of
+// averages. A shard locked for writing is held only while locking 'names',
+// which locks nothing itself, so readers may hold several shards at once. The
+// cache is locked with 'index' held and no shard. Visit writes and deletes
+// take 'index' for writing, which makes their checks that a user or location
+// exists stable until the change is applied.
+pub struct Database {
+    pub users: Shards<UserId, User>,
+    pub locations: Shards<LocationId, Location>,
+    pub visits: Shards<VisitId, Visit>,
+    pub index: RwLock<Index>,
+    // owners of the emails, which are unique. Locked before 'users'
+    pub emails: RwLock<HashMap<String, UserId>>,
+    // pool of location strings shared between locations, see 'Name'. Names
+    // are removed with the last location using them, see 'release'
+    pub names: Mutex<HashSet<Name>>
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Database::new(1)
+    }
+}
+
+impl Database {
+    pub fn new(shards: usize) -> Database {
+        Database {
+            users: Shards::new(shards),
+            locations: Shards::new(shards),
+            visits: Shards::new(shards),
+            index: Default::default(),
+            emails: Default::default(),
+            names: Default::default()
+        }
+    }
+
+    // kept in the shard of the entity, see 'Shard'
+    pub fn version(&self, entity: EntityId) -> u64 {
+        match entity {
+            EntityId::User(id) => self.users.version(&id),
+            EntityId::Location(id) => self.locations.version(&id),
+            EntityId::Visit(id) => self.visits.version(&id)
+        }
+    }
+
+    // the pooled copy of the name, which is added if it's new
+    pub fn intern(&self, name: Name) -> Name {
+        let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+        intern(&mut names, name)
+    }
+
+    // makes the location share its strings with the others
+    pub fn intern_location(&self, location: &mut Location) {
+        let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+        intern_location(&mut names, location);
+    }
+
+    // Drops a name no location uses anymore, and its pooled copy when that
+    // is the only one left. Copies held by requests at the moment keep it
+    // in the pool
+    pub fn release(&self, name: Name) {
+        let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+        release(&mut names, name);
+    }
+
+    // the names of a removed or rejected location
+    pub fn release_location(&self, location: Location) {
+        let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+        release(&mut names, location.place);
+        release(&mut names, location.country);
+        release(&mut names, location.city);
+    }
+
+    // Zip members are split into contiguous ranges, one per thread ('shards' 
+    // at most), each thread reading the archive on its own. Entities with 
+    // the same id in different members are resolved as if loading sequentially
+    // (the last one wins), so the result doesn't depend on scheduling.
+    // With 'strict' visits of unknown users or locations are dropped, 
+    // otherwise they are kept as is
+    pub fn from_file<P: AsRef<Path> + Display>(path: P, shards: usize, strict: bool) -> Result<Database, Box<Error>> {
+        Database::load(path, shards, strict).map(|(database, _replaced)| database)
+    }
+
+    // Loads the dataset as is and looks for problems, for '--check'
+    pub fn check_file<P: AsRef<Path> + Display>(path: P, shards: usize) -> Result<CheckReport, Box<Error>> {
+        let (database, replaced) = Database::load(path, shards, false)?;
+        let mut report = CheckReport {
+            users: database.users.len(),
+            locations: database.locations.len(),
+            visits: database.visits.len(),
+            duplicate_ids: replaced,
+            ..Default::default()
+        };
+
+        let users = database.users.read_all();
+        let locations = database.locations.read_all();
+        let visits = database.visits.read_all();
+        for visit in visits.values() {
+            report.unknown_users += users.get(&visit.user).is_none() as usize;
+            report.unknown_locations += locations.get(&visit.location).is_none() as usize;
+            report.invalid_marks += !is_valid_mark(visit.mark) as usize;
+        }
+        Ok(report)
+    }
+
+    // Walks the index comparing it with the entities, under the read locks
+    pub fn stats(&self) -> IndexStats {
+        let index = self.index.read().unwrap_or_else(PoisonError::into_inner);
+        let users = self.users.read_all();
+        let locations = self.locations.read_all();
+        let visits = self.visits.read_all();
+
+        let mut stats = IndexStats::default();
+        for (user, entries) in &index.visits_by_user {
+            count_stale(&mut stats, entries, &visits, |visit| visit.user == *user);
+            if users.get(user).is_none() {
+                stats.unknown_users += entries.len();
+            }
+        }
+        for (location, entries) in &index.visits_by_location {
+            count_stale(&mut stats, entries, &visits, |visit| visit.location == *location);
+            if locations.get(location).is_none() {
+                stats.unknown_locations += entries.len();
+            }
+        }
+        stats
+    }
+
+    // also returns the number of entities defined more than once
+    fn load<P: AsRef<Path> + Display>(path: P, shards: usize, strict: bool) -> Result<(Database, usize), Box<Error>> {
+        info!("Loading database from {}", path);
+        let file = File::open(&path)
+            .map_err(|e| format!("Unable to open {}: {}", path, e))?;
+        let mut archive = ZipArchive::new(file)
+            .map_err(|e| format!("Invalid zip archive {}: {}", path, e))?;
+        let members = archive.len();
+        let entities = (0..members)
+            .filter(|&i| archive.by_index(i).ok().is_some_and(|file| is_entities(file.name())))
+            .count();
+        if entities == 0 {
+            warn!("No users, locations or visits in {}, the database is empty", path);
+        }
+        let threads = cmp::max(1, cmp::min(shards, members));
+
+        let mut database = Database::new(shards);
+        let replaced = if threads > 1 {
+            let chunk = members.div_ceil(threads);
+            database.load_parallel(path.as_ref(), members, chunk)?
+        } else {
+            0
+        };
+
+        // with duplicates the order of insertion matters, so start over
+        let replaced = if threads == 1 || replaced != 0 {
+            if replaced != 0 {
+                warn!("{} entities are defined more than once, loading sequentially", replaced);
+                database = Database::new(shards);
+            }
+            database.load_members(path.as_ref(), 0..members)
+                .map_err(|e| e as Box<Error>)?
+        } else {
+            0
+        };
+
+        // indexing needs locations, which may come after visits in the archive
+        database.build_index(strict);
+        Ok((database, replaced))
+    }
+
+    // An in-memory dataset, indexed the same way as a loaded one. Visits of
+    // unknown users or locations are kept
+    pub fn from_entities(users: Vec<User>, locations: Vec<Location>, visits: Vec<Visit>) -> Database {
+        let database = Database::default();
+        {
+            let mut names = database.names.lock().unwrap_or_else(PoisonError::into_inner);
+            for mut location in locations {
+                intern_location(&mut names, &mut location);
+                database.locations.insert(location.id, location);
+            }
+        }
+        for user in users {
+            database.users.insert(user.id, user);
+        }
+        for visit in visits {
+            database.visits.insert(visit.id, visit);
+        }
+
+        let mut database = database;
+        database.build_index(false);
+        database
+    }
+
+    // Indexes visits and emails. With 'strict' visits of unknown users or
+    // locations are dropped first
+    fn build_index(&mut self, strict: bool) {
+        let started = Instant::now();
+        let threads = self.visits.shards.len();
+        let index = {
+            let users = self.users.read_all();
+            let locations = self.locations.read_all();
+            let mut visits = self.visits.write_all();
+
+            let dangling: Vec<VisitId> = if strict {
+                visits.values()
+                    .filter(|visit| users.get(&visit.user).is_none() 
+                                 || locations.get(&visit.location).is_none())
+                    .map(|visit| visit.id)
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            if !dangling.is_empty() {
+                warn!("Dropping {} visits of unknown users or locations", dangling.len());
+                for id in &dangling {
+                    visits.remove(id);
                 }
+            }
+
+            let visits: Vec<&Visit> = visits.values().collect();
+            let country = |id| locations.get(&id).map_or("", |location| location.country.as_str());
+            let rater = |id| users.get(&id).map(Rater::from);
+            Index::build(&visits, threads, country, rater)
+        };
+        info!("Indexed {} visits in {:?} on {} threads", self.visits.len(), started.elapsed(), threads);
+
+        // the data isn't checked for duplicates, the last user keeps the email
+        let emails = self.users.read_all().values()
+            .map(|user| (user.email.clone(), user.id))
+            .collect();
 
-                let mut bytes = Vec::new();
-                file.read_to_end(&mut bytes)?;
-                let Visits { visits } = serde_json::from_slice(&bytes)?;
-                for visit in visits {
-                    database.visits.insert(visit.id, visit.clone());
-                    database.visits_by_location.entry(visit.location)
-                        .or_insert_with(Default::default)
-                        .insert(visit.visited_at, visit.clone());
-                    database.visits_by_user.entry(visit.user)
-                        .or_insert_with(Default::default)
-                        .insert(visit.visited_at, visit.clone());
-                }     
+        self.index = RwLock::new(index);
+        self.emails = RwLock::new(emails);
+    }
+
+    fn load_parallel(&self, path: &Path, members: usize, chunk: usize) -> Result<usize, Box<Error>> {
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..members).step_by(chunk)
+                .map(|start| {
+                    let end = cmp::min(start + chunk, members);
+                    scope.spawn(move || self.load_members(path, start..end))
+                })
+                .collect();
+
+            let mut replaced = 0;
+            for worker in workers {
+                replaced += worker.join().expect("Loading thread panic")
+                    .map_err(|e| e as Box<Error>)?;
             }
+            Ok(replaced)
+        })
+    }
+
+    // returns the number of entities replaced by ones with the same id
+    fn load_members(&self, path: &Path, members: Range<usize>) -> Result<usize, Box<Error + Send + Sync>> {
+        let mut archive = ZipArchive::new(File::open(path)?)?;
+        let mut replaced = 0;
+        for i in members {
+            let file = archive.by_index(i)
+                .map_err(|e| format!("Unable to read member #{} of {}: {}", i, path.display(), e))?;
+            let name = file.name().to_string();
+            replaced += self.load_member(file)
+                .map_err(|e| format!("Unable to load {} from {}: {}", name, path.display(), e))?;
         }
+        Ok(replaced)
+    }
+
+    fn load_member(&self, mut file: ZipFile) -> Result<usize, Box<Error + Send + Sync>> {
+        let mut replaced = 0;
+        if file.name().starts_with("users") {
+            #[derive(Deserialize)]
+            struct Users {
+                users: Vec<User>
+            }
+
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            let Users { users } = serde_json::from_slice(&bytes)?;
+            for user in users {
+                replaced += self.users.insert(user.id, user).is_some() as usize;
+            }
+        } else if file.name().starts_with("locations") {
+            #[derive(Deserialize)]
+            struct Locations {
+                locations: Vec<Location>
+            }
 
-        Ok(database)
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            let Locations { locations } = serde_json::from_slice(&bytes)?;
+            let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+            for mut location in locations {
+                intern_location(&mut names, &mut location);
+                replaced += self.locations.insert(location.id, location).is_some() as usize;
+            }
+        } else if file.name().starts_with("visits") {
+            // the biggest files, so visits are inserted while parsing
+            let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(file));
+            let visits = &self.visits;
+            let entities = Entities::new("visits", |visit: Visit| { 
+                replaced += visits.insert(visit.id, visit).is_some() as usize;
+            });
+            entities.deserialize(&mut deserializer)?;
+            deserializer.end()?;
+        }
+        Ok(replaced)
+    }
+}
+
+#[inline]
+fn intern(names: &mut HashSet<Name>, name: Name) -> Name {
+    if let Some(pooled) = names.get(name.as_str()) {
+        return pooled.clone();
     }
-}
\ No newline at end of file
+    names.insert(name.clone());
+    name
+}
+
+#[inline]
+fn release(names: &mut HashSet<Name>, name: Name) {
+    let is_last = names.get(name.as_str())
+        .is_some_and(|pooled| pooled.ptr_eq(&name) && name.ref_count() == 2);
+    if is_last {
+        names.remove(name.as_str());
+    }
+}
+
+#[inline]
+fn intern_location(names: &mut HashSet<Name>, location: &mut Location) {
+    location.place = intern(names, location.place.clone());
+    location.country = intern(names, location.country.clone());
+    location.city = intern(names, location.city.clone());
+}
+
+// members named otherwise are skipped while loading
+#[inline]
+fn is_entities(name: &str) -> bool {
+    ["users", "locations", "visits"].iter().any(|prefix| name.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_visits(json: &str) -> serde_json::Result<Vec<Visit>> {
+        let mut visits = Vec::new();
+        {
+            let mut deserializer = serde_json::Deserializer::from_reader(json.as_bytes());
+            Entities::new("visits", |visit| visits.push(visit)).deserialize(&mut deserializer)?;
+            deserializer.end()?;
+        }
+        Ok(visits)
+    }
+
+    #[test]
+    fn stream_entities() {
+        let json = r#"{"other": [1, {"a": 2}], "visits": [
+            {"id": 1, "location": 2, "user": 3, "visited_at": 4, "mark": 5},
+            {"id": 2, "location": 2, "user": 3, "visited_at": 5, "mark": 0}
+        ]}"#;
+        let visits = parse_visits(json).unwrap();
+        assert_eq!(visits.iter().map(|visit| visit.id).collect::<Vec<_>>(), vec![VisitId(1), VisitId(2)]);
+
+        assert!(parse_visits(r#"{"visits": []}"#).unwrap().is_empty());
+        assert!(parse_visits(r#"{"users": []}"#).is_err());
+        assert!(parse_visits(r#"{"visits": [], "visits": []}"#).is_err());
+        assert!(parse_visits(r#"{"visits": [{"id": 1}]}"#).is_err());
+        assert!(parse_visits(r#"{"visits": []} []"#).is_err());
+    }
+
+    #[test]
+    fn from_entities() {
+        let user = User {
+            id: UserId(1),
+            email: "robosen@icloud.com".to_string(),
+            first_name: "Данила".to_string(),
+            last_name: "Стамленский".to_string(),
+            gender: Gender::Male,
+            birth_date: 345081600
+        };
+        let location = Location {
+            id: LocationId(1),
+            place: "Набережная".into(),
+            country: "Россия".into(),
+            city: "Москва".into(),
+            distance: 10
+        };
+        let visits = vec![
+            Visit { id: VisitId(1), location: LocationId(1), user: UserId(1), visited_at: 1000, mark: 5 },
+            // kept, as the loader does without 'strict'
+            Visit { id: VisitId(2), location: LocationId(1), user: UserId(2), visited_at: 2000, mark: 3 }
+        ];
+        let database = Database::from_entities(vec![user], vec![location], visits);
+
+        assert_eq!(database.visits.len(), 2);
+        assert_eq!(database.emails.read().unwrap().get("robosen@icloud.com"), Some(&UserId(1)));
+        assert_eq!(database.stats(), IndexStats { indexed_visits: 4, unknown_users: 1, ..Default::default() });
+
+        let index = database.index.read().unwrap();
+        assert_eq!(index.visits_by_user.get(&UserId(1)).map(|visits| visits.len()), Some(1));
+        assert_eq!(index.visits_by_user.get(&UserId(2)).map(|visits| visits.len()), Some(1));
+        assert_eq!(index.visits_by_location.get(&LocationId(1)).map(|visits| visits.len()), Some(2));
+        assert!(index.has_visit_at(UserId(1), 1000));
+    }
+
+    #[test]
+    fn sharded_index() {
+        let visits: Vec<Visit> = (1..2000u32)
+            .map(|id| Visit {
+                id: VisitId(id),
+                location: LocationId(id * 7 % 31),
+                user: UserId(id * 13 % 97),
+                // many equal times, ordered by id
+                visited_at: (id % 50) as Timestamp,
+                mark: (id % 6) as u8
+            })
+            .collect();
+        let visits: Vec<&Visit> = visits.iter().collect();
+        let country = |id: LocationId| ["Россия", "Китай", "Чехия"][id.0 as usize % 3];
+        // user 0 is unknown
+        let rater = |id: UserId| if id.0 == 0 {
+            None
+        } else {
+            let gender = [Gender::Male, Gender::Female][id.0 as usize % 2];
+            Some(Rater { gender, birth_date: id.0 as Timestamp * 10000000 })
+        };
+
+        let single = Index::build(&visits, 1, country, rater);
+        for &threads in &[2, 4, 7] {
+            let sharded = Index::build(&visits, threads, country, rater);
+            assert!(sharded.visits_by_user == single.visits_by_user);
+            assert!(sharded.visits_by_location == single.visits_by_location);
+            assert!(sharded.marks_by_location == single.marks_by_location);
+            assert!(sharded.visits_by_location_age == single.visits_by_location_age);
+            assert!(sharded.visits_by_user_country == single.visits_by_user_country);
+
+            let range = |index: &Index| index.visits_by_user[&UserId(5)]
+                .range((10, VisitId(0))..(20, VisitId(0)))
+                .map(|(_, visit)| visit.id)
+                .collect::<Vec<_>>();
+            assert!(!range(&single).is_empty());
+            assert_eq!(range(&sharded), range(&single));
+        }
+        assert_eq!(single.visits_by_user.values().map(|visits| visits.len()).sum::<usize>(), visits.len());
+    }
+}
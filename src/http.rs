@@ -1,98 +1,1979 @@
-use std::sync::{RwLock, Arc};
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
 use std::ops::Deref;
+use std::cmp;
+use std::time::{Duration, Instant};
 
-use futures::future::Future;
-use futures::stream::Stream;
+use futures::future::{self, Future, Either};
+use futures::stream::{self, Stream};
+use futures::sink::Sink;
+use futures::sync::oneshot;
+use tokio_core::reactor::{Handle, Interval, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
 
-use hyper::server::Service;
-use hyper::{self, Method, Response as HttpResponse, Request as HttpRequest};
-use hyper::header::{Headers, ContentLength};
+use hyper::server::{Http, Service};
+use hyper::{self, Method, StatusCode, Chunk, Body, Response as HttpResponse, Request as HttpRequest};
+use hyper::header::{Headers, ContentLength, Allow, ContentEncoding, AcceptEncoding, Encoding, q};
+use hyper::header::{Accept, ContentType, ETag, EntityTag, IfNoneMatch, TransferEncoding};
+use bytes::Bytes;
+use flate2::Compression;
+use flate2::write::{GzEncoder, DeflateEncoder};
+use log::Level;
 
-use api::Api;
+use api::{self, Api, ApiError, Counters};
+use database::Database;
 use router;
 
 pub struct TravelsServer {
-    pub api: Arc<RwLock<Api>>,
+    pub api: ApiHandle,
+    // value of 'Access-Control-Allow-Origin'
+    pub cors_origin: Bytes,
+    pub keep_alive: bool,
+    // limits reading of request bodies
+    pub timeout: Option<RequestTimeout>,
+    pub max_body_bytes: usize,
+    // long visit lists are streamed by tasks on this reactor, if any
+    pub handle: Option<Handle>,
+    // while set every request but 'MAINTENANCE_PATH' gets '503 Service
+    // Unavailable', shared by the servers of all threads
+    pub maintenance: Arc<AtomicBool>,
+    // 'X-Admin-Token' of 'MAINTENANCE_PATH' and 'RELOAD_PATH' requests,
+    // which are routed as usual without one
+    pub admin_token: Option<Bytes>,
+    // what 'RELOAD_PATH' loads, it's routed as usual without one
+    pub dataset: Option<Dataset>,
+    // headers of every successful POST and DELETE response, built on the
+    // first one and cloned after
+    pub post_headers: Rc<OnceCell<Headers>>
 }
 
+// The 'Api' of all threads, replaced as a whole by 'RELOAD_PATH' requests.
+// A request keeps the 'Api' it started with, so it sees a single database
+pub struct SharedApi {
+    api: RwLock<Arc<Api>>,
+    // bumped after every replacement
+    generation: AtomicU64,
+    is_reloading: AtomicBool
+}
+
+impl SharedApi {
+    pub fn new(api: Arc<Api>) -> SharedApi {
+        SharedApi { api: RwLock::new(api), generation: AtomicU64::new(0), is_reloading: AtomicBool::new(false) }
+    }
+
+    pub fn get(&self) -> Arc<Api> {
+        self.api.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn replace(&self, api: Arc<Api>) {
+        *self.api.write().unwrap_or_else(PoisonError::into_inner) = api;
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+// 'SharedApi' of a thread, which only takes the lock after a replacement
+pub struct ApiHandle {
+    shared: Arc<SharedApi>,
+    current: RefCell<(u64, Arc<Api>)>
+}
+
+impl ApiHandle {
+    pub fn new(shared: Arc<SharedApi>) -> ApiHandle {
+        let generation = shared.generation.load(Ordering::Acquire);
+        let current = RefCell::new((generation, shared.get()));
+        ApiHandle { shared, current }
+    }
+
+    #[inline]
+    pub fn get(&self) -> Arc<Api> {
+        let generation = self.shared.generation.load(Ordering::Acquire);
+        let mut current = self.current.borrow_mut();
+        if current.0 != generation {
+            *current = (generation, self.shared.get());
+        }
+        current.1.clone()
+    }
+}
+
+impl From<Arc<Api>> for ApiHandle {
+    fn from(api: Arc<Api>) -> ApiHandle {
+        ApiHandle::new(Arc::new(SharedApi::new(api)))
+    }
+}
+
+// see 'Database::from_file'
+#[derive(Clone)]
+pub struct Dataset {
+    pub path: String,
+    pub shards: usize,
+    pub strict: bool
+}
+
+#[derive(Clone)]
+pub struct RequestTimeout {
+    pub duration: Duration,
+    pub handle: Handle
+}
+
+static ERROR_RESPONSE: &[u8] = b"{}";
+static CORS_ALLOWED_METHODS: &str = "GET, HEAD, POST, DELETE";
+static CORS_ALLOWED_HEADERS: &str = "Content-Type";
+static MAINTENANCE_PATH: &str = "/admin/maintenance";
+static RELOAD_PATH: &str = "/admin/reload";
+static PING_PATH: &str = "/ping";
+// hyper adds 'Date' itself, formatted once a second
+static SERVER: &str = concat!("highloadcup/", env!("CARGO_PKG_VERSION"));
+
+// requests taking longer are logged as warnings
+const SLOW_REQUEST: Duration = Duration::from_millis(10);
+
+// 'X-Request-Id' of a request without a usable one
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// The 'X-Request-Id' of the request, if it is printable ASCII and not too
+// long, or the next one of a counter
 #[inline]
-fn read_to_end<S, I>(stream: S) -> impl Future<Item = Vec<u8>, Error = hyper::Error>
+fn request_id(headers: &Headers) -> String {
+    let received = headers.get_raw("X-Request-Id")
+        .and_then(|value| value.one())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+        .filter(|id| id.iter().all(|&byte| byte > b' ' && byte < 0x7f));
+    match received {
+        // checked to be ASCII
+        Some(id) => String::from_utf8_lossy(id).into_owned(),
+        None => format!("{:016x}", NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+pub const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+// pause after a transient accept error, see 'skip_accept_errors'
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+
+// smaller bodies are sent as is, compressing them isn't worth it
+const COMPRESSION_THRESHOLD: usize = 512;
+
+// Longer visit lists are serialized while sent, with 'Transfer-Encoding: 
+// chunked', unless they are compressed or converted
+pub const STREAMING_THRESHOLD: usize = 1000;
+
+// Chunks are serialized as the channel to the connection drains, so the whole
+// list is never buffered. The task stops if the connection is closed
+#[inline]
+fn stream_chunks<I: Iterator<Item = Bytes> + 'static>(chunks: I, handle: &Handle) -> Body {
+    let (sender, body) = Body::pair();
+    let chunks = stream::iter_ok(chunks.map(|chunk| Ok(Chunk::from(chunk))));
+    handle.spawn(sender.sink_map_err(|_| ()).send_all(chunks).map(|_| ()));
+    body
+}
+
+// gzip is preferred when the client accepts both
+#[inline]
+fn accepted_encoding(headers: &Headers) -> Option<Encoding> {
+    let accept_encoding = headers.get::<AcceptEncoding>()?;
+    let is_accepted = |encoding: &Encoding| accept_encoding.iter()
+        .any(|item| item.item == *encoding && item.quality > q(0));
+
+    if is_accepted(&Encoding::Gzip) {
+        Some(Encoding::Gzip)
+    } else if is_accepted(&Encoding::Deflate) {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+// 'application/msgpack' bodies are converted from the JSON ones, so only
+// the clients asking for them pay for it
+#[inline]
+fn accepts_msgpack(headers: &Headers) -> bool {
+    headers.get::<Accept>().is_some_and(|accept| accept.iter()
+        .any(|item| item.quality > q(0) 
+                 && item.item.type_() == "application" 
+                 && item.item.subtype() == "msgpack"))
+}
+
+// bodies without 'Content-Type' are JSON too, as they always were
+#[inline]
+fn is_json_body(headers: &Headers) -> bool {
+    if headers.get_raw("Content-Type").is_none() {
+        return true;
+    }
+    headers.get::<ContentType>().is_some_and(|ContentType(mime)| 
+        mime.type_() == "application" && mime.subtype() == "json")
+}
+
+// Coding of the request body, 'Err' for the ones that can't be decoded
+#[inline]
+fn body_encoding(headers: &Headers) -> Result<Option<Encoding>, StatusCode> {
+    let encodings = match headers.get::<ContentEncoding>() {
+        Some(ContentEncoding(encodings)) => encodings,
+        None => return Ok(None)
+    };
+    match encodings[..] {
+        [] | [Encoding::Identity] => Ok(None),
+        [Encoding::Gzip] => Ok(Some(Encoding::Gzip)),
+        _ => Err(StatusCode::UnsupportedMediaType)
+    }
+}
+
+// The body as sent before 'encoding', which isn't allowed to be longer than
+// 'max_length' either
+#[inline]
+fn decode_body(body: Vec<u8>, encoding: Option<Encoding>, max_length: usize) -> Result<Vec<u8>, StatusCode> {
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+
+    match encoding {
+        Some(Encoding::Gzip) => {
+            let mut decoded = Vec::with_capacity(cmp::min(body.len() * 4, max_length));
+            // one byte more tells a body of exactly 'max_length' from a longer one
+            GzDecoder::new(&body[..]).take(max_length as u64 + 1).read_to_end(&mut decoded)
+                .map_err(|_| StatusCode::BadRequest)?;
+            if decoded.len() > max_length {
+                return Err(StatusCode::PayloadTooLarge);
+            }
+            Ok(decoded)
+        },
+        _ => Ok(body)
+    }
+}
+
+// 'If-Match' of updates holds a version from 'X-Version', quoted or not.
+// 'None' for '*', which matches any version
+#[inline]
+fn if_match_version(headers: &Headers) -> Option<Result<Option<u64>, StatusCode>> {
+    use std::str;
+
+    let value = headers.get_raw("If-Match")?;
+    let version = value.one()
+        .and_then(|value| str::from_utf8(value).ok())
+        .map(|value| value.trim().trim_matches('"'))
+        .ok_or(StatusCode::BadRequest)
+        .and_then(|value| match value {
+            "*" => Ok(None),
+            value => value.parse().map(Some).map_err(|_| StatusCode::BadRequest)
+        });
+    Some(version)
+}
+
+#[inline]
+fn to_msgpack(json: &[u8]) -> Result<Bytes, StatusCode> {
+    use serde_json;
+    use rmp_serde;
+
+    let value: serde_json::Value = serde_json::from_slice(json)
+        .map_err(|_| StatusCode::InternalServerError)?;
+    // with field names, so the documents are the same as the JSON ones
+    rmp_serde::to_vec_named(&value)
+        .map(Bytes::from)
+        .map_err(|_| StatusCode::InternalServerError)
+}
+
+// A panicking request is answered with 500 instead of taking the worker
+// (and every connection on it) down
+fn catch_panic<T, F>(f: F) -> Result<T, ApiError>
+where
+    F: FnOnce() -> Result<T, ApiError>
+{
+    use std::panic::{self, AssertUnwindSafe};
+
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        error!("Request handler panicked");
+        Err(ApiError::from(StatusCode::InternalServerError))
+    })
+}
+
+#[inline]
+fn error_response(message: &str) -> Bytes {
+    use serde_json;
+
+    #[derive(Serialize)]
+    struct ErrorResponse<'a> {
+        error: &'a str
+    }
+
+    serde_json::to_vec(&ErrorResponse { error: message }).unwrap().into()
+}
+
+#[inline]
+fn compress(body: &[u8], encoding: &Encoding) -> Bytes {
+    let buffer = Vec::with_capacity(body.len() / 2);
+    let compressed = match *encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(buffer, Compression::fast());
+            encoder.write_all(body).and_then(|_| encoder.finish())
+        },
+        _ => {
+            let mut encoder = DeflateEncoder::new(buffer, Compression::fast());
+            encoder.write_all(body).and_then(|_| encoder.finish())
+        }
+    };
+
+    compressed.expect("Failed to compress response").into()
+}
+
+// '413 Payload Too Large' once the body exceeds 'max_length', the rest isn't
+// read. A body of other than the declared length is '400 Bad Request'
+#[inline]
+fn read_to_end<S, I>(stream: S, max_length: usize, declared_length: Option<u64>) 
+    -> impl Future<Item = Result<Vec<u8>, StatusCode>, Error = hyper::Error>
 where
     S: Stream<Item = I, Error = hyper::Error>,
     I: Deref<Target = [u8]>,
 {
     type Buffer = Result<Vec<u8>, hyper::Error>;
-    stream.fold(Vec::with_capacity(512), |mut buffer, chunk| -> Buffer {
-        buffer.extend_from_slice(&chunk);
-        Ok(buffer)
+    stream
+        .fold(Vec::with_capacity(512), move |mut buffer, chunk| -> Buffer {
+            if buffer.len() + chunk.len() > max_length {
+                // only used to stop the fold, see below
+                return Err(hyper::Error::TooLarge);
+            }
+
+            buffer.extend_from_slice(&chunk);
+            Ok(buffer)
+        })
+        .then(move |result| match result {
+            Ok(ref buffer) if declared_length.is_some_and(|length| length != buffer.len() as u64) 
+                => Ok(Err(StatusCode::BadRequest)),
+            Ok(buffer) => Ok(Ok(buffer)),
+            Err(hyper::Error::TooLarge) => Ok(Err(StatusCode::PayloadTooLarge)),
+            Err(e) => Err(e)
+        })
+}
+
+// 'None' if 'future' hasn't completed in time
+#[inline]
+fn with_timeout<F>(future: F, timeout: &RequestTimeout) -> impl Future<Item = Option<F::Item>, Error = hyper::Error>
+where
+    F: Future<Error = hyper::Error>
+{
+    let timer = Timeout::new(timeout.duration, &timeout.handle)
+        .expect("Failed to initialize request timeout");
+    future.select2(timer).then(|result| match result {
+        Ok(Either::A((item, _))) => Ok(Some(item)),
+        Ok(Either::B(((), _))) => Ok(None),
+        Err(Either::A((e, _))) => Err(e),
+        Err(Either::B((e, _))) => Err(hyper::Error::Io(e))
     })
 }
 
+// Serves the connection, closing it if the client takes longer than the 
+// timeout to send the headers of the next request. Hyper calls the service 
+// only when the headers are parsed, so the service can't limit this itself
+pub fn serve_with_timeout<I, S>(http: &Http<hyper::Chunk>, io: I, service: S, 
+                                timeout: &RequestTimeout) -> impl Future<Item = (), Error = ()> 
+where
+    I: AsyncRead + AsyncWrite + 'static,
+    S: Service<Request = HttpRequest, Response = HttpResponse, Error = hyper::Error> + 'static
+{
+    let idle = IdleService { service, idle_since: Rc::new(Cell::new(Some(Instant::now()))) };
+    let idle_since = idle.idle_since.clone();
+    let duration = timeout.duration;
+    let is_expired = move || idle_since.get()
+        .is_some_and(|since| since.elapsed() > duration);
+
+    let watchdog = Interval::new(duration / 4, &timeout.handle)
+        .expect("Failed to initialize request timeout")
+        .take_while(move |_| Ok(!is_expired()))
+        .for_each(|_| Ok(()));
+
+    http.serve_connection(io, idle)
+        .map(|_| ())
+        .select2(watchdog)
+        .then(|_| Ok(()))
+}
+
+// Last requests served, for 'GET /debug/trace'. Without the 'trace' feature
+// nothing is recorded
+#[cfg(feature = "trace")]
+mod trace {
+    use std::collections::VecDeque;
+    use std::sync::{Mutex, PoisonError};
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use hyper::{Method, StatusCode};
+    use serde_json;
+
+    // the oldest requests are overwritten
+    pub const CAPACITY: usize = 1024;
+
+    #[derive(Serialize)]
+    struct TraceEntry {
+        method: String,
+        path: String,
+        status: u16,
+        duration_us: u64
+    }
+
+    lazy_static! {
+        static ref TRACE: Mutex<VecDeque<TraceEntry>> = Mutex::new(VecDeque::with_capacity(CAPACITY));
+    }
+
+    pub fn record(method: &Method, path: &str, status: StatusCode, duration: Duration) {
+        let entry = TraceEntry {
+            method: method.to_string(),
+            path: path.to_string(),
+            status: status.as_u16(),
+            duration_us: duration.as_secs() * 1000000 + duration.subsec_nanos() as u64 / 1000
+        };
+
+        let mut trace = TRACE.lock().unwrap_or_else(PoisonError::into_inner);
+        if trace.len() == CAPACITY {
+            trace.pop_front();
+        }
+        trace.push_back(entry);
+    }
+
+    // oldest first
+    pub fn to_json() -> Bytes {
+        #[derive(Serialize)]
+        struct TraceResponse<'a> {
+            requests: &'a VecDeque<TraceEntry>
+        }
+
+        let trace = TRACE.lock().unwrap_or_else(PoisonError::into_inner);
+        serde_json::to_vec(&TraceResponse { requests: &trace }).unwrap().into()
+    }
+}
+
+// Counts the connection in 'active_connections' until it's closed or 
+// dropped with the reactor
+pub fn track_connection<F>(connection: F, counters: Arc<Counters>) -> impl Future<Item = (), Error = ()>
+where
+    F: Future<Item = (), Error = ()>
+{
+    let guard = ConnectionGuard::new(counters);
+    connection.then(move |result| {
+        drop(guard);
+        result
+    })
+}
+
+struct ConnectionGuard {
+    counters: Arc<Counters>
+}
+
+impl ConnectionGuard {
+    fn new(counters: Arc<Counters>) -> ConnectionGuard {
+        counters.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { counters }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.counters.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Transient accept errors are counted and skipped, so running out of file
+// descriptors doesn't stop the server. Pending connections stay in the 
+// backlog, so accepting is paused for ACCEPT_BACKOFF instead of spinning.
+// Other errors end the stream
+pub fn skip_accept_errors<S>(incoming: S, counters: Arc<Counters>, handle: &Handle) 
+    -> impl Stream<Item = S::Item, Error = io::Error>
+where
+    S: Stream<Error = io::Error>
+{
+    let handle = handle.clone();
+    incoming
+        .then(move |result| match result {
+            Ok(connection) => Either::A(future::ok(Some(connection))),
+            Err(e) => {
+                counters.accept_errors.fetch_add(1, Ordering::Relaxed);
+                if !is_transient_accept_error(&e) {
+                    error!("Unable to accept connections: {}", e);
+                    return Either::A(future::err(e));
+                }
+
+                warn!("Unable to accept connection: {}", e);
+                let backoff = Timeout::new(ACCEPT_BACKOFF, &handle)
+                    .expect("Failed to initialize accept backoff");
+                Either::B(backoff.map(|_| None))
+            }
+        })
+        .filter_map(|connection| connection)
+}
+
+#[inline]
+fn is_transient_accept_error(e: &io::Error) -> bool {
+    use libc::{EMFILE, ENFILE, ENOBUFS, ENOMEM};
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | 
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | 
+        io::ErrorKind::TimedOut => true,
+        _ => e.raw_os_error().is_some_and(|code| [EMFILE, ENFILE, ENOBUFS, ENOMEM].contains(&code))
+    }
+}
+
+// Notes when the connection waits for a request, 'None' while one is served
+struct IdleService<S> {
+    service: S,
+    idle_since: Rc<Cell<Option<Instant>>>
+}
+
+impl<S: Service + 'static> Service for IdleService<S> {
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    #[inline]
+    fn call(&self, request: Self::Request) -> Self::Future {
+        self.idle_since.set(None);
+        let idle_since = self.idle_since.clone();
+        Box::new(self.service.call(request).then(move |result| {
+            idle_since.set(Some(Instant::now()));
+            result
+        }))
+    }
+}
+
+// Token bucket per client IP, 'rps' tokens at most and refilled at 'rps' per
+// second, every request taking one. Shared by all workers, since with
+// 'SO_REUSEPORT' connections of a client end up in different threads
+pub struct RateLimiter {
+    rps: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+// beyond that full buckets are forgotten, as they are the same as new ones
+const MAX_RATE_LIMITED_CLIENTS: usize = 65536;
+
+impl RateLimiter {
+    pub fn new(rps: u32) -> RateLimiter {
+        assert!(rps > 0, "Rate limit must be positive");
+        RateLimiter { rps, buckets: Default::default() }
+    }
+
+    // takes a token if the client has one
+    pub fn check(&self, client: IpAddr, now: Instant) -> bool {
+        let rps = self.rps as f64;
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if buckets.len() >= MAX_RATE_LIMITED_CLIENTS && !buckets.contains_key(&client) {
+            let refill = Duration::from_secs(1);
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < refill);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: rps, updated: now });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rps).min(rps);
+        bucket.updated = cmp::max(bucket.updated, now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+// The service of a connection from 'client', answering '429 Too Many 
+// Requests' once it runs out of tokens
+pub struct RateLimited {
+    pub server: Rc<TravelsServer>,
+    pub limiter: Option<Arc<RateLimiter>>,
+    pub client: IpAddr
+}
+
+impl Service for RateLimited {
+    type Request = HttpRequest;
+    type Response = HttpResponse;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    #[inline]
+    fn call(&self, request: Self::Request) -> Self::Future {
+        if let Some(ref limiter) = self.limiter {
+            if !limiter.check(self.client, Instant::now()) {
+                self.server.api.get().counters.rate_limited.fetch_add(1, Ordering::Relaxed);
+                let mut response = self.server.plain_response(StatusCode::TooManyRequests, ERROR_RESPONSE);
+                response.headers_mut().set_raw("X-Request-Id", request_id(request.headers()));
+                return Box::new(future::ok(response));
+            }
+        }
+        self.server.call(request)
+    }
+}
+
+impl TravelsServer {
+    // 'POST /admin/maintenance?enabled=<bool>'
+    fn set_maintenance(&self, token: &Bytes, headers: &Headers, query: Option<&str>) -> HttpResponse {
+        let is_authorized = headers.get_raw("X-Admin-Token")
+            .and_then(|value| value.one())
+            .is_some_and(|value| value == &token[..]);
+        let enabled = query.unwrap_or("").split('&')
+            .find_map(|parameter| parameter.strip_prefix("enabled="))
+            .and_then(|enabled| enabled.parse().ok());
+
+        let (code, body): (_, &'static [u8]) = match enabled {
+            _ if !is_authorized => (StatusCode::Forbidden, ERROR_RESPONSE),
+            Some(true) => (StatusCode::Ok, b"{\"maintenance\":true}"),
+            Some(false) => (StatusCode::Ok, b"{\"maintenance\":false}"),
+            None => (StatusCode::BadRequest, ERROR_RESPONSE)
+        };
+        if let Some(enabled) = enabled.filter(|_| is_authorized) {
+            info!("Maintenance mode {}", if enabled { "enabled" } else { "disabled" });
+            self.maintenance.store(enabled, Ordering::SeqCst);
+        }
+        self.plain_response(code, body)
+    }
+
+    // 'POST /admin/reload', loads the dataset on another thread in maintenance
+    // mode, so writes to the old database aren't silently lost. In-flight 
+    // requests finish with the old one. With a log the mutations in it would 
+    // be replayed over the new dataset on restart, so the request is refused
+    fn reload(&self, token: &Bytes, headers: &Headers, dataset: &Dataset) -> Box<Future<Item = HttpResponse, Error = hyper::Error>> {
+        let is_authorized = headers.get_raw("X-Admin-Token")
+            .and_then(|value| value.one())
+            .is_some_and(|value| value == &token[..]);
+        let api = self.api.get();
+        let shared = self.api.shared.clone();
+        if !is_authorized {
+            return Box::new(future::ok(self.plain_response(StatusCode::Forbidden, ERROR_RESPONSE)));
+        }
+        if api.has_wal() || shared.is_reloading.swap(true, Ordering::SeqCst) {
+            return Box::new(future::ok(self.plain_response(StatusCode::Conflict, ERROR_RESPONSE)));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let dataset = dataset.clone();
+        let maintenance = self.maintenance.clone();
+        thread::spawn(move || {
+            let was_maintenance = maintenance.swap(true, Ordering::SeqCst);
+            info!("Reloading database from {}", dataset.path);
+            let response = match Database::from_file(&dataset.path, dataset.shards, dataset.strict) {
+                Ok(database) => {
+                    let body = format!(r#"{{"users":{},"locations":{},"visits":{}}}"#, 
+                                       database.users.len(), database.locations.len(), database.visits.len());
+                    shared.replace(Arc::new(api.with_database(database)));
+                    info!("Reloaded database from {}", dataset.path);
+                    (StatusCode::Ok, Bytes::from(body))
+                },
+                Err(e) => {
+                    error!("Unable to reload database: {}", e);
+                    (StatusCode::InternalServerError, Bytes::from_static(ERROR_RESPONSE))
+                }
+            };
+            maintenance.store(was_maintenance, Ordering::SeqCst);
+            shared.is_reloading.store(false, Ordering::SeqCst);
+            let _ = sender.send(response);
+        });
+
+        let cors_origin = self.cors_origin.clone();
+        let keep_alive = self.keep_alive;
+        Box::new(receiver.then(move |response| {
+            let (code, body) = response
+                .unwrap_or((StatusCode::InternalServerError, Bytes::from_static(ERROR_RESPONSE)));
+            Ok(plain_response(code, body, cors_origin, keep_alive))
+        }))
+    }
+
+    #[inline]
+    fn ping_response(&self) -> HttpResponse {
+        let mut headers = Headers::with_capacity(4);
+        headers.set(ContentLength(0));
+        headers.set_raw("Access-Control-Allow-Origin", self.cors_origin.clone());
+        headers.set_raw("Connection", if self.keep_alive { "keep-alive" } else { "close" });
+        headers.set_raw("Server", SERVER);
+        HttpResponse::new().with_headers(headers)
+    }
+
+    #[inline]
+    fn plain_response(&self, code: StatusCode, body: &'static [u8]) -> HttpResponse {
+        plain_response(code, Bytes::from_static(body), self.cors_origin.clone(), self.keep_alive)
+    }
+}
+
+#[inline]
+fn plain_response(code: StatusCode, body: Bytes, cors_origin: Bytes, keep_alive: bool) -> HttpResponse {
+    let mut headers = Headers::with_capacity(6);
+    headers.set(ContentLength(body.len() as u64));
+    headers.set_raw("Content-Type", "application/json");
+    headers.set_raw("Access-Control-Allow-Origin", cors_origin);
+    headers.set_raw("Connection", if keep_alive { "keep-alive" } else { "close" });
+    headers.set_raw("Server", SERVER);
+    // a second refills at least one token of a rate limited client
+    if code == StatusCode::ServiceUnavailable || code == StatusCode::TooManyRequests {
+        headers.set_raw("Retry-After", "1");
+    }
+    HttpResponse::new()
+        .with_headers(headers)
+        .with_status(code)
+        .with_body(body)
+}
+
 impl Service for TravelsServer {
     type Request = HttpRequest;
     type Response = HttpResponse;
     type Error = hyper::Error;
     type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
 
+    // every response echoes the id of the request, or one made up for it
     #[inline]
     fn call(&self, request: Self::Request) -> Self::Future {
-        let (method, uri, _http_version, _headers, body) = request.deconstruct();
-        let is_post = method == Method::Post;
-        let read_body = read_to_end(body);
+        let request_id = request_id(request.headers());
+        let response_id = request_id.clone();
+        Box::new(self.serve(request, request_id).map(move |mut response| {
+            response.headers_mut().set_raw("X-Request-Id", response_id);
+            response
+        }))
+    }
+}
+
+impl TravelsServer {
+    #[inline]
+    fn serve(&self, request: HttpRequest, request_id: String) -> Box<Future<Item = HttpResponse, Error = hyper::Error>> {
+        let (method, uri, _http_version, headers, body) = request.deconstruct();
+        // the cheapest probe, answered without the api or any of its locks
+        if (method == Method::Get || method == Method::Head) && uri.path() == PING_PATH {
+            return Box::new(future::ok(self.ping_response()));
+        }
+        if let Some(ref token) = self.admin_token {
+            if method == Method::Post && uri.path() == MAINTENANCE_PATH {
+                return Box::new(future::ok(self.set_maintenance(token, &headers, uri.query())));
+            }
+            if let Some(ref dataset) = self.dataset {
+                if method == Method::Post && uri.path() == RELOAD_PATH {
+                    return self.reload(token, &headers, dataset);
+                }
+            }
+        }
+        // skips routing, so nothing changes the database while it is reloaded
+        if self.maintenance.load(Ordering::Relaxed) {
+            return Box::new(future::ok(self.plain_response(StatusCode::ServiceUnavailable, ERROR_RESPONSE)));
+        }
+
+        // hyper goes by 'Transfer-Encoding', a proxy in front may go by the
+        // length and take the rest of the body for another request
+        if headers.has::<TransferEncoding>() && headers.has::<ContentLength>() {
+            let mut response = self.plain_response(StatusCode::BadRequest, ERROR_RESPONSE);
+            response.headers_mut().set_raw("Connection", "close");
+            return Box::new(future::ok(response));
+        }
+
+        // must agree with 'Http::keep_alive' the connections are served with
+        let connection = if self.keep_alive { "keep-alive" } else { "close" };
+        let is_head = method == Method::Head;
+        let is_write = method == Method::Post || method == Method::Delete;
+        let is_preflight = method == Method::Options && uri.path() != "/";
+        let encoding = accepted_encoding(&headers);
+        let is_msgpack = accepts_msgpack(&headers);
+        let if_none_match = headers.get::<IfNoneMatch>().cloned();
+        let if_match = if_match_version(&headers);
+        let started = Instant::now();
+        // cloning 'Uri' is cheap, but still skip it when nothing is logged
+        let request_line = if cfg!(feature = "trace") || log_enabled!(Level::Warn) {
+            Some((method.clone(), uri.clone(), request_id))
+        } else {
+            None
+        };
+
+        let api = self.api.get();
+        let counter = match method {
+            Method::Get => Some(&api.counters.get_requests),
+            Method::Post => Some(&api.counters.post_requests),
+            _ => None
+        };
+        if let Some(counter) = counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let is_too_large = headers.get::<ContentLength>()
+            .is_some_and(|&ContentLength(length)| length > self.max_body_bytes as u64);
+
+        let is_unsupported = method == Method::Post && !is_json_body(&headers);
+        let body_encoding = body_encoding(&headers);
 
-        let api = self.api.clone();
+        type ReadBody = Box<Future<Item = Result<Vec<u8>, StatusCode>, Error = hyper::Error>>;
+        let read_body: ReadBody = if is_too_large {
+            Box::new(future::ok(Err(StatusCode::PayloadTooLarge)))
+        } else if is_unsupported {
+            Box::new(future::ok(Err(StatusCode::UnsupportedMediaType)))
+        } else if let Err(code) = body_encoding {
+            Box::new(future::ok(Err(code)))
+        } else {
+            let declared_length = headers.get::<ContentLength>().map(|&ContentLength(length)| length);
+            let read_body = read_to_end(body, self.max_body_bytes, declared_length);
+            match self.timeout {
+                Some(ref timeout) => Box::new(with_timeout(read_body, timeout)
+                    .map(|body| body.unwrap_or(Err(StatusCode::RequestTimeout)))),
+                None => Box::new(read_body)
+            }
+        };
+
+        let cors_origin = self.cors_origin.clone();
+        let handle = self.handle.clone();
+        let post_headers = self.post_headers.clone();
+        let max_body_bytes = self.max_body_bytes;
+        let request_encoding = body_encoding.unwrap_or(None);
         let http_response = read_body.map(move |body| {
-            use request::Request;
-            let result = router::route(method, uri, &body)
-                .and_then(|request| match request {
-                    Request::Get(request) => {
-                        let lock = api.read().expect("Failed to lock (read)");
-                        lock.do_get(request)
-                    }
-                    Request::Post(request) => {
-                        let mut lock = api.write().expect("Failed to lock (write)");
-                        lock.do_post(request)
-                    }
-            });
+            use request::{Request, GetRequest, PostRequest};
+            let mut is_entity = false;
+            let mut version = None;
+            let mut is_metrics = false;
+            // exports are always sent as is, to keep the memory flat
+            let mut is_export = false;
+            let mut streamed: Option<Box<Iterator<Item = Bytes>>> = None;
+            let result = body
+                .and_then(|body| decode_body(body, request_encoding, max_body_bytes))
+                .map_err(ApiError::from)
+                .and_then(|body| router::route(method, uri, &body))
+                .and_then(|request| catch_panic(|| match request {
+                    Request::Get(GetRequest::GetVisits(id, ref parameters)) 
+                    if handle.is_some() && !parameters.count && !is_msgpack && encoding.is_none() => {
+                        let chunks = Api::visit_chunks(api.clone(), id, parameters)?;
+                        if chunks.len() > STREAMING_THRESHOLD {
+                            streamed = Some(Box::new(chunks));
+                            Ok(Bytes::new())
+                        } else {
+                            Ok(chunks.collect::<Vec<_>>().concat().into())
+                        }
+                    },
+                    Request::Get(GetRequest::Export(kind)) if handle.is_some() => {
+                        is_export = true;
+                        streamed = Some(Box::new(Api::export_chunks(api.clone(), kind)));
+                        Ok(Bytes::new())
+                    },
+                    Request::Get(GetRequest::GetEntity(request)) | Request::Head(GetRequest::GetEntity(request)) => {
+                        is_entity = true;
+                        let (body, entity_version) = api.do_get_versioned(request)?;
+                        version = Some(entity_version);
+                        Ok(body)
+                    },
+                    Request::Get(request) | Request::Head(request) => {
+                        is_metrics = matches!(request, GetRequest::Metrics);
+                        is_export = matches!(request, GetRequest::Export(_));
+                        api.do_get(request).map_err(ApiError::from)
+                    },
+                    Request::Post(PostRequest::UpdateEntity(update)) => match if_match {
+                        Some(if_match) => match if_match? {
+                            Some(version) => api.do_post(PostRequest::UpdateEntityIfVersion(update, version)),
+                            None => api.do_post(PostRequest::UpdateEntity(update))
+                        },
+                        None => api.do_post(PostRequest::UpdateEntity(update))
+                    },
+                    Request::Post(request) => api.do_post(request),
+                    Request::Delete(request) => api.do_delete(request),
+                    Request::Preflight => Ok(Bytes::new()),
+                    Request::Discovery => Ok(router::discovery()),
+                    #[cfg(feature = "trace")]
+                    Request::Trace => Ok(trace::to_json())
+                }))
+                .and_then(|response| if is_msgpack && !is_metrics && !is_export && !response.is_empty() {
+                    to_msgpack(&response).map_err(ApiError::from)
+                } else {
+                    Ok(response)
+                });
 
-            match result {
+            let (http_response, body) = match result {
+                Ok(response) if is_write && !is_msgpack && response == api::POST_RESPONSE => {
+                    let headers = post_headers.get_or_init(|| {
+                        let mut headers = Headers::with_capacity(5);
+                        headers.set(ContentLength(response.len() as u64));
+                        headers.set_raw("Content-Type", "application/json");
+                        headers.set_raw("Access-Control-Allow-Origin", cors_origin.clone());
+                        headers.set_raw("Connection", connection);
+                        headers.set_raw("Server", SERVER);
+                        headers
+                    });
+                    (HttpResponse::new().with_headers(headers.clone()), response.into())
+                },
                 Ok(response) => {
+                    let etag = if is_entity {
+                        Some(EntityTag::strong(api::entity_tag(&response)))
+                    } else {
+                        None
+                    };
+                    let is_not_modified = match (&etag, &if_none_match) {
+                        (Some(_), Some(IfNoneMatch::Any)) => true,
+                        (Some(etag), Some(IfNoneMatch::Items(tags))) 
+                            => tags.iter().any(|tag| tag.weak_eq(etag)),
+                        _ => false
+                    };
+
+                    let encoding = encoding
+                        .filter(|_| response.len() > COMPRESSION_THRESHOLD && !is_export);
+                    let response = match encoding {
+                        Some(ref encoding) => compress(&response, encoding),
+                        None => response
+                    };
+
                     let headers = {
-                        let mut headers = Headers::with_capacity(3);
+                        let mut headers = Headers::with_capacity(8);
                         headers.set(ContentLength(response.len() as u64));
+                        if is_preflight {
+                            headers.set_raw("Access-Control-Allow-Methods", CORS_ALLOWED_METHODS);
+                            headers.set_raw("Access-Control-Allow-Headers", CORS_ALLOWED_HEADERS);
+                        }
+                        if let Some(encoding) = encoding {
+                            headers.set(ContentEncoding(vec![encoding]));
+                        }
                         // raw headers to avoid allocation
-                        headers.set_raw("Content-Type", "application/json");
-                        if is_post {
-                            headers.set_raw("Connection", "close");
-                        } else {
-                            headers.set_raw("Connection", "keep-alive");
+                        let content_type = if is_metrics {
+                            "text/plain; version=0.0.4"
+                        } else if is_export {
+                            "application/x-ndjson"
+                        } else if is_msgpack { 
+                            "application/msgpack" 
+                        } else { 
+                            "application/json" 
+                        };
+                        headers.set_raw("Content-Type", content_type);
+                        headers.set_raw("Access-Control-Allow-Origin", cors_origin.clone());
+                        headers.set_raw("Connection", connection);
+                        headers.set_raw("Server", SERVER);
+                        if let Some(etag) = etag {
+                            headers.set(ETag(etag));
+                        }
+                        if let Some(version) = version {
+                            headers.set_raw("X-Version", version.to_string());
                         }
                         headers
                     };
 
-                    HttpResponse::new()
-                        .with_headers(headers)
-                        .with_body(response)
+                    if is_not_modified {
+                        let mut headers = headers;
+                        headers.remove::<ContentLength>();
+                        let http_response = HttpResponse::new()
+                            .with_headers(headers)
+                            .with_status(StatusCode::NotModified);
+                        (http_response, Body::empty())
+                    } else if let (Some(chunks), Some(handle)) = (streamed.take(), handle.as_ref()) {
+                        let mut headers = headers;
+                        headers.remove::<ContentLength>();
+                        (HttpResponse::new().with_headers(headers), stream_chunks(chunks, handle))
+                    } else {
+                        (HttpResponse::new().with_headers(headers), response.into())
+                    }
                 }
-                Err(code) => {
+                Err(ApiError { code, message }) => {
+                    let body = if message.is_empty() {
+                        Bytes::from_static(ERROR_RESPONSE)
+                    } else {
+                        error_response(message)
+                    };
+
                     let headers = {
-                        let mut headers = Headers::with_capacity(2);
-                        headers.set_raw("Content-Type", "json");
-                        if is_post {
-                            headers.set_raw("Connection", "close");
-                        } else {
-                            headers.set_raw("Connection", "keep-alive");
+                        let mut headers = Headers::with_capacity(6);
+                        headers.set(ContentLength(body.len() as u64));
+                        headers.set_raw("Content-Type", "application/json");
+                        headers.set_raw("Access-Control-Allow-Origin", cors_origin.clone());
+                        if code == StatusCode::MethodNotAllowed {
+                            headers.set(Allow(router::ALLOWED_METHODS.to_vec()));
                         }
+                        headers.set_raw("Connection", connection);
+                        headers.set_raw("Server", SERVER);
                         headers
                     };
 
-                    HttpResponse::new()
+                    let http_response = HttpResponse::new()
                         .with_headers(headers)
-                        .with_status(code)
+                        .with_status(code);
+                    (http_response, body.into())
                 }
+            };
+
+            let elapsed = started.elapsed();
+            api.counters.request_durations.observe(elapsed);
+            if let Some((method, uri, request_id)) = request_line {
+                let status = http_response.status();
+                if elapsed > SLOW_REQUEST {
+                    warn!("Slow request {} {} {} {:?} {}", method, uri.path(), status, elapsed, request_id);
+                } else {
+                    debug!("{} {} {} {:?} {}", method, uri.path(), status, elapsed, request_id);
+                }
+
+                #[cfg(feature = "trace")]
+                trace::record(&method, uri.path(), status, elapsed);
+            }
+
+            // hyper keeps 'Content-Length' of a body-less response to HEAD
+            if is_head || http_response.status() == StatusCode::NotModified {
+                http_response
+            } else {
+                http_response.with_body(body)
             }
         });
 
         Box::new(http_response)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use hyper::Request;
+    use flate2::read::{GzDecoder, DeflateDecoder};
+    use database::Database;
+    use data::*;
+    use request::{PostRequest, CreateEntity};
+
+    fn server() -> TravelsServer {
+        let api = Api::new(Database::default());
+        TravelsServer { 
+            api: Arc::new(api).into(), 
+            cors_origin: Bytes::from_static(b"*"), 
+            keep_alive: true, 
+            timeout: None,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            handle: None,
+            maintenance: Default::default(),
+            admin_token: None,
+            dataset: None,
+            post_headers: Default::default()
+        }
+    }
+
+    #[test]
+    fn health() {
+        let request = Request::new(Method::Get, "/health".parse().unwrap());
+        let response = server().call(request).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.headers().get_raw("Server").unwrap(), SERVER);
+
+        let body = response.body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"{\"status\":\"ok\"}");
+    }
+
+    #[test]
+    fn request_id() {
+        let server = server();
+        let id = |request_id: Option<&str>| {
+            let mut request = Request::new(Method::Get, "/users/1".parse().unwrap());
+            if let Some(request_id) = request_id {
+                request.headers_mut().set_raw("X-Request-Id", request_id.to_string());
+            }
+            let response = server.call(request).wait().unwrap();
+            assert_eq!(response.status(), StatusCode::NotFound);
+            let raw = response.headers().get_raw("X-Request-Id").unwrap().one().unwrap().to_vec();
+            String::from_utf8(raw).unwrap()
+        };
+
+        assert_eq!(id(Some("f81d4fae-7dec-11d0-a765-00a0c91e6bf6")), "f81d4fae-7dec-11d0-a765-00a0c91e6bf6");
+        let (first, second) = (id(None), id(None));
+        assert!(!first.is_empty() && first != second);
+        for invalid in &["", "two words", "имя"] {
+            assert_ne!(id(Some(invalid)), *invalid);
+        }
+        assert_eq!(id(Some(&"a".repeat(MAX_REQUEST_ID_LENGTH))).len(), MAX_REQUEST_ID_LENGTH);
+        assert_ne!(id(Some(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1))).len(), MAX_REQUEST_ID_LENGTH + 1);
+
+        // answered before routing
+        let mut request = Request::new(Method::Get, "/ping".parse().unwrap());
+        request.headers_mut().set_raw("X-Request-Id", "ping-1");
+        let response = server.call(request).wait().unwrap();
+        assert_eq!(response.headers().get_raw("X-Request-Id").unwrap(), "ping-1");
+    }
+
+    #[test]
+    fn ping() {
+        use std::sync::mpsc;
+
+        let server = server();
+        let api = server.api.get();
+        let shared = server.api.shared.clone();
+        let (locked, is_locked) = mpsc::channel();
+        // a long update holding every lock the api has
+        let update = thread::spawn(move || {
+            let _shared = shared.api.write().unwrap();
+            let _index = api.database.index.write().unwrap();
+            let _users = api.database.users.write(&UserId(1));
+            locked.send(()).unwrap();
+            thread::sleep(Duration::from_millis(500));
+        });
+        is_locked.recv().unwrap();
+
+        let started = Instant::now();
+        for method in &[Method::Get, Method::Head] {
+            let response = server.call(Request::new(method.clone(), "/ping".parse().unwrap())).wait().unwrap();
+            assert_eq!(response.status(), StatusCode::Ok);
+            assert_eq!(response.headers().get::<ContentLength>(), Some(&ContentLength(0)));
+            assert!(response.headers().get_raw("Content-Type").is_none());
+            assert!(response.body().concat2().wait().unwrap().is_empty());
+        }
+        assert!(started.elapsed() < Duration::from_millis(250), "{:?}", started.elapsed());
+        update.join().unwrap();
+    }
+
+    #[test]
+    fn request_counters() {
+        let server = server();
+        for &(ref method, path) in &[(Method::Get, "/users/1"), (Method::Get, "/health"), (Method::Post, "/users/1")] {
+            let request = Request::new(method.clone(), path.parse().unwrap());
+            server.call(request).wait().unwrap();
+        }
+
+        let request = Request::new(Method::Get, "/stats".parse().unwrap());
+        let response = server.call(request).wait().unwrap();
+        let body = response.body().concat2().wait().unwrap();
+        assert_eq!(&body[..], 
+            &br#"{"users":0,"locations":0,"visits":0,"get_requests":3,"post_requests":1,"active_connections":0,"accept_errors":0,"rate_limited":0}"#[..]);
+    }
+
+    #[test]
+    fn connection_counters() {
+        use futures::sync::oneshot;
+
+        let server = server();
+        let active = || server.api.get().counters.active_connections.load(Ordering::Relaxed);
+
+        let (close_first, first) = oneshot::channel::<()>();
+        let (_close_second, second) = oneshot::channel::<()>();
+        let first = track_connection(first.map_err(|_| ()), server.api.get().counters.clone());
+        let second = track_connection(second.map_err(|_| ()), server.api.get().counters.clone());
+        assert_eq!(active(), 2);
+
+        close_first.send(()).unwrap();
+        first.wait().unwrap();
+        assert_eq!(active(), 1);
+        // closed along with the reactor
+        drop(second);
+        assert_eq!(active(), 0);
+    }
+
+    #[test]
+    fn accept_errors() {
+        use libc::{EMFILE, EBADF};
+        use tokio_core::reactor::Core;
+
+        let server = server();
+        let mut core = Core::new().unwrap();
+        let accept = |results: Vec<io::Result<u32>>, core: &mut Core| {
+            let incoming = futures::stream::iter_result(results);
+            let accepted = skip_accept_errors(incoming, server.api.get().counters.clone(), &core.handle());
+            core.run(accepted.collect())
+        };
+
+        let emfile = || io::Error::from_raw_os_error(EMFILE);
+        let aborted = || io::Error::from(io::ErrorKind::ConnectionAborted);
+        let accepted = accept(vec![Ok(1), Err(emfile()), Ok(2), Err(aborted()), Ok(3)], &mut core);
+        assert_eq!(accepted.unwrap(), vec![1, 2, 3]);
+        assert_eq!(server.api.get().counters.accept_errors.load(Ordering::Relaxed), 2);
+
+        let ebadf = io::Error::from_raw_os_error(EBADF);
+        let accepted = accept(vec![Ok(1), Err(ebadf), Ok(2)], &mut core);
+        assert_eq!(accepted.unwrap_err().raw_os_error(), Some(EBADF));
+        assert_eq!(server.api.get().counters.accept_errors.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn error_response() {
+        let request = Request::new(Method::Get, "/users/1".parse().unwrap());
+        let response = server().call(request).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::NotFound);
+        assert_eq!(response.headers().get_raw("Content-Type").unwrap(), "application/json");
+        assert_eq!(response.headers().get(), Some(&ContentLength(2)));
+
+        let body = response.body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"{}");
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn debug_trace() {
+        use serde_json::{self, Value};
+
+        let server = server();
+        for path in &["/users/1?trace=1", "/health?trace=2"] {
+            let request = Request::new(Method::Get, path.parse().unwrap());
+            server.call(request).wait().unwrap();
+        }
+
+        let request = Request::new(Method::Get, "/debug/trace".parse().unwrap());
+        let response = server.call(request).wait().unwrap();
+        let body = response.body().concat2().wait().unwrap();
+        let trace: Value = serde_json::from_slice(&body).unwrap();
+        let requests = trace["requests"].as_array().unwrap();
+        assert!(requests.len() <= trace::CAPACITY);
+
+        // other tests write to the same buffer
+        let statuses: Vec<_> = requests.iter()
+            .filter(|request| request["path"] == "/users/1" || request["path"] == "/health")
+            .map(|request| (request["method"].clone(), request["status"].clone()))
+            .collect();
+        assert!(statuses.contains(&(Value::from("GET"), Value::from(404))));
+        assert!(statuses.contains(&(Value::from("GET"), Value::from(200))));
+    }
+
+    #[test]
+    fn prometheus_metrics() {
+        use std::collections::HashSet;
+
+        let server = server_with_visits();
+        for path in &["/users/1", "/users/1/visits", "/health"] {
+            server.call(Request::new(Method::Get, path.parse().unwrap())).wait().unwrap();
+        }
+
+        let response = server.call(Request::new(Method::Get, "/metrics".parse().unwrap())).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.headers().get_raw("Content-Type").unwrap(), "text/plain; version=0.0.4");
+        let body = response.body().concat2().wait().unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+
+        // 'name{labels} value' lines, the labels are quoted key-value pairs
+        let mut names = HashSet::new();
+        let mut values = Vec::new();
+        for line in metrics.lines().filter(|line| !line.starts_with('#')) {
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            let value: f64 = value.parse().unwrap_or_else(|_| panic!("Invalid value: {}", line));
+            let name = match series.split_once('{') {
+                Some((name, labels)) => {
+                    let labels = labels.strip_suffix('}').unwrap();
+                    for label in labels.split(',') {
+                        let (key, value) = label.split_once('=').unwrap();
+                        assert!(key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'), "{}", line);
+                        assert!(value.len() >= 2 && value.starts_with('"') && value.ends_with('"'), "{}", line);
+                    }
+                    name
+                },
+                None => series
+            };
+            assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'), "{}", line);
+            names.insert(name.to_string());
+            values.push((series.to_string(), value));
+        }
+
+        for name in &["http_requests_total", "http_request_duration_seconds_bucket", 
+                      "http_request_duration_seconds_sum", "http_request_duration_seconds_count", "entities"] {
+            assert!(names.contains(*name), "{} is missing", name);
+        }
+        let value = |series: &str| values.iter().find(|(s, _)| s == series).unwrap().1;
+        assert_eq!(value(r#"http_requests_total{method="GET"}"#), 4.0);
+        assert_eq!(value("http_request_duration_seconds_count"), 3.0);
+        assert_eq!(value(r#"http_request_duration_seconds_bucket{le="+Inf"}"#), 3.0);
+        assert_eq!(value(r#"entities{type="visits"}"#), 50.0);
+    }
+
+    #[test]
+    fn entity_etag() {
+        let server = server_with_visits();
+        let get = |path: &str, if_none_match: Option<&EntityTag>| {
+            let mut request = Request::new(Method::Get, path.parse().unwrap());
+            if let Some(etag) = if_none_match {
+                request.headers_mut().set(IfNoneMatch::Items(vec![etag.clone()]));
+            }
+            server.call(request).wait().unwrap()
+        };
+
+        let response = get("/users/1", None);
+        let etag = response.headers().get::<ETag>().unwrap().0.clone();
+
+        let response = get("/users/1", Some(&etag));
+        assert_eq!(response.status(), StatusCode::NotModified);
+        assert_eq!(response.headers().get(), Some(&ETag(etag.clone())));
+        assert!(response.body().concat2().wait().unwrap().is_empty());
+
+        let mut request = Request::new(Method::Post, "/users/1".parse().unwrap());
+        request.set_body(r#"{"first_name":"Иван"}"#);
+        assert_eq!(server.call(request).wait().unwrap().status(), StatusCode::Ok);
+
+        let response = get("/users/1", Some(&etag));
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_ne!(response.headers().get::<ETag>().unwrap().0, etag);
+        assert!(!response.body().concat2().wait().unwrap().is_empty());
+
+        assert!(get("/users/1/visits", None).headers().get::<ETag>().is_none());
+    }
+
+    #[test]
+    fn utf8_content_length() {
+        let server = server_with_visits();
+        let request = Request::new(Method::Get, "/users/1".parse().unwrap());
+        let response = server.call(request).wait().unwrap();
+        let length = response.headers().get::<ContentLength>().cloned();
+        let body = String::from_utf8(response.body().concat2().wait().unwrap().to_vec()).unwrap();
+
+        // serde_json doesn't escape non-ASCII characters
+        assert!(body.contains(r#""first_name":"Данила","last_name":"Стамленский""#));
+        assert!(body.chars().count() < body.len());
+        assert_eq!(length, Some(ContentLength(body.len() as u64)));
+    }
+
+    #[test]
+    fn error_precedence() {
+        let server = server_with_visits();
+        let status = |path: &str| {
+            let request = Request::new(Method::Get, path.parse().unwrap());
+            server.call(request).wait().unwrap().status()
+        };
+
+        assert_eq!(status("/locations/abc/avg"), StatusCode::NotFound);
+        assert_eq!(status("/locations/abc/avg?gender=male"), StatusCode::NotFound);
+        assert_eq!(status("/locations/1/avg?gender=male"), StatusCode::BadRequest);
+        assert_eq!(status("/locations/999/avg?gender=male"), StatusCode::BadRequest);
+        assert_eq!(status("/locations/999/avg?gender=m"), StatusCode::NotFound);
+        assert_eq!(status("/locations/1/avg?gender=m"), StatusCode::Ok);
+    }
+
+    #[test]
+    fn msgpack_response() {
+        use rmp_serde;
+        use serde_json::Value;
+
+        let server = server_with_visits();
+        let get = |path: &str, accept: &str| {
+            let mut request = Request::new(Method::Get, path.parse().unwrap());
+            request.headers_mut().set_raw("Accept", accept.to_string());
+            server.call(request).wait().unwrap()
+        };
+
+        let response = get("/users/1", "application/msgpack");
+        assert_eq!(response.headers().get_raw("Content-Type").unwrap(), "application/msgpack");
+        let body = response.body().concat2().wait().unwrap();
+        let user: User = rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(user.email, "user@mail.ru");
+        assert_eq!(user.birth_date, 345081600);
+
+        let response = get("/users/1/visits?limit=2", "text/html, application/msgpack;q=0.9");
+        let body = response.body().concat2().wait().unwrap();
+        let visits: Value = rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(visits["visits"].as_array().unwrap().len(), 2);
+        assert_eq!(visits["visits"][0]["place"], "Набережная");
+
+        let response = get("/users/1", "application/json");
+        assert_eq!(response.headers().get_raw("Content-Type").unwrap(), "application/json");
+        let response = get("/users/2", "application/msgpack");
+        assert_eq!(response.status(), StatusCode::NotFound);
+        assert_eq!(response.headers().get_raw("Content-Type").unwrap(), "application/json");
+    }
+
+    #[test]
+    fn validation_error_messages() {
+        let server = server_with_visits();
+        let post = |path: &str, body: &'static str| {
+            let mut request = Request::new(Method::Post, path.parse().unwrap());
+            request.set_body(body);
+            let response = server.call(request).wait().unwrap();
+            let status = response.status();
+            let body = response.body().concat2().wait().unwrap().to_vec();
+            (status, String::from_utf8(body).unwrap())
+        };
+
+        let user = r#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}"#;
+        assert_eq!(post("/users/new", user), 
+                   (StatusCode::BadRequest, r#"{"error":"duplicate id"}"#.to_string()));
+
+        let visit = r#"{"id":100,"location":1,"user":1,"visited_at":0,"mark":6}"#;
+        assert_eq!(post("/visits/new", visit), 
+                   (StatusCode::BadRequest, r#"{"error":"invalid mark"}"#.to_string()));
+
+        // malformed bodies have no message
+        assert_eq!(post("/visits/new", "{"), (StatusCode::BadRequest, "{}".to_string()));
+    }
+
+    fn server_with_visits() -> TravelsServer {
+        let server = server();
+        let user = User {
+            id: UserId(1),
+            email: "user@mail.ru".to_string(),
+            first_name: "Данила".to_string(),
+            last_name: "Стамленский".to_string(),
+            gender: Gender::Male,
+            birth_date: 345081600
+        };
+        let location = Location {
+            id: LocationId(1),
+            place: "Набережная".into(),
+            country: "Россия".into(),
+            city: "Москва".into(),
+            distance: 10
+        };
+
+        let mut entities = vec![CreateEntity::User(user), CreateEntity::Location(location)];
+        for i in 0..50 {
+            let visit = Visit {
+                id: VisitId(i),
+                location: LocationId(1),
+                user: UserId(1),
+                visited_at: 1000000000 + i as Timestamp,
+                mark: (i % 6) as u8
+            };
+            entities.push(CreateEntity::Visit(visit));
+        }
+
+        for entity in entities {
+            server.api.get().do_post(PostRequest::CreateEntity(entity)).unwrap();
+        }
+        server
+    }
+
+    fn get_visits(server: &TravelsServer, accept_encoding: Option<&str>) -> HttpResponse {
+        let mut request = Request::new(Method::Get, "/users/1/visits".parse().unwrap());
+        if let Some(accept_encoding) = accept_encoding {
+            request.headers_mut().set_raw("Accept-Encoding", accept_encoding.to_string());
+        }
+        server.call(request).wait().unwrap()
+    }
+
+    #[test]
+    fn response_compression() {
+        let server = server_with_visits();
+
+        let response = get_visits(&server, None);
+        assert_eq!(response.headers().get::<ContentEncoding>(), None);
+        let plain = response.body().concat2().wait().unwrap().to_vec();
+        assert!(plain.len() > COMPRESSION_THRESHOLD);
+
+        let response = get_visits(&server, Some("gzip, deflate"));
+        assert_eq!(response.headers().get(), Some(&ContentEncoding(vec![Encoding::Gzip])));
+        let length = response.headers().get::<ContentLength>().cloned();
+        let body = response.body().concat2().wait().unwrap();
+        assert_eq!(length, Some(ContentLength(body.len() as u64)));
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&body[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, plain);
+
+        let response = get_visits(&server, Some("deflate"));
+        assert_eq!(response.headers().get(), Some(&ContentEncoding(vec![Encoding::Deflate])));
+        let body = response.body().concat2().wait().unwrap();
+        let mut decompressed = Vec::new();
+        DeflateDecoder::new(&body[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, plain);
+    }
+
+    #[test]
+    fn streamed_visits() {
+        use tokio_core::reactor::Core;
+        use request::{GetRequest, GetVisits};
+
+        let mut core = Core::new().unwrap();
+        let server = TravelsServer { handle: Some(core.handle()), ..server_with_visits() };
+        let visits = (50..STREAMING_THRESHOLD as u32 + 100)
+            .map(|i| Visit {
+                id: VisitId(i),
+                location: LocationId(1),
+                user: UserId(1),
+                visited_at: 1000000000 + i as Timestamp,
+                mark: (i % 6) as u8
+            })
+            .collect();
+        server.api.get().do_post(PostRequest::CreateEntity(CreateEntity::VisitBatch(visits))).unwrap();
+
+        let request = Request::new(Method::Get, "/users/1/visits".parse().unwrap());
+        let response = core.run(server.call(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.headers().get::<ContentLength>(), None);
+        let chunks = core.run(response.body().collect()).unwrap();
+        assert!(chunks.len() > 1);
+
+        let streamed: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.iter().cloned()).collect();
+        let buffered = server.api.get().do_get(GetRequest::GetVisits(UserId(1), GetVisits::default())).unwrap();
+        assert_eq!(streamed, buffered.to_vec());
+
+        // compressed responses need the whole body
+        let response = core.run(server.call({
+            let mut request = Request::new(Method::Get, "/users/1/visits".parse().unwrap());
+            request.headers_mut().set_raw("Accept-Encoding", "gzip");
+            request
+        })).unwrap();
+        assert!(response.headers().get::<ContentLength>().is_some());
+
+        let response = core.run(server.call(Request::new(Method::Get, "/users/1/visits?limit=10".parse().unwrap()))).unwrap();
+        assert!(response.headers().get::<ContentLength>().is_some());
+    }
+
+    #[test]
+    fn streamed_export() {
+        use tokio_core::reactor::Core;
+
+        let mut core = Core::new().unwrap();
+        let server = TravelsServer { handle: Some(core.handle()), ..server_with_visits() };
+        let visits = (50..3000)
+            .map(|i| Visit { id: VisitId(i), location: LocationId(1), user: UserId(1), visited_at: i as Timestamp, mark: 0 })
+            .collect();
+        server.api.get().do_post(PostRequest::CreateEntity(CreateEntity::VisitBatch(visits))).unwrap();
+
+        let mut request = Request::new(Method::Get, "/export/visits".parse().unwrap());
+        request.headers_mut().set_raw("Accept-Encoding", "gzip");
+        let response = core.run(server.call(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.headers().get::<ContentLength>(), None);
+        assert_eq!(response.headers().get::<ContentEncoding>(), None);
+        assert_eq!(response.headers().get_raw("Content-Type").unwrap(), "application/x-ndjson");
+        let chunks = core.run(response.body().collect()).unwrap();
+        assert!(chunks.len() > 1);
+
+        let body: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.iter().cloned()).collect();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.ends_with('\n'));
+        assert_eq!(body.lines().count(), server.api.get().database.visits.len());
+        let ids: Vec<u32> = body.lines()
+            .map(|line| serde_json::from_str::<Visit>(line).unwrap().id.0)
+            .collect();
+        assert!(ids.windows(2).all(|ids| ids[0] < ids[1]));
+    }
+
+    #[test]
+    fn small_responses_are_not_compressed() {
+        let server = server_with_visits();
+        let mut request = Request::new(Method::Get, "/users/1".parse().unwrap());
+        request.headers_mut().set_raw("Accept-Encoding", "gzip");
+        let response = server.call(request).wait().unwrap();
+        assert_eq!(response.headers().get::<ContentEncoding>(), None);
+
+        let mut request = Request::new(Method::Get, "/users/2/visits".parse().unwrap());
+        request.headers_mut().set_raw("Accept-Encoding", "gzip");
+        let response = server.call(request).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::NotFound);
+        assert_eq!(response.headers().get::<ContentEncoding>(), None);
+    }
+
+    #[test]
+    fn head_request() {
+        let server = server_with_visits();
+        for &(path, status) in &[("/users/1", StatusCode::Ok),
+                                 ("/users/1/visits", StatusCode::Ok),
+                                 ("/locations/1/avg", StatusCode::Ok),
+                                 ("/health", StatusCode::Ok),
+                                 ("/users/2", StatusCode::NotFound)] {
+            let request = Request::new(Method::Get, path.parse().unwrap());
+            let get = server.call(request).wait().unwrap();
+            let request = Request::new(Method::Head, path.parse().unwrap());
+            let head = server.call(request).wait().unwrap();
+
+            assert_eq!(head.status(), status);
+            assert_eq!(head.status(), get.status());
+            assert_eq!(head.headers().get::<ContentLength>(), get.headers().get::<ContentLength>());
+
+            let length = get.body().concat2().wait().unwrap().len() as u64;
+            assert_eq!(head.headers().get(), Some(&ContentLength(length)));
+            assert!(head.body().concat2().wait().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        for method in &[Method::Put, Method::Patch] {
+            let request = Request::new(method.clone(), "/users/1".parse().unwrap());
+            let response = server().call(request).wait().unwrap();
+            assert_eq!(response.status(), StatusCode::MethodNotAllowed);
+            assert_eq!(response.headers().get_raw("Allow").unwrap(), "GET, HEAD, POST, DELETE");
+        }
+    }
+
+    #[test]
+    fn cors_headers() {
+        let mut server = server();
+        server.cors_origin = Bytes::from_static(b"https://example.com");
+
+        let request = Request::new(Method::Options, "/users/abc".parse().unwrap());
+        let response = server.call(request).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.headers().get_raw("Access-Control-Allow-Origin").unwrap(), 
+                   "https://example.com");
+        assert_eq!(response.headers().get_raw("Access-Control-Allow-Methods").unwrap(), 
+                   "GET, HEAD, POST, DELETE");
+        assert_eq!(response.headers().get_raw("Access-Control-Allow-Headers").unwrap(), 
+                   "Content-Type");
+
+        for path in &["/health", "/users/1"] {
+            let request = Request::new(Method::Get, path.parse().unwrap());
+            let response = server.call(request).wait().unwrap();
+            assert_eq!(response.headers().get_raw("Access-Control-Allow-Origin").unwrap(), 
+                       "https://example.com");
+            assert!(response.headers().get_raw("Access-Control-Allow-Methods").is_none());
+        }
+    }
+
+    #[test]
+    fn if_match() {
+        let server = server();
+        let user = r#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}"#;
+        let mut request = Request::new(Method::Post, "/users/new".parse().unwrap());
+        request.set_body(user);
+        assert_eq!(server.call(request).wait().unwrap().status(), StatusCode::Ok);
+
+        let version = || {
+            let request = Request::new(Method::Get, "/users/1".parse().unwrap());
+            let response = server.call(request).wait().unwrap();
+            response.headers().get_raw("X-Version").unwrap().one().unwrap().to_vec()
+        };
+        let update = |if_match: &str| {
+            let mut request = Request::new(Method::Post, "/users/1".parse().unwrap());
+            request.set_body(r#"{"first_name":"Аня"}"#);
+            request.headers_mut().set_raw("If-Match", if_match.to_string());
+            server.call(request).wait().unwrap().status()
+        };
+
+        assert_eq!(version(), b"0");
+        assert_eq!(update("\"0\""), StatusCode::Ok);
+        assert_eq!(version(), b"1");
+        assert_eq!(update("0"), StatusCode::Conflict);
+        assert_eq!(update("1"), StatusCode::Ok);
+        assert_eq!(update("*"), StatusCode::Ok);
+        assert_eq!(update("latest"), StatusCode::BadRequest);
+        assert_eq!(version(), b"3");
+    }
+
+    #[test]
+    fn request_content_type() {
+        let server = server();
+        let user = r#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}"#;
+        let post = |content_type: Option<&str>| {
+            let mut request = Request::new(Method::Post, "/users/new".parse().unwrap());
+            request.set_body(user);
+            if let Some(content_type) = content_type {
+                request.headers_mut().set_raw("Content-Type", content_type.to_string());
+            }
+            server.call(request).wait().unwrap().status()
+        };
+
+        assert_eq!(post(Some("text/plain")), StatusCode::UnsupportedMediaType);
+        assert_eq!(post(Some("application/x-www-form-urlencoded")), StatusCode::UnsupportedMediaType);
+        assert_eq!(post(Some("json")), StatusCode::UnsupportedMediaType);
+        assert_eq!(server.api.get().database.users.len(), 0);
+
+        assert_eq!(post(None), StatusCode::Ok);
+        // already created
+        assert_eq!(post(Some("application/json; charset=utf-8")), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn maintenance() {
+        let server = TravelsServer { admin_token: Some(Bytes::from_static(b"secret")), ..server() };
+        let set = |query: &str, token: Option<&str>| {
+            let uri = format!("/admin/maintenance?{}", query);
+            let mut request = Request::new(Method::Post, uri.parse().unwrap());
+            if let Some(token) = token {
+                request.headers_mut().set_raw("X-Admin-Token", token.to_string());
+            }
+            server.call(request).wait().unwrap().status()
+        };
+        let health = || {
+            let request = Request::new(Method::Get, "/health".parse().unwrap());
+            server.call(request).wait().unwrap()
+        };
+
+        assert_eq!(set("enabled=true", None), StatusCode::Forbidden);
+        assert_eq!(set("enabled=true", Some("guess")), StatusCode::Forbidden);
+        assert_eq!(set("enabled=yes", Some("secret")), StatusCode::BadRequest);
+        assert_eq!(health().status(), StatusCode::Ok);
+
+        assert_eq!(set("enabled=true", Some("secret")), StatusCode::Ok);
+        let response = health();
+        assert_eq!(response.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(response.headers().get_raw("Retry-After").unwrap(), "1");
+        let request = Request::new(Method::Post, "/users/new".parse().unwrap());
+        assert_eq!(server.call(request).wait().unwrap().status(), StatusCode::ServiceUnavailable);
+
+        assert_eq!(set("enabled=false", Some("secret")), StatusCode::Ok);
+        assert_eq!(health().status(), StatusCode::Ok);
+
+        // without a token there is no such endpoint
+        let unprotected = TravelsServer { maintenance: server.maintenance.clone(), ..self::server() };
+        let request = Request::new(Method::Post, "/admin/maintenance?enabled=true".parse().unwrap());
+        assert_eq!(unprotected.call(request).wait().unwrap().status(), StatusCode::NotFound);
+        assert!(!server.maintenance.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn rate_limiter() {
+        let limiter = RateLimiter::new(2);
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+        assert!(limiter.check(client, start));
+        assert!(limiter.check(client, start));
+        assert!(!limiter.check(client, start));
+        assert!(limiter.check("10.0.0.2".parse().unwrap(), start));
+
+        // a token per half a second, two at most
+        assert!(!limiter.check(client, start + Duration::from_millis(400)));
+        assert!(limiter.check(client, start + Duration::from_millis(500)));
+        assert!(!limiter.check(client, start + Duration::from_millis(500)));
+        let later = start + Duration::from_secs(10);
+        assert!(limiter.check(client, later));
+        assert!(limiter.check(client, later));
+        assert!(!limiter.check(client, later));
+    }
+
+    #[test]
+    fn rate_limited_client() {
+        let server = Rc::new(server());
+        let limiter = Arc::new(RateLimiter::new(50));
+        let connection = |client: &str| RateLimited {
+            server: server.clone(),
+            limiter: Some(limiter.clone()),
+            client: client.parse().unwrap()
+        };
+        let health = |connection: &RateLimited| {
+            let request = Request::new(Method::Get, "/health".parse().unwrap());
+            connection.call(request).wait().unwrap()
+        };
+
+        // a connection per request, the bucket is per client
+        let statuses: Vec<_> = (0..100)
+            .map(|_| health(&connection("10.0.0.1")).status())
+            .collect();
+        let limited = statuses.iter().position(|&status| status == StatusCode::TooManyRequests).unwrap();
+        assert!(limited >= 50, "limited after {} requests", limited);
+        let response = health(&connection("10.0.0.1"));
+        assert_eq!(response.status(), StatusCode::TooManyRequests);
+        assert_eq!(response.headers().get_raw("Retry-After").unwrap(), "1");
+        assert!(server.api.get().counters.rate_limited.load(Ordering::Relaxed) > 0);
+
+        assert_eq!(health(&connection("::1")).status(), StatusCode::Ok);
+        let unlimited = RateLimited { limiter: None, ..connection("10.0.0.1") };
+        assert_eq!(health(&unlimited).status(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn panicking_request() {
+        let result: Result<(), ApiError> = catch_panic(|| panic!("injected"));
+        assert_eq!(result, Err(ApiError::from(StatusCode::InternalServerError)));
+        assert_eq!(catch_panic(|| Ok(1)), Ok(1));
+
+        // a request panicking while holding the locks poisons them
+        let server = server_with_visits();
+        let api = server.api.get();
+        let poisoned = thread::spawn(move || {
+            let _index = api.database.index.write().unwrap();
+            let _users = api.database.users.write(&UserId(1));
+            panic!("injected");
+        }).join();
+        assert!(poisoned.is_err());
+        assert!(server.api.get().database.index.is_poisoned());
+
+        let get = |path: &str| {
+            let request = Request::new(Method::Get, path.parse().unwrap());
+            server.call(request).wait().unwrap().status()
+        };
+        assert_eq!(get("/users/1"), StatusCode::Ok);
+        assert_eq!(get("/users/1/visits"), StatusCode::Ok);
+
+        let mut request = Request::new(Method::Post, "/users/1".parse().unwrap());
+        request.set_body(r#"{"first_name":"Данил"}"#);
+        assert_eq!(server.call(request).wait().unwrap().status(), StatusCode::Ok);
+        assert_eq!(get("/users/1"), StatusCode::Ok);
+    }
+
+    #[test]
+    fn reload() {
+        use std::env;
+        use std::fs::{self, File};
+        use std::process;
+        use zip::write::{ZipWriter, FileOptions};
+
+        let path = env::temp_dir().join(format!("highloadcup-reload-{}.zip", process::id()));
+        let write_users = |count: u32| {
+            let mut zip = ZipWriter::new(File::create(&path).unwrap());
+            zip.start_file("users_1.json", FileOptions::default()).unwrap();
+            let users: Vec<String> = (1..count + 1)
+                .map(|id| format!(r#"{{"id":{},"email":"{}@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}}"#, id, id))
+                .collect();
+            write!(zip, r#"{{"users":[{}]}}"#, users.join(",")).unwrap();
+            zip.finish().unwrap();
+        };
+        let dataset = Dataset { path: path.to_str().unwrap().to_string(), shards: 2, strict: false };
+        let server = TravelsServer { 
+            admin_token: Some(Bytes::from_static(b"secret")), 
+            dataset: Some(dataset.clone()), 
+            ..server() 
+        };
+        let reload = |token: &str| {
+            let mut request = Request::new(Method::Post, "/admin/reload".parse().unwrap());
+            request.headers_mut().set_raw("X-Admin-Token", token.to_string());
+            let response = server.call(request).wait().unwrap();
+            let status = response.status();
+            (status, String::from_utf8(response.body().concat2().wait().unwrap().to_vec()).unwrap())
+        };
+        let user = |id: u32| {
+            let request = Request::new(Method::Get, format!("/users/{}", id).parse().unwrap());
+            server.call(request).wait().unwrap().status()
+        };
+
+        write_users(1);
+        assert_eq!(reload("guess").0, StatusCode::Forbidden);
+        assert_eq!(user(1), StatusCode::NotFound);
+        assert_eq!(reload("secret"), (StatusCode::Ok, r#"{"users":1,"locations":0,"visits":0}"#.to_string()));
+        assert_eq!(user(1), StatusCode::Ok);
+
+        // requests started earlier keep the old database
+        let before = server.api.get();
+        write_users(3);
+        assert_eq!(reload("secret").0, StatusCode::Ok);
+        assert_eq!((user(3), user(4)), (StatusCode::Ok, StatusCode::NotFound));
+        assert_eq!(before.database.users.len(), 1);
+        // maintenance is only on while loading
+        assert!(!server.maintenance.load(Ordering::SeqCst));
+
+        // the old database stays if the new one can't be loaded
+        fs::write(&path, b"not a zip").unwrap();
+        assert_eq!(reload("secret").0, StatusCode::InternalServerError);
+        assert_eq!(user(3), StatusCode::Ok);
+        fs::remove_file(&path).unwrap();
+
+        // the other threads switch too
+        let other = TravelsServer { api: ApiHandle::new(server.api.shared.clone()), ..self::server() };
+        assert!(Arc::ptr_eq(&other.api.get(), &server.api.get()));
+
+        // without a dataset there is no such endpoint
+        let request = Request::new(Method::Post, "/admin/reload".parse().unwrap());
+        assert_eq!(self::server().call(request).wait().unwrap().status(), StatusCode::NotFound);
+    }
+
+    #[test]
+    fn post_response() {
+        let server = server();
+        let user = r#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}"#;
+        for &(ref method, path, body) in &[(Method::Post, "/users/new", user), 
+                                            (Method::Post, "/users/1", r#"{"first_name":"Аня"}"#),
+                                            (Method::Delete, "/users/1", "")] {
+            let mut request = Request::new(method.clone(), path.parse().unwrap());
+            request.set_body(body);
+            let response = server.call(request).wait().unwrap();
+            assert_eq!(response.status(), StatusCode::Ok);
+            assert_eq!(response.headers().get(), Some(&ContentLength(2)));
+            assert_eq!(response.headers().get_raw("Content-Type").unwrap(), "application/json");
+            assert_eq!(response.headers().get_raw("Connection").unwrap(), "keep-alive");
+
+            let body = response.body().concat2().wait().unwrap();
+            assert_eq!(&body[..], b"{}");
+        }
+    }
+
+    #[test]
+    fn connection_header() {
+        let mut server = server();
+        for &(keep_alive, connection) in &[(true, "keep-alive"), (false, "close")] {
+            server.keep_alive = keep_alive;
+            for method in &[Method::Get, Method::Post] {
+                let request = Request::new(method.clone(), "/users/1".parse().unwrap());
+                let response = server.call(request).wait().unwrap();
+                assert_eq!(response.headers().get_raw("Connection").unwrap(), connection);
+            }
+
+            let request = Request::new(Method::Get, "/health".parse().unwrap());
+            let response = server.call(request).wait().unwrap();
+            assert_eq!(response.headers().get_raw("Connection").unwrap(), connection);
+        }
+    }
+
+    #[test]
+    fn body_timeout() {
+        use tokio_core::reactor::Core;
+
+        let mut core = Core::new().unwrap();
+        let mut server = server();
+        server.timeout = Some(RequestTimeout { 
+            duration: Duration::from_millis(50), 
+            handle: core.handle() 
+        });
+
+        // the sender is alive, so the body never ends
+        let (_sender, body) = hyper::Body::pair();
+        let mut request = Request::new(Method::Post, "/users/new".parse().unwrap());
+        request.set_body(body);
+        let response = core.run(server.call(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::RequestTimeout);
+
+        let request = Request::new(Method::Get, "/health".parse().unwrap());
+        let response = core.run(server.call(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn request_framing() {
+        use hyper::header::Encoding;
+
+        let server = server();
+        // the same length for every id below 10
+        let user = |id| format!(r#"{{"id":{},"email":"{}@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}}"#, id, id);
+        let length = user(1).len() as u64;
+        let post = |id: u32, content_length: Option<u64>, is_chunked: bool| {
+            let mut request = Request::new(Method::Post, "/users/new".parse().unwrap());
+            request.set_body(user(id));
+            request.headers_mut().remove::<ContentLength>();
+            if let Some(length) = content_length {
+                request.headers_mut().set(ContentLength(length));
+            }
+            if is_chunked {
+                request.headers_mut().set(TransferEncoding(vec![Encoding::Chunked]));
+            }
+            server.call(request).wait().unwrap()
+        };
+
+        assert_eq!(post(1, None, true).status(), StatusCode::Ok);
+        assert_eq!(post(2, Some(length), false).status(), StatusCode::Ok);
+
+        let response = post(3, Some(length), true);
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        assert_eq!(response.headers().get_raw("Connection").unwrap(), "close");
+        assert_eq!(post(3, Some(length + 1), false).status(), StatusCode::BadRequest);
+        assert_eq!(post(3, Some(length - 1), false).status(), StatusCode::BadRequest);
+        assert!(!server.api.get().database.users.contains_key(&UserId(3)));
+    }
+
+    #[test]
+    fn max_body_size() {
+        let mut server = server();
+        server.max_body_bytes = 100;
+        let user = r#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}"#;
+        let post = |body: String, content_length: Option<u64>| {
+            let mut request = Request::new(Method::Post, "/users/new".parse().unwrap());
+            request.set_body(body);
+            // without 'Content-Length' the limit applies while reading
+            match content_length {
+                Some(length) => request.headers_mut().set(ContentLength(length)),
+                None => { request.headers_mut().remove::<ContentLength>(); }
+            }
+            server.call(request).wait().unwrap().status()
+        };
+
+        let under = format!("{}{}", user, " ".repeat(100 - user.len()));
+        let over = format!("{} ", under);
+        assert_eq!(post(under.clone(), Some(100)), StatusCode::Ok);
+        assert_eq!(post(over.clone(), Some(101)), StatusCode::PayloadTooLarge);
+        assert_eq!(post(over, None), StatusCode::PayloadTooLarge);
+        // read in full, but the user already exists
+        assert_eq!(post(under, None), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn compressed_request_body() {
+        let mut server = server();
+        server.max_body_bytes = 1000;
+        let post = |uri: &str, body: Vec<u8>, encoding: &str| {
+            let mut request = Request::new(Method::Post, uri.parse().unwrap());
+            request.headers_mut().set(ContentLength(body.len() as u64));
+            request.headers_mut().set_raw("Content-Encoding", encoding.to_string());
+            request.set_body(body);
+            server.call(request).wait().unwrap().status()
+        };
+        let gzip = |body: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let user = br#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}"#;
+        assert_eq!(post("/users/new", gzip(user), "gzip"), StatusCode::Ok);
+        assert!(server.api.get().database.users.contains_key(&UserId(1)));
+        assert_eq!(post("/users/1", b"{}".to_vec(), "identity"), StatusCode::Ok);
+
+        assert_eq!(post("/users/1", gzip(b"{}"), "deflate"), StatusCode::UnsupportedMediaType);
+        assert_eq!(post("/users/1", gzip(b"{}"), "gzip, gzip"), StatusCode::UnsupportedMediaType);
+        assert_eq!(post("/users/1", b"{}".to_vec(), "gzip"), StatusCode::BadRequest);
+
+        // a few bytes on the wire, but over the limit once decompressed
+        let bomb = gzip(&vec![b' '; 500000]);
+        assert!(bomb.len() < 1000);
+        assert_eq!(post("/users/1", bomb, "gzip"), StatusCode::PayloadTooLarge);
+        let padded = format!("{}{}", " ".repeat(998), "{}");
+        assert_eq!(post("/users/1", gzip(padded.as_bytes()), "gzip"), StatusCode::Ok);
+    }
+}
@@ -0,0 +1,125 @@
+#![feature(conservative_impl_trait)]
+
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate hyper;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate zip;
+extern crate percent_encoding;
+#[macro_use]
+extern crate lazy_static;
+extern crate bytes;
+extern crate flate2;
+extern crate libc;
+extern crate rmp_serde;
+#[macro_use]
+extern crate log;
+
+pub mod data;
+pub mod http;
+pub mod router;
+pub mod request;
+pub mod api;
+pub mod database;
+pub mod wal;
+pub mod cache;
+
+use std::io::{self, BufRead};
+use data::Timestamp;
+
+// data generation phase from the second line of 'options.txt'
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Phase {
+    #[default]
+    First,
+    // rating-heavy
+    Second
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub now: Timestamp,
+    pub phase: Phase
+}
+
+// Parses 'options.txt': the timestamp on the first line and optionally
+// the phase ('1' or '2') on the second one
+pub fn parse_options<R: BufRead>(reader: R) -> io::Result<Options> {
+    let mut lines = reader.lines();
+    let now = lines.next()
+        .unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no timestamp")))?
+        .trim()
+        .parse::<Timestamp>()
+        .map_err(io::Error::other)?;
+
+    let phase = match lines.next() {
+        Some(line) => match line?.trim() {
+            "1" => Phase::First,
+            "2" => Phase::Second,
+            "" => Phase::default(),
+            phase => return Err(io::Error::other(format!("invalid phase {:?}", phase)))
+        },
+        None => Phase::default()
+    };
+
+    Ok(Options { now, phase })
+}
+
+lazy_static! {
+    pub static ref OPTIONS: Options = {
+        use std::io::BufReader;
+        use std::fs::File;
+
+        File::open("/tmp/data/options.txt")
+            .map(BufReader::new)
+            .and_then(parse_options)
+            .unwrap_or_else(|e| {
+                warn!("Unable to read options.txt: {}", e);
+                use std::time::{SystemTime, UNIX_EPOCH};
+            
+                let current_timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_secs() as Timestamp;
+                Options { now: current_timestamp, phase: Phase::default() }
+            })
+    };
+
+    pub static ref NOW: Timestamp = OPTIONS.now;
+    pub static ref PHASE: Phase = OPTIONS.phase;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn one_line_options() {
+        let options = parse_options(Cursor::new("1503695452\n")).unwrap();
+        assert_eq!(options, Options { now: 1503695452, phase: Phase::First });
+
+        let options = parse_options(Cursor::new("1503695452")).unwrap();
+        assert_eq!(options, Options { now: 1503695452, phase: Phase::First });
+    }
+
+    #[test]
+    fn two_line_options() {
+        let options = parse_options(Cursor::new("1503695452\n1\n")).unwrap();
+        assert_eq!(options, Options { now: 1503695452, phase: Phase::First });
+
+        let options = parse_options(Cursor::new("1503695452\n2")).unwrap();
+        assert_eq!(options, Options { now: 1503695452, phase: Phase::Second });
+    }
+
+    #[test]
+    fn invalid_options() {
+        for options in &["", "now\n1", "1503695452\n3"] {
+            assert!(parse_options(Cursor::new(*options)).is_err());
+        }
+    }
+}
@@ -1,5 +1,3 @@
-#![feature(conservative_impl_trait)]
-
 extern crate futures;
 extern crate tokio_core;
 extern crate net2;
@@ -8,71 +6,59 @@ extern crate hyper;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
-extern crate serde_json;
 extern crate serde_yaml;
-extern crate zip;
-extern crate percent_encoding;
-#[macro_use]
-extern crate lazy_static;
 extern crate num_cpus;
+extern crate libc;
 extern crate bytes;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+extern crate highloadcup;
 
-mod data;
-mod http;
-mod router;
-mod request;
-mod api;
-mod database;
-
+use std::env;
+use std::error::Error;
+use std::fmt::Display;
 use std::fs::File;
-use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::io;
+use std::process;
+use std::str::FromStr;
+use std::net::{SocketAddr, Ipv6Addr, TcpListener as StdTcpListener};
+use std::rc::Rc;
+use std::sync::{Arc, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core, Interval, Timeout};
 use tokio_core::net::TcpListener;
 use futures::stream::Stream;
-use futures::future;
+use futures::future::{self, Future};
 use net2::TcpBuilder;
 use net2::unix::UnixTcpBuilderExt;
 use hyper::server::Http;
+use bytes::Bytes;
 
-use database::Database;
-use api::Api;
-use http::TravelsServer;
-use data::Timestamp;
+use highloadcup::data::Timestamp;
+use highloadcup::database::Database;
+use highloadcup::api::{Api, DEFAULT_MAX_MULTIGET, DEFAULT_MIN_VISITED_AT, DEFAULT_MAX_VISITED_AT};
+use highloadcup::cache::AverageCache;
+use highloadcup::http::{TravelsServer, SharedApi, ApiHandle, Dataset, RateLimiter, RateLimited, RequestTimeout};
+use highloadcup::http::DEFAULT_MAX_BODY_BYTES;
+use highloadcup::http::{serve_with_timeout, track_connection, skip_accept_errors};
+use highloadcup::{NOW, PHASE};
 
 const PRIORITY_MAX: i32 = 19;
+const DEFAULT_LISTEN_BACKLOG: i32 = 10000;
 
-lazy_static! {
-    pub static ref NOW: Timestamp = {
-        use std::io::{BufRead, BufReader};
-        use std::fs::File;
-
-        File::open("/tmp/data/options.txt")
-            .map(BufReader::new)
-            .and_then(|mut file| {
-                let mut line = String::new();
-                file.read_line(&mut line)
-                    .map(move |_| line)
-            })
-            .and_then(|line| {
-                use std::io;
-                line.trim()
-                    .parse::<Timestamp>()
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-            })
-            .unwrap_or_else(|e| {
-                println!("Unable to read timestamp from options.txt: {}", e);
-                use std::time::{SystemTime, UNIX_EPOCH};
-            
-                let current_timestamp = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards")
-                    .as_secs() as Timestamp;
-                current_timestamp
-            })
-    };
+// after SIGTERM/SIGINT each worker stops accepting connections and gives
+// the open ones at most DRAIN_TIME to finish their requests
+const DRAIN_TIME: Duration = Duration::from_secs(1);
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
 }
 
 #[derive(Serialize, Deserialize)]
@@ -80,7 +66,77 @@ struct Config {
     bind:        SocketAddr,
     data_file:   String,
     keep_alive:  bool,
-    num_threads: Option<usize>
+    num_threads: Option<usize>,
+    // mutations are logged here and replayed on startup
+    wal_path:    Option<String>,
+    // 'env_logger' filter, e.g. "info" or "highloadcup::http=debug"
+    log_level:   Option<String>,
+    // 'Access-Control-Allow-Origin', any origin by default
+    cors_origin: Option<String>,
+    // limit for receiving a request, connections are closed if headers don't
+    // arrive in time and slow bodies get '408 Request Timeout'
+    request_timeout_ms: Option<u64>,
+    // larger requests get '413 Payload Too Large', 1 MiB by default
+    max_body_bytes: Option<usize>,
+    // drop loaded visits of unknown users or locations
+    strict_load: Option<bool>,
+    // read the whole index once after loading
+    warmup: Option<bool>,
+    // pending connections queue, the kernel silently caps it at 'somaxconn'
+    #[serde(default = "default_listen_backlog")]
+    listen_backlog: i32,
+    #[serde(default = "default_true")]
+    tcp_nodelay: bool,
+    // every thread binds its own listener, which needs 'SO_REUSEPORT' with
+    // more than one thread
+    #[serde(default = "default_true")]
+    reuse_port: bool,
+    // worker 'i' runs on core 'i' modulo the number of cores
+    #[serde(default = "default_true")]
+    pin_threads: bool,
+    // listen on '::' with the port of 'bind', IPv4 clients come as mapped
+    // addresses. Otherwise the family of 'bind' is the only one
+    #[serde(default)]
+    dual_stack: bool,
+    // 'X-Admin-Token' enabling 'POST /admin/maintenance' and 'POST 
+    // /admin/reload' (of 'data_file'), which are absent without one
+    admin_token: Option<String>,
+    // requests per second of a client IP, more get '429 Too Many Requests'.
+    // Unlimited by default
+    rate_limit_rps: Option<u32>,
+    // '{"avg":5.00000}' rather than '{"avg":5}'
+    #[serde(default = "default_true")]
+    avg_trailing_zeros: bool,
+    // ids of a '/users?ids=...' request, 'DEFAULT_MAX_MULTIGET' by default
+    max_multiget: Option<usize>,
+    // 'country=russia' finds visits to 'Russia' too
+    #[serde(default)]
+    case_insensitive_country: bool,
+    // creating or moving a visit to the time of another visit of the user
+    // is '400 Bad Request'. The log is replayed with it, so it shouldn't
+    // change while the log is kept
+    #[serde(default)]
+    unique_visit_time: bool,
+    // 'visited_at' of created or updated visits, 'DEFAULT_MIN_VISITED_AT'
+    // and 'DEFAULT_MAX_VISITED_AT' by default. Like 'unique_visit_time'
+    // they shouldn't change while the log is kept
+    min_visited_at: Option<Timestamp>,
+    max_visited_at: Option<Timestamp>,
+    // responses of '/locations/<id>/avg' kept until the visits of the
+    // location change, none by default
+    avg_cache_size: Option<usize>,
+    // PEM certificate chain and key. TLS isn't built in, so the server
+    // refuses to start rather than serve plain HTTP when they are set
+    tls_cert: Option<String>,
+    tls_key:  Option<String>
+}
+
+fn default_listen_backlog() -> i32 {
+    DEFAULT_LISTEN_BACKLOG
+}
+
+fn default_true() -> bool {
+    true
 }
 
 impl Default for Config {
@@ -95,82 +151,619 @@ impl Default for Config {
             bind: address,
             data_file: "/tmp/data/data.zip".to_string(),
             keep_alive: true,
-            num_threads: Some(4)
+            num_threads: Some(4),
+            wal_path: None,
+            log_level: None,
+            cors_origin: None,
+            request_timeout_ms: None,
+            max_body_bytes: None,
+            strict_load: None,
+            warmup: None,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            tcp_nodelay: true,
+            reuse_port: true,
+            pin_threads: true,
+            dual_stack: false,
+            admin_token: None,
+            rate_limit_rps: None,
+            avg_trailing_zeros: true,
+            max_multiget: None,
+            case_insensitive_country: false,
+            unique_visit_time: false,
+            min_visited_at: None,
+            max_visited_at: None,
+            avg_cache_size: None,
+            tls_cert: None,
+            tls_key: None
+        }
+    }
+}
+
+impl Config {
+    // 'lookup' is 'env::var' outside of tests. Problems are printed directly,
+    // because the logger is configured from the result
+    fn with_env_overrides<F>(mut self, lookup: F) -> Config
+    where
+        F: Fn(&str) -> Option<String>
+    {
+        fn parse<T, F>(lookup: &F, key: &str) -> Option<T>
+        where
+            T: FromStr,
+            T::Err: Display,
+            F: Fn(&str) -> Option<String>
+        {
+            lookup(key).and_then(|value| match value.parse() {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    println!("Ignoring invalid {} '{}': {}", key, value, e);
+                    None
+                }
+            })
+        }
+
+        if let Some(bind) = parse(&lookup, "BIND_ADDR") {
+            self.bind = bind;
+        }
+
+        if let Some(data_file) = lookup("DATA_FILE") {
+            self.data_file = data_file;
+        }
+
+        if let Some(num_threads) = parse(&lookup, "NUM_THREADS") {
+            self.num_threads = Some(num_threads);
+        }
+
+        if let Some(keep_alive) = parse(&lookup, "KEEP_ALIVE") {
+            self.keep_alive = keep_alive;
+        }
+
+        if let Some(wal_path) = lookup("WAL_PATH") {
+            self.wal_path = Some(wal_path);
+        }
+
+        if let Some(log_level) = lookup("LOG_LEVEL") {
+            self.log_level = Some(log_level);
+        }
+
+        if let Some(cors_origin) = lookup("CORS_ORIGIN") {
+            self.cors_origin = Some(cors_origin);
+        }
+
+        if let Some(request_timeout_ms) = parse(&lookup, "REQUEST_TIMEOUT_MS") {
+            self.request_timeout_ms = Some(request_timeout_ms);
+        }
+
+        if let Some(max_body_bytes) = parse(&lookup, "MAX_BODY_BYTES") {
+            self.max_body_bytes = Some(max_body_bytes);
+        }
+
+        if let Some(strict_load) = parse(&lookup, "STRICT_LOAD") {
+            self.strict_load = Some(strict_load);
+        }
+
+        if let Some(warmup) = parse(&lookup, "WARMUP") {
+            self.warmup = Some(warmup);
+        }
+
+        if let Some(listen_backlog) = parse(&lookup, "LISTEN_BACKLOG") {
+            self.listen_backlog = listen_backlog;
+        }
+
+        if let Some(tcp_nodelay) = parse(&lookup, "TCP_NODELAY") {
+            self.tcp_nodelay = tcp_nodelay;
+        }
+
+        if let Some(reuse_port) = parse(&lookup, "REUSE_PORT") {
+            self.reuse_port = reuse_port;
+        }
+
+        if let Some(pin_threads) = parse(&lookup, "PIN_THREADS") {
+            self.pin_threads = pin_threads;
+        }
+
+        if let Some(dual_stack) = parse(&lookup, "DUAL_STACK") {
+            self.dual_stack = dual_stack;
+        }
+
+        if let Some(admin_token) = lookup("ADMIN_TOKEN") {
+            self.admin_token = Some(admin_token);
+        }
+
+        if let Some(rate_limit_rps) = parse(&lookup, "RATE_LIMIT_RPS") {
+            self.rate_limit_rps = Some(rate_limit_rps);
+        }
+
+        if let Some(avg_trailing_zeros) = parse(&lookup, "AVG_TRAILING_ZEROS") {
+            self.avg_trailing_zeros = avg_trailing_zeros;
+        }
+
+        if let Some(max_multiget) = parse(&lookup, "MAX_MULTIGET") {
+            self.max_multiget = Some(max_multiget);
+        }
+
+        if let Some(case_insensitive_country) = parse(&lookup, "CASE_INSENSITIVE_COUNTRY") {
+            self.case_insensitive_country = case_insensitive_country;
         }
+
+        if let Some(unique_visit_time) = parse(&lookup, "UNIQUE_VISIT_TIME") {
+            self.unique_visit_time = unique_visit_time;
+        }
+
+        if let Some(min_visited_at) = parse(&lookup, "MIN_VISITED_AT") {
+            self.min_visited_at = Some(min_visited_at);
+        }
+
+        if let Some(max_visited_at) = parse(&lookup, "MAX_VISITED_AT") {
+            self.max_visited_at = Some(max_visited_at);
+        }
+
+        if let Some(avg_cache_size) = parse(&lookup, "AVG_CACHE_SIZE") {
+            self.avg_cache_size = Some(avg_cache_size);
+        }
+
+        if let Some(tls_cert) = lookup("TLS_CERT") {
+            self.tls_cert = Some(tls_cert);
+        }
+
+        if let Some(tls_key) = lookup("TLS_KEY") {
+            self.tls_key = Some(tls_key);
+        }
+
+        self
+    }
+
+    // Replaces values the server can't work with, 'nthreads' is the resolved
+    // number of threads
+    fn validated(mut self, nthreads: usize) -> Config {
+        if self.listen_backlog <= 0 {
+            warn!("Invalid listen backlog {}, using {}", self.listen_backlog, DEFAULT_LISTEN_BACKLOG);
+            self.listen_backlog = DEFAULT_LISTEN_BACKLOG;
+        }
+
+        if !self.reuse_port && nthreads > 1 {
+            warn!("Port reuse is disabled, using 1 thread instead of {}", nthreads);
+            self.num_threads = Some(1);
+        }
+
+        if self.rate_limit_rps == Some(0) {
+            warn!("Invalid rate limit 0, requests are not limited");
+            self.rate_limit_rps = None;
+        }
+
+        self
     }
 }
 
 fn main() {
-    scheduler::set_self_priority(scheduler::Which::Process, PRIORITY_MAX)
-        .expect("Unable to increase process priority");
+    if scheduler::set_self_priority(scheduler::Which::Process, PRIORITY_MAX).is_err() {
+        println!("Unable to increase process priority");
+    }
 
-    println!("Current timestamp is: {}", *NOW);
     let config: Config = File::open("config.yml")
             .map_err(|e| serde_yaml::Error::io(e))
             .and_then(serde_yaml::from_reader)
             .unwrap_or_else(|e| {
                 println!("Unable to read configuration: {}", e);
-                Default::default()
-            });
+                Config::default()
+            })
+            .with_env_overrides(|key| env::var(key).ok());
+
+    env_logger::Builder::new()
+        .parse(config.log_level.as_ref().map_or("info", String::as_str))
+        .init();
+
+    // 'highloadcup --check [data.zip]' validates the dataset, by default the
+    // configured one, and exits
+    let mut args = env::args().skip(1);
+    if args.next().as_deref() == Some("--check") {
+        let path = args.next().unwrap_or_else(|| config.data_file.clone());
+        process::exit(check(&path, config.num_threads.unwrap_or_else(num_cpus::get)));
+    }
+
+    if let Err(e) = run(config) {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
+
+// returns the exit code, 1 if the dataset has problems or can't be read
+fn check(path: &str, nthreads: usize) -> i32 {
+    match Database::check_file(path, nthreads) {
+        Ok(report) => {
+            println!("{}", report);
+            if report.is_clean() {
+                println!("{} is clean", path);
+                0
+            } else {
+                println!("{} has problems", path);
+                1
+            }
+        },
+        Err(e) => {
+            println!("Unable to read {}: {}", path, e);
+            1
+        }
+    }
+}
+
+// Pins the current thread, failing only with a warning, e.g. if the core
+// isn't in the cpuset of the process
+fn pin_worker(i: usize) {
+    let core = i % num_cpus::get();
+    if scheduler::set_self_affinity(scheduler::CpuSet::single(core)).is_err() {
+        warn!("Unable to pin worker {} to core {}", i, core);
+    }
+}
+
+// Sets SHUTDOWN when a worker stops, so that a failed or panicked worker
+// stops the others too and 'run' can report it
+struct StopOthers;
+
+impl Drop for StopOthers {
+    fn drop(&mut self) {
+        SHUTDOWN.store(true, Ordering::SeqCst);
+    }
+}
+
+fn listen(config: &Config) -> io::Result<StdTcpListener> {
+    let address = if config.dual_stack {
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), config.bind.port())
+    } else {
+        config.bind
+    };
+
+    let builder = if address.is_ipv6() { TcpBuilder::new_v6()? } else { TcpBuilder::new_v4()? };
+    if address.is_ipv6() {
+        // the default depends on 'net.ipv6.bindv6only', so it's always set
+        builder.only_v6(!config.dual_stack)?;
+    }
+    if config.reuse_port {
+        // binding still fails for the other threads, but with a clear message
+        if let Err(e) = builder.reuse_port(true) {
+            warn!("Unable to reuse port: {}", e);
+        }
+    }
+    builder.bind(address)?.listen(config.listen_backlog)
+}
+
+// Listeners are bound before loading, so a busy port is reported immediately
+fn run(config: Config) -> Result<(), Box<Error>> {
+    info!("Current timestamp is: {}, phase: {:?}", *NOW, *PHASE);
+
+    if config.tls_cert.is_some() || config.tls_key.is_some() {
+        return Err("TLS is not supported by this build, terminate it in a proxy".into());
+    }
+
+    let nthreads = config.num_threads.unwrap_or_else(num_cpus::get);
+    let config = config.validated(nthreads);
+    let nthreads = config.num_threads.unwrap_or(nthreads);
 
-    let service = {
-        let database = Database::from_file(&config.data_file)
-            .expect("Unable to initialize database");
-        println!("Users: {} Locations: {}, Visits: {}", 
+    let mut listeners = Vec::with_capacity(nthreads);
+    for _ in 0..nthreads {
+        let listener = listen(&config)
+            .map_err(|e| format!("Unable to listen on {}: {}", config.bind, e))?;
+        listeners.push(listener);
+    }
+
+    let api = {
+        let database = Database::from_file(&config.data_file, nthreads, 
+                                           config.strict_load.unwrap_or(false))
+            .map_err(|e| format!("Unable to initialize database: {}", e))?;
+        info!("Users: {} Locations: {}, Visits: {}", 
                  database.users.len(),
                  database.locations.len(),
                  database.visits.len());
+
+        if config.warmup.unwrap_or(false) {
+            let started = Instant::now();
+            let marks = database.index.read().unwrap_or_else(PoisonError::into_inner).warmup();
+            info!("Index warmed up in {:?} ({} marks)", started.elapsed(), marks);
+        }
         
-        let api = {
-            let api = Api { database };
-            let api = RwLock::new(api);
-            Arc::new(api)
+        let mut api = Api::new(database);
+        api.avg_trailing_zeros = config.avg_trailing_zeros;
+        api.max_multiget = config.max_multiget.unwrap_or(DEFAULT_MAX_MULTIGET);
+        api.case_insensitive_country = config.case_insensitive_country;
+        api.unique_visit_time = config.unique_visit_time;
+        api.min_visited_at = config.min_visited_at.unwrap_or(DEFAULT_MIN_VISITED_AT);
+        api.max_visited_at = config.max_visited_at.unwrap_or(DEFAULT_MAX_VISITED_AT);
+        api.avg_cache = config.avg_cache_size
+            .filter(|&size| size != 0)
+            .map(AverageCache::new);
+        let api = match config.wal_path {
+            Some(ref path) => api.replay(path)
+                .map_err(|e| format!("Unable to replay log: {}", e))?,
+            None => api
         };
-        
-        Arc::new(TravelsServer { api })
+        Arc::new(SharedApi::new(Arc::new(api)))
+    };
+    let dataset = Dataset {
+        path: config.data_file.clone(),
+        shards: nthreads,
+        strict: config.strict_load.unwrap_or(false)
     };
 
-    let nthreads = config.num_threads.unwrap_or_else(num_cpus::get);
+    let cors_origin: Bytes = config.cors_origin.clone()
+        .unwrap_or_else(|| "*".to_string())
+        .into();
+    let request_timeout = config.request_timeout_ms.map(Duration::from_millis);
+    let max_body_bytes = config.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES);
+    let admin_token: Option<Bytes> = config.admin_token.clone().map(Bytes::from);
+    let maintenance = Arc::new(AtomicBool::new(false));
+    let limiter = config.rate_limit_rps.map(|rps| Arc::new(RateLimiter::new(rps)));
+
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+
     let mut threads = Vec::with_capacity(nthreads);
-    for i in 0..nthreads {
-        let service = service.clone();
-        let service_factory = move || service.clone();
+    for (i, listener) in listeners.into_iter().enumerate() {
+        let api = api.clone();
+        let dataset = dataset.clone();
+        let cors_origin = cors_origin.clone();
         let is_keep_alive = config.keep_alive;
+        let tcp_nodelay = config.tcp_nodelay;
+        let pin_threads = config.pin_threads;
+        let admin_token = admin_token.clone();
+        let maintenance = maintenance.clone();
+        let limiter = limiter.clone();
 
-        let address = config.bind.clone();
-        let thread = thread::spawn(move || {
-            scheduler::set_self_affinity(scheduler::CpuSet::single(i))
-                .expect("Failed to set affinity");
-            
-            let mut core = Core::new().expect("Failed to initialize Core");
-            let listener = TcpBuilder::new_v4().expect("Failed to initialize TcpBuilder")
-                .reuse_port(true).expect("Failed to reuse port")
-                .bind(address).expect("Failed to bind")
-                .listen(10000).expect("Failed to listen");
+        let thread = thread::Builder::new().name(format!("worker-{}", i)).spawn(move || -> io::Result<()> {
+            let _stop_others = StopOthers;
 
-            let address = listener.local_addr()
-                .expect("Failed to get address");
+            if pin_threads {
+                pin_worker(i);
+            }
+            
+            let mut core = Core::new()?;
+            let address = listener.local_addr()?;
 
             let handle = core.handle();
-            let listener = TcpListener::from_listener(listener, &address, &handle)
-                .expect("Failed to initialize tcp listener");
+            let listener = TcpListener::from_listener(listener, &address, &handle)?;
             
             let mut http = Http::new();
             http.keep_alive(is_keep_alive);
 
-            let server = listener.incoming().for_each(move |(socket, address)| {
-                socket.set_nodelay(true).expect("Failed to set 'TCP_NODELAY' option");
-                http.bind_connection(&handle, socket, address, service_factory());
+            // reactor timers can't leave the thread, so the service is per thread
+            let timeout = request_timeout.map(|duration| RequestTimeout { 
+                duration, 
+                handle: handle.clone() 
+            });
+            let counters = api.get().counters.clone();
+            let service = Rc::new(TravelsServer { 
+                api: ApiHandle::new(api), 
+                cors_origin, 
+                keep_alive: is_keep_alive, 
+                timeout: timeout.clone(),
+                max_body_bytes,
+                handle: Some(handle.clone()),
+                maintenance,
+                admin_token,
+                dataset: Some(dataset),
+                post_headers: Default::default()
+            });
+
+            let incoming = skip_accept_errors(listener.incoming(), counters.clone(), &handle);
+            let server = incoming.for_each(move |(socket, address)| {
+                if let Err(e) = socket.set_nodelay(tcp_nodelay) {
+                    debug!("Failed to set 'TCP_NODELAY' option: {}", e);
+                }
+                let service = RateLimited { 
+                    server: service.clone(), 
+                    limiter: limiter.clone(), 
+                    client: address.ip() 
+                };
+                match timeout {
+                    Some(ref timeout) => {
+                        let connection = serve_with_timeout(&http, socket, service, timeout);
+                        handle.spawn(track_connection(connection, counters.clone()));
+                    },
+                    None => {
+                        let connection = http.serve_connection(socket, service)
+                            .map(|_| ())
+                            .map_err(|e| debug!("Connection error: {}", e));
+                        handle.spawn(track_connection(connection, counters.clone()));
+                    }
+                }
                 future::ok(())
             });
 
-            core.run(server).expect("Server error");
-        });
+            // the flag is set by a signal handler and has to be polled
+            let shutdown = Interval::new(SHUTDOWN_POLL_INTERVAL, &core.handle())?
+                .take_while(|_| Ok(!SHUTDOWN.load(Ordering::SeqCst)))
+                .for_each(|_| Ok(()));
+
+            core.run(server.select(shutdown).map(|_| ()).map_err(|(e, _)| e))?;
+
+            let drain = Timeout::new(DRAIN_TIME, &core.handle())?;
+            core.run(drain)
+        })?;
         threads.push(thread);
     }
 
-    println!("Server started on {} ({} threads)", config.bind, nthreads);
+    info!("Server started on {} ({} threads)", config.bind, nthreads);
+    let mut failed = 0;
     for thread in threads {
-        thread.join().expect("Thread panic");
+        let name = thread.thread().name().unwrap_or("worker").to_string();
+        match thread.join() {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => {
+                error!("{} failed: {}", name, e);
+                failed += 1;
+            }
+            // the panic message is already printed by the default hook
+            Err(_) => {
+                error!("{} panicked", name);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} workers failed", failed, nthreads).into());
+    }
+    info!("Server stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_overrides() {
+        let config = Config::default().with_env_overrides(|key| match key {
+            "BIND_ADDR" => Some("127.0.0.1:8080".to_string()),
+            "DATA_FILE" => Some("/data.zip".to_string()),
+            "NUM_THREADS" => Some("8".to_string()),
+            "KEEP_ALIVE" => Some("false".to_string()),
+            "WAL_PATH" => Some("/data.log".to_string()),
+            "LOG_LEVEL" => Some("debug".to_string()),
+            "CORS_ORIGIN" => Some("https://example.com".to_string()),
+            "REQUEST_TIMEOUT_MS" => Some("500".to_string()),
+            "MAX_BODY_BYTES" => Some("4096".to_string()),
+            "STRICT_LOAD" => Some("true".to_string()),
+            "WARMUP" => Some("true".to_string()),
+            "LISTEN_BACKLOG" => Some("128".to_string()),
+            "TCP_NODELAY" => Some("false".to_string()),
+            "REUSE_PORT" => Some("false".to_string()),
+            "PIN_THREADS" => Some("false".to_string()),
+            "DUAL_STACK" => Some("true".to_string()),
+            "ADMIN_TOKEN" => Some("secret".to_string()),
+            "RATE_LIMIT_RPS" => Some("100".to_string()),
+            "AVG_TRAILING_ZEROS" => Some("false".to_string()),
+            "MAX_MULTIGET" => Some("10".to_string()),
+            "CASE_INSENSITIVE_COUNTRY" => Some("true".to_string()),
+            "UNIQUE_VISIT_TIME" => Some("true".to_string()),
+            "MIN_VISITED_AT" => Some("0".to_string()),
+            "MAX_VISITED_AT" => Some("2000000000".to_string()),
+            "AVG_CACHE_SIZE" => Some("1000".to_string()),
+            "TLS_CERT" => Some("/cert.pem".to_string()),
+            "TLS_KEY" => Some("/key.pem".to_string()),
+            _ => None
+        });
+
+        assert_eq!(config.bind, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.data_file, "/data.zip");
+        assert_eq!(config.num_threads, Some(8));
+        assert!(!config.keep_alive);
+        assert_eq!(config.wal_path, Some("/data.log".to_string()));
+        assert_eq!(config.log_level, Some("debug".to_string()));
+        assert_eq!(config.cors_origin, Some("https://example.com".to_string()));
+        assert_eq!(config.request_timeout_ms, Some(500));
+        assert_eq!(config.max_body_bytes, Some(4096));
+        assert_eq!(config.strict_load, Some(true));
+        assert_eq!(config.warmup, Some(true));
+        assert_eq!(config.listen_backlog, 128);
+        assert!(!config.tcp_nodelay);
+        assert!(!config.reuse_port);
+        assert!(!config.pin_threads);
+        assert!(config.dual_stack);
+        assert_eq!(config.admin_token, Some("secret".to_string()));
+        assert_eq!(config.rate_limit_rps, Some(100));
+        assert!(!config.avg_trailing_zeros);
+        assert_eq!(config.max_multiget, Some(10));
+        assert!(config.case_insensitive_country);
+        assert!(config.unique_visit_time);
+        assert_eq!(config.min_visited_at, Some(0));
+        assert_eq!(config.max_visited_at, Some(2000000000));
+        assert_eq!(config.avg_cache_size, Some(1000));
+        assert_eq!(config.tls_cert, Some("/cert.pem".to_string()));
+        assert_eq!(config.tls_key, Some("/key.pem".to_string()));
+    }
+
+    #[test]
+    fn socket_options_defaults() {
+        let config: Config = serde_yaml::from_str("bind: 0.0.0.0:80\ndata_file: data.zip\nkeep_alive: true").unwrap();
+        assert_eq!(config.listen_backlog, DEFAULT_LISTEN_BACKLOG);
+        assert!(config.tcp_nodelay);
+        assert!(config.reuse_port);
+        assert!(config.avg_trailing_zeros);
+    }
+
+    #[test]
+    fn run_on_bound_port() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Config {
+            bind: listener.local_addr().unwrap(),
+            num_threads: Some(1),
+            ..Config::default()
+        };
+
+        let error = run(config).unwrap_err();
+        assert!(error.to_string().starts_with("Unable to listen on"), "{}", error);
+    }
+
+    #[test]
+    fn tls_is_refused() {
+        let config = Config { tls_cert: Some("cert.pem".to_string()), ..Config::default() };
+        let error = run(config).unwrap_err();
+        assert!(error.to_string().starts_with("TLS is not supported"), "{}", error);
+    }
+
+    #[test]
+    fn ipv6_listeners() {
+        use std::net::TcpStream;
+
+        let config = Config { bind: "[::1]:0".parse().unwrap(), ..Config::default() };
+        let listener = listen(&config).unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(address.is_ipv6());
+        TcpStream::connect(address).unwrap();
+        // 'reuse_port' lets another thread listen on the same port
+        listen(&Config { bind: address, ..config }).unwrap();
+
+        let config = Config { bind: "127.0.0.1:0".parse().unwrap(), dual_stack: true, ..Config::default() };
+        let listener = listen(&config).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(listener.local_addr().unwrap().is_ipv6());
+        TcpStream::connect(("127.0.0.1", port)).unwrap();
+        TcpStream::connect(("::1", port)).unwrap();
+    }
+
+    #[test]
+    fn more_workers_than_cores() {
+        let workers: Vec<_> = (0..num_cpus::get() * 2 + 1)
+            .map(|i| thread::spawn(move || pin_worker(i)))
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn validated() {
+        let config = Config { listen_backlog: -1, reuse_port: false, ..Config::default() }
+            .validated(4);
+        assert_eq!(config.listen_backlog, DEFAULT_LISTEN_BACKLOG);
+        assert_eq!(config.num_threads, Some(1));
+
+        let config = Config { reuse_port: false, num_threads: Some(1), ..Config::default() }
+            .validated(1);
+        assert_eq!(config.num_threads, Some(1));
+        assert!(!config.reuse_port);
+
+        let config = Config { rate_limit_rps: Some(0), ..Config::default() }.validated(1);
+        assert_eq!(config.rate_limit_rps, None);
+    }
+
+    #[test]
+    fn invalid_env_overrides() {
+        let config = Config::default().with_env_overrides(|key| match key {
+            "BIND_ADDR" => Some("localhost".to_string()),
+            "NUM_THREADS" => Some("many".to_string()),
+            "KEEP_ALIVE" => Some("yes".to_string()),
+            _ => None
+        });
+
+        let default = Config::default();
+        assert_eq!(config.bind, default.bind);
+        assert_eq!(config.data_file, default.data_file);
+        assert_eq!(config.num_threads, default.num_threads);
+        assert_eq!(config.keep_alive, default.keep_alive);
     }
 }
@@ -1,99 +1,229 @@
 use data::*;
-use serde::de::{Deserializer, Deserialize};
+use serde::{Serialize, Serializer};
+use serde::de::{self, Deserializer, Deserialize};
 
 #[derive(Debug)]
 pub enum Request {
     Get(GetRequest),
-    Post(PostRequest)
+    // same as 'Get', but only headers are sent back
+    Head(GetRequest),
+    Post(PostRequest),
+    Delete(DeleteRequest),
+    // CORS preflight, answered by the server itself
+    Preflight,
+    // 'OPTIONS /', description of the routes
+    Discovery,
+    // 'GET /debug/trace', the last requests served
+    #[cfg(feature = "trace")]
+    Trace
 }
 
 #[derive(Debug)]
 pub enum GetRequest {
     GetEntity(GetEntity),
     GetVisits(UserId, GetVisits),
-    GetAverageLocationRating(LocationId, GetAverageLocationRating)
+    // '/locations/<id>/visits', by time with the users who made them
+    GetLocationVisits(LocationId, GetLocationVisits),
+    GetAverageLocationRating(LocationId, GetAverageLocationRating),
+    // visits with ids in 'from_id..to_id'
+    GetVisitRange(VisitId, VisitId),
+    // '/users?ids=1,2,3', the entities found and the ids that weren't
+    GetEntities(EntityKind, Vec<u32>),
+    Health,
+    // '?verbose=1' adds the health of the index, see 'Database::stats'
+    Stats(bool),
+    // Prometheus text format
+    Metrics,
+    // '/export/{entity}', every entity as a line of JSON
+    Export(EntityKind)
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntityKind {
+    Users,
+    Locations,
+    Visits
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub enum PostRequest {
     UpdateEntity(UpdateEntity),
+    // 'If-Match', applied only if the entity is still at the version
+    UpdateEntityIfVersion(UpdateEntity, u64),
     CreateEntity(CreateEntity)
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum GetEntity {
     User(UserId),
+    // '?expand=visits', the user and its visits, at most 'limit' of them
+    UserWithVisits(UserId, Option<usize>),
     Location(LocationId),
+    // '?withVisits=count', the location and the number of its visits
+    LocationWithVisitCount(LocationId),
     Visit(VisitId)
 }
 
+impl GetEntity {
+    #[inline]
+    pub fn id(&self) -> EntityId {
+        match *self {
+            GetEntity::User(id) | GetEntity::UserWithVisits(id, _) => EntityId::User(id),
+            GetEntity::Location(id) | GetEntity::LocationWithVisitCount(id) => EntityId::Location(id),
+            GetEntity::Visit(id) => EntityId::Visit(id)
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct GetVisits {
     pub from_date:   Option<Timestamp>,
     pub to_date:     Option<Timestamp>,
+    // bounds exclude visits exactly at the dates by default
+    pub from_date_inclusive: bool,
+    pub to_date_inclusive:   bool,
     pub country:     Option<String>,
-    pub to_distance: Option<u32>
+    pub city:        Option<String>,
+    // 'fromDistance' is inclusive and 'toDistance' exclusive, so adjacent
+    // bands don't overlap
+    pub from_distance: Option<u32>,
+    pub to_distance: Option<u32>,
+    // unlike dates mark bounds are inclusive
+    pub from_mark:   Option<u8>,
+    pub to_mark:     Option<u8>,
+    pub limit:       Option<usize>,
+    // 'after=<visited_at>:<id>', resumes the listing after the visit in its
+    // order. Limited responses have the 'next' cursor when visits remain
+    pub after:       Option<Cursor>,
+    pub order:       Order,
+    // respond with the number of matching visits only
+    pub count:       bool
+}
+
+// position in a user's visits, the key of 'VisitMap'
+pub type Cursor = (Timestamp, VisitId);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Order {
+    Ascending,
+    Descending
 }
 
+impl Default for Order {
+    #[inline]
+    fn default() -> Self {
+        Order::Ascending
+    }
+}
+
+// filters of 'GetVisits' that apply to the visits of a location
 #[derive(Default, Debug)]
+pub struct GetLocationVisits {
+    pub from_date: Option<Timestamp>,
+    pub to_date:   Option<Timestamp>,
+    pub from_date_inclusive: bool,
+    pub to_date_inclusive:   bool,
+    pub from_mark: Option<u8>,
+    pub to_mark:   Option<u8>
+}
+
+#[derive(Hash, Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct GetAverageLocationRating {
     pub from_date: Option<Timestamp>,
     pub to_date:   Option<Timestamp>,
+    pub from_date_inclusive: bool,
+    pub to_date_inclusive:   bool,
     pub from_age:  Option<Timestamp>,
     pub to_age:    Option<Timestamp>,
-    pub gender:    Option<Gender>
+    pub gender:    Option<Gender>,
+    // decimal places of the average, 'DEFAULT_DECIMAL_PLACES' if not set
+    pub round:     Option<usize>,
+    // adds the number of averaged marks to the response
+    pub with_count: bool
 }
 
-#[derive(Debug)]
+pub const DEFAULT_DECIMAL_PLACES: usize = 5;
+pub const MAX_DECIMAL_PLACES: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug)]
 pub enum UpdateEntity {
     User(UserId, UserUpdate),
     Location(LocationId, LocationUpdate),
     Visit(VisitId, VisitUpdate)
 }
 
-#[derive(Deserialize, Debug)]
+impl UpdateEntity {
+    #[inline]
+    pub fn id(&self) -> EntityId {
+        match *self {
+            UpdateEntity::User(id, _) => EntityId::User(id),
+            UpdateEntity::Location(id, _) => EntityId::Location(id),
+            UpdateEntity::Visit(id, _) => EntityId::Visit(id)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct UserUpdate {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub email:      Optional<String>,
-    #[serde(default)]    
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub first_name: Optional<String>,
-    #[serde(default)]    
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub last_name:  Optional<String>,
-    #[serde(default)]    
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub gender:     Optional<Gender>,
-    #[serde(default)]    
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub birth_date: Optional<Timestamp>
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct LocationUpdate {
-    #[serde(default)]    
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub place:    Optional<String>,
-    #[serde(default)]    
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub country:  Optional<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub city:     Optional<String>,
-    #[serde(default)]    
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub distance: Optional<u32>
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct VisitUpdate {
-    #[serde(default)]    
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub location:   Optional<LocationId>,
-    #[serde(default)]    
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub user:       Optional<UserId>,
-    #[serde(default)]    
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub visited_at: Optional<Timestamp>,
-    #[serde(default)]    
+    #[serde(default, skip_serializing_if = "Optional::is_nothing")]
     pub mark:       Optional<u8>
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
+pub enum DeleteEntity {
+    User(UserId),
+    Location(LocationId),
+    Visit(VisitId)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteRequest {
+    pub entity:  DeleteEntity,
+    // also delete visits referencing the user/location
+    pub cascade: bool
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub enum CreateEntity {
     User(User),
     Location(Location),
-    Visit(Visit)
+    Visit(Visit),
+    // all or nothing, a single invalid entity rejects the whole batch
+    UserBatch(Vec<User>),
+    LocationBatch(Vec<Location>),
+    VisitBatch(Vec<Visit>)
 }
 
 // Custom 'Option' type to generate errors when deserializing 'null' value
@@ -103,6 +233,16 @@ pub enum Optional<T> {
     Nothing
 }
 
+impl<T> Optional<T> {
+    #[inline]
+    pub fn is_nothing(&self) -> bool {
+        match *self {
+            Optional::Nothing => true,
+            Optional::Something(_) => false
+        }
+    }
+}
+
 impl<T> Default for Optional<T> {
     #[inline]
     fn default() -> Self {
@@ -110,6 +250,8 @@ impl<T> Default for Optional<T> {
     }
 }
 
+// A missing field is 'Nothing' (see '#[serde(default)]'), while 'null' is
+// rejected, updates can't clear fields
 impl<'de, T> Deserialize<'de> for Optional<T> 
     where T: Deserialize<'de> {
     
@@ -118,7 +260,25 @@ impl<'de, T> Deserialize<'de> for Optional<T>
     where
         D: Deserializer<'de>
     {
-        let value = T::deserialize(deserializer)?;
-        Ok(Optional::Something(value))
+        match Option::<T>::deserialize(deserializer)? {
+            Some(value) => Ok(Optional::Something(value)),
+            None => Err(de::Error::custom("null is not allowed"))
+        }
     }
-}
\ No newline at end of file
+}
+
+// 'Nothing' fields are expected to be skipped
+impl<T> Serialize for Optional<T>
+    where T: Serialize {
+
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer
+    {
+        match *self {
+            Optional::Something(ref value) => value.serialize(serializer),
+            Optional::Nothing => serializer.serialize_none()
+        }
+    }
+}
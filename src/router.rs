@@ -1,66 +1,337 @@
+use std::borrow::Cow;
+use std::vec;
+
+use bytes::Bytes;
 use hyper::{StatusCode, Uri, Method};
 
-use data::{LocationId, UserId, VisitId};
-use request::{self, GetEntity, CreateEntity, UpdateEntity, Request as ApiRequest, GetRequest, PostRequest};
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use api::ApiError;
+use data::{LocationId, UserId, VisitId, User, Location, Visit, Name, Gender, Timestamp};
+use request::{self, GetEntity, EntityKind, CreateEntity, UpdateEntity, DeleteEntity, Request as ApiRequest, GetRequest, PostRequest, DeleteRequest};
+
+// sent in 'Allow' header of '405 Method Not Allowed' responses
+pub static ALLOWED_METHODS: [Method; 4] = [Method::Get, Method::Head, Method::Post, Method::Delete];
+
+#[derive(Serialize)]
+pub struct Route {
+    pub method:     &'static str,
+    pub path:       &'static str,
+    pub parameters: &'static [&'static str],
+}
+
+// described by 'OPTIONS /', keep in sync with the routing below
+pub static ROUTES: [Route; 17] = [
+    Route { method: "GET", path: "/users/{id}", parameters: &["expand", "limit"] },
+    Route { method: "GET", path: "/locations/{id}", parameters: &["withVisits"] },
+    Route { method: "GET", path: "/visits/{id}", parameters: &[] },
+    Route { method: "GET", path: "/users/{id}/visits", parameters: &[
+        "fromDate", "toDate", "fromDateInclusive", "toDateInclusive", "country", "city",
+        "fromDistance", "toDistance", "fromMark", "toMark", "limit", "after", "order", "count"] },
+    Route { method: "GET", path: "/locations/{id}/visits", parameters: &[
+        "fromDate", "toDate", "fromDateInclusive", "toDateInclusive", "fromMark", "toMark"] },
+    Route { method: "GET", path: "/locations/{id}/avg", parameters: &[
+        "fromDate", "toDate", "fromDateInclusive", "toDateInclusive",
+        "fromAge", "toAge", "gender", "round", "withCount"] },
+    Route { method: "GET", path: "/users", parameters: &["ids"] },
+    Route { method: "GET", path: "/locations", parameters: &["ids"] },
+    Route { method: "GET", path: "/visits", parameters: &["fromId", "toId", "ids"] },
+    Route { method: "GET", path: "/health", parameters: &[] },
+    Route { method: "GET", path: "/stats", parameters: &["verbose"] },
+    Route { method: "GET", path: "/metrics", parameters: &[] },
+    Route { method: "GET", path: "/export/{entity}", parameters: &[] },
+    Route { method: "POST", path: "/{entity}/new", parameters: &[] },
+    Route { method: "POST", path: "/{entity}/bulk", parameters: &[] },
+    Route { method: "POST", path: "/{entity}/{id}", parameters: &[] },
+    Route { method: "DELETE", path: "/{entity}/{id}", parameters: &["cascade"] },
+];
+
+lazy_static! {
+    static ref DISCOVERY: Bytes = {
+        use serde_json;
+        serde_json::to_vec(&ROUTES[..]).unwrap().into()
+    };
+}
+
+#[inline]
+pub fn discovery() -> Bytes {
+    DISCOVERY.clone()
+}
 
+// Only invalid bodies of create requests get a message
 #[inline]
-pub fn route(method: Method, uri: Uri, body: &[u8]) -> Result<ApiRequest, StatusCode> {
+pub fn route(method: Method, uri: Uri, body: &[u8]) -> Result<ApiRequest, ApiError> {
+    let uri = canonical(uri)?;
     match method {
-        Method::Get => route_get_request(uri).map(ApiRequest::Get),
+        #[cfg(feature = "trace")]
+        Method::Get if uri.path() == "/debug/trace" => Ok(ApiRequest::Trace),
+        Method::Get => Ok(ApiRequest::Get(route_get_request(uri)?)),
+        Method::Head => Ok(ApiRequest::Head(route_get_request(uri)?)),
         Method::Post => route_post_request(uri, body).map(ApiRequest::Post),
-        _ => Err(StatusCode::BadRequest),
+        Method::Delete => Ok(ApiRequest::Delete(route_delete_request(uri)?)),
+        Method::Options if uri.path() == "/" => Ok(ApiRequest::Discovery),
+        Method::Options => Ok(ApiRequest::Preflight),
+        _ => Err(StatusCode::MethodNotAllowed.into()),
+    }
+}
+
+// Routes match paths without empty segments, so '/users/1/', '//users/1' 
+// and '/users//1' are all '/users/1'. Only the non-canonical ones are copied
+#[inline]
+fn canonical(uri: Uri) -> Result<Uri, StatusCode> {
+    let path = uri.path();
+    if !path.contains("//") && (path == "/" || !path.ends_with('/')) {
+        return Ok(uri);
+    }
+
+    let mut canonical = String::with_capacity(path.len());
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        canonical.push('/');
+        canonical.push_str(segment);
+    }
+    if canonical.is_empty() {
+        canonical.push('/');
+    }
+    if let Some(query) = uri.query() {
+        canonical.push('?');
+        canonical.push_str(query);
+    }
+    canonical.parse().map_err(|_| StatusCode::BadRequest)
+}
+
+// The resources of an entity id, see 'entity_request'
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Shape {
+    User,
+    UserVisits,
+    Location,
+    LocationVisits,
+    LocationAverage,
+    Visit
+}
+
+// The hot paths, '/users/{id}', '/users/{id}/visits', '/locations/{id}', 
+// '/locations/{id}/avg', '/locations/{id}/visits' and '/visits/{id}' with
+// a plain decimal id, in a single pass over the bytes. Anything else is
+// 'None' and left to the general routing, which agrees on these paths
+#[inline]
+fn fast_shape(path: &[u8]) -> Option<(Shape, u32)> {
+    let (entity, rest) = if path.starts_with(b"/users/") {
+        (Shape::User, &path[7..])
+    } else if path.starts_with(b"/locations/") {
+        (Shape::Location, &path[11..])
+    } else if path.starts_with(b"/visits/") {
+        (Shape::Visit, &path[8..])
+    } else {
+        return None;
+    };
+
+    let mut id = 0u64;
+    let mut digits = 0;
+    for &byte in rest.iter().take_while(|byte| byte.is_ascii_digit()) {
+        id = id * 10 + (byte - b'0') as u64;
+        digits += 1;
+        // longer ids overflow, which is up to 'parse_id'
+        if digits > 10 {
+            return None;
+        }
     }
+    if digits == 0 || id > u32::MAX as u64 {
+        return None;
+    }
+
+    let shape = match (entity, &rest[digits..]) {
+        (_, b"") => entity,
+        (Shape::User, b"/visits") => Shape::UserVisits,
+        (Shape::Location, b"/visits") => Shape::LocationVisits,
+        (Shape::Location, b"/avg") => Shape::LocationAverage,
+        _ => return None
+    };
+    Some((shape, id as u32))
 }
 
+// Precedence of errors: an id that isn't a number is '404 Not Found', then
+// invalid parameters are '400 Bad Request' whether the entity exists or not
+// (it's only looked up by 'Api'), then an unknown id is '404 Not Found'
 #[inline]
 fn route_get_request(uri: Uri) -> Result<GetRequest, StatusCode> {
+    match fast_shape(uri.path().as_bytes()) {
+        Some((shape, id)) => entity_request(shape, id, uri.query()),
+        None => route_path(uri)
+    }
+}
+
+// every GET path, one segment at a time
+#[inline]
+fn route_path(uri: Uri) -> Result<GetRequest, StatusCode> {
     let path = uri.path();
-    let id: u32 = path.split('/')
-        .nth(2)
-        .ok_or(StatusCode::BadRequest)?
-        .parse()
-        .map_err(|_| StatusCode::NotFound)?;
-
-    let request = if path.ends_with("/avg") {
-        let parameters = {
-            match uri.query() {
-                Some(query) => parse_alr_parameters(query)?,
-                None => Default::default()
-            }
-        };
-        GetRequest::GetAverageLocationRating(LocationId(id), parameters)
+    match path {
+        "/health" => return Ok(GetRequest::Health),
+        "/stats" => return parse_verbose(uri.query().unwrap_or("")).map(GetRequest::Stats),
+        "/metrics" => return Ok(GetRequest::Metrics),
+        "/users" => return parse_ids(uri.query().unwrap_or(""))
+            .map(|ids| GetRequest::GetEntities(EntityKind::Users, ids)),
+        "/locations" => return parse_ids(uri.query().unwrap_or(""))
+            .map(|ids| GetRequest::GetEntities(EntityKind::Locations, ids)),
+        "/visits" => return parse_visit_range(uri.query().unwrap_or("")),
+        "/export/users" => return Ok(GetRequest::Export(EntityKind::Users)),
+        "/export/locations" => return Ok(GetRequest::Export(EntityKind::Locations)),
+        "/export/visits" => return Ok(GetRequest::Export(EntityKind::Visits)),
+        _ => {}
+    }
+
+    let id = parse_id(path.split('/').nth(2).ok_or(StatusCode::BadRequest)?)?;
+
+    let shape = if path.ends_with("/avg") {
+        Shape::LocationAverage
+    } else if path.ends_with("/visits") && path.starts_with("/locations/") {
+        Shape::LocationVisits
     } else if path.ends_with("/visits") {
-        let parameters = {
-            match uri.query() {
-                Some(query) => parse_visits_parameters(query)?,
-                None => Default::default()
-            }
-        };
-        GetRequest::GetVisits(UserId(id), parameters)
+        Shape::UserVisits
     } else {
-        let request = match path.split('/').nth(1).ok_or(StatusCode::NotFound)? {
-            "users" => GetEntity::User(UserId(id)),
-            "locations" => GetEntity::Location(LocationId(id)),
-            "visits" => GetEntity::Visit(VisitId(id)),
+        match path.split('/').nth(1).ok_or(StatusCode::NotFound)? {
+            "users" => Shape::User,
+            "locations" => Shape::Location,
+            "visits" => Shape::Visit,
             _ => return Err(StatusCode::BadRequest),
-        };
+        }
+    };
 
-        GetRequest::GetEntity(request)
+    entity_request(shape, id, uri.query())
+}
+
+#[inline]
+fn entity_request(shape: Shape, id: u32, query: Option<&str>) -> Result<GetRequest, StatusCode> {
+    let request = match shape {
+        Shape::LocationAverage => {
+            let parameters = match query {
+                Some(query) => parse_alr_parameters(query)?,
+                None => Default::default()
+            };
+            GetRequest::GetAverageLocationRating(LocationId(id), parameters)
+        },
+        Shape::LocationVisits => {
+            let parameters = match query {
+                Some(query) => parse_location_visits_parameters(query)?,
+                None => Default::default()
+            };
+            GetRequest::GetLocationVisits(LocationId(id), parameters)
+        },
+        Shape::UserVisits => {
+            let parameters = match query {
+                Some(query) => parse_visits_parameters(query)?,
+                None => Default::default()
+            };
+            GetRequest::GetVisits(UserId(id), parameters)
+        },
+        Shape::User => GetRequest::GetEntity(match query {
+            Some(query) => match parse_expand(query)? {
+                (true, limit) => GetEntity::UserWithVisits(UserId(id), limit),
+                (false, _) => GetEntity::User(UserId(id))
+            },
+            None => GetEntity::User(UserId(id))
+        }),
+        Shape::Location => GetRequest::GetEntity(match query {
+            Some(query) if parse_with_visit_count(query)? 
+                => GetEntity::LocationWithVisitCount(LocationId(id)),
+            _ => GetEntity::Location(LocationId(id))
+        }),
+        Shape::Visit => GetRequest::GetEntity(GetEntity::Visit(VisitId(id)))
     };
 
     Ok(request)
 }
 
+// Some clients percent-encode every path segment, '/users/%31' is '/users/1'
+#[inline]
+fn parse_id(segment: &str) -> Result<u32, StatusCode> {
+    use percent_encoding;
+    let segment = percent_encoding::percent_decode(segment.as_bytes())
+        .decode_utf8()
+        .map_err(|_| StatusCode::BadRequest)?;
+
+    // an encoded '/' must not be taken for a segment separator
+    if segment.contains('/') {
+        return Err(StatusCode::BadRequest);
+    }
+
+    segment.parse().map_err(|_| StatusCode::NotFound)
+}
+
+#[inline]
+fn parse_flag(value: &str) -> Result<bool, StatusCode> {
+    match value {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(StatusCode::BadRequest)
+    }
+}
+
+#[inline]
+fn parse_mark(value: &str) -> Result<u8, StatusCode> {
+    use data::is_valid_mark;
+    match value.parse() {
+        Ok(mark) if is_valid_mark(mark) => Ok(mark),
+        _ => Err(StatusCode::BadRequest)
+    }
+}
+
+#[inline]
+fn decode_string(value: &str) -> Result<Cow<'_, str>, StatusCode> {
+    use percent_encoding;
+    let value = percent_encoding::percent_decode(value.as_bytes())
+        .decode_utf8()
+        .map_err(|_| StatusCode::BadRequest)?;
+
+    // hack for 'application/x-www-form-urlencoded' percent encoding
+    if value.contains('+') {
+        Ok(Cow::Owned(value.replace('+', " ")))
+    } else {
+        Ok(value)
+    }
+}
+
+// Percent-decoded query string pairs, split on the first '=' only, so values
+// may contain it. Pairs without '=' and repeated names are rejected
+struct QueryParams<'a> {
+    pairs: Vec<(Cow<'a, str>, Cow<'a, str>)>
+}
+
+impl<'a> QueryParams<'a> {
+    fn parse(query: &'a str) -> Result<QueryParams<'a>, StatusCode> {
+        let mut pairs: Vec<(Cow<str>, Cow<str>)> = Vec::new();
+        for pair in query.split('&') {
+            let mut iter = pair.splitn(2, '=');
+            let name = decode_string(iter.next().ok_or(StatusCode::BadRequest)?)?;
+            let value = decode_string(iter.next().ok_or(StatusCode::BadRequest)?)?;
+
+            if pairs.iter().any(|(seen, _)| *seen == name) {
+                return Err(StatusCode::BadRequest);
+            }
+            pairs.push((name, value));
+        }
+
+        Ok(QueryParams { pairs })
+    }
+}
+
+impl<'a> IntoIterator for QueryParams<'a> {
+    type Item = (Cow<'a, str>, Cow<'a, str>);
+    type IntoIter = vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.pairs.into_iter()
+    }
+}
+
 #[inline]
 fn parse_visits_parameters(query: &str) -> Result<request::GetVisits, StatusCode> {
     let mut result = request::GetVisits::default();
 
-    for pair in query.split('&') {
-        let mut iter = pair.split('=');
-        let name  = iter.next().ok_or(StatusCode::BadRequest)?;
-        let value = iter.next().ok_or(StatusCode::BadRequest)?;
-
-        match name {
+    for (name, value) in QueryParams::parse(query)? {
+        let value: &str = &value;
+        match &*name {
             "fromDate" => {
                 let from_date = value.parse()
                     .map_err(|_| StatusCode::BadRequest)?;
@@ -71,21 +342,36 @@ fn parse_visits_parameters(query: &str) -> Result<request::GetVisits, StatusCode
                     .map_err(|_| StatusCode::BadRequest)?;
                 result.to_date = Some(to_date);
             },
-            "country" => {
-                use percent_encoding;
-                let country = percent_encoding::percent_decode(value.as_bytes())
-                    .decode_utf8()
-                    .map_err(|_| StatusCode::BadRequest)?
-                    // hack for 'application/x-www-form-urlencoded' percent encoding
-                    .replace('+', " ");
-
-                result.country = Some(country.into());
+            "country" => result.country = Some(value.to_string()),
+            "city" => result.city = Some(value.to_string()),
+            "fromDistance" => {
+                let from_distance = value.parse()
+                    .map_err(|_| StatusCode::BadRequest)?;
+                result.from_distance = Some(from_distance);
             },
             "toDistance" => {
                 let to_distance = value.parse()
                     .map_err(|_| StatusCode::BadRequest)?;
                 result.to_distance = Some(to_distance);
             },
+            "fromMark" => result.from_mark = Some(parse_mark(value)?),
+            "toMark" => result.to_mark = Some(parse_mark(value)?),
+            "limit" => {
+                let limit = value.parse()
+                    .map_err(|_| StatusCode::BadRequest)?;
+                result.limit = Some(limit);
+            },
+            "after" => result.after = Some(parse_cursor(value)?),
+            "order" => {
+                result.order = match value {
+                    "asc" => request::Order::Ascending,
+                    "desc" => request::Order::Descending,
+                    _ => return Err(StatusCode::BadRequest),
+                };
+            },
+            "fromDateInclusive" => result.from_date_inclusive = parse_flag(value)?,
+            "toDateInclusive" => result.to_date_inclusive = parse_flag(value)?,
+            "count" => result.count = parse_flag(value)?,
             _ => return Err(StatusCode::BadRequest)
         }
     }
@@ -93,25 +379,160 @@ fn parse_visits_parameters(query: &str) -> Result<request::GetVisits, StatusCode
     Ok(result)
 }
 
+// other parameters of entity requests are ignored
+#[inline]
+fn parse_with_visit_count(query: &str) -> Result<bool, StatusCode> {
+    let mut with_visit_count = false;
+    for (name, value) in QueryParams::parse(query)? {
+        if name == "withVisits" {
+            if value != "count" {
+                return Err(StatusCode::BadRequest);
+            }
+            with_visit_count = true;
+        }
+    }
+    Ok(with_visit_count)
+}
+
+// whether visits are expanded and how many of them, 'limit' alone is ignored
+#[inline]
+fn parse_expand(query: &str) -> Result<(bool, Option<usize>), StatusCode> {
+    let (mut expand, mut limit) = (false, None);
+    for (name, value) in QueryParams::parse(query)? {
+        match &*name {
+            "expand" => {
+                if value != "visits" {
+                    return Err(StatusCode::BadRequest);
+                }
+                expand = true;
+            },
+            "limit" => {
+                let value = value.parse()
+                    .map_err(|_| StatusCode::BadRequest)?;
+                limit = Some(value);
+            },
+            _ => {}
+        }
+    }
+    Ok((expand, limit))
+}
+
+// '<visited_at>:<id>'
+#[inline]
+fn parse_cursor(value: &str) -> Result<request::Cursor, StatusCode> {
+    let mut parts = value.splitn(2, ':');
+    let visited_at = parts.next().and_then(|visited_at| visited_at.parse().ok());
+    let id = parts.next().and_then(|id| id.parse().ok());
+    match (visited_at, id) {
+        (Some(visited_at), Some(id)) => Ok((visited_at, VisitId(id))),
+        _ => Err(StatusCode::BadRequest)
+    }
+}
+
+#[inline]
+fn parse_verbose(query: &str) -> Result<bool, StatusCode> {
+    let mut verbose = false;
+    if query.is_empty() {
+        return Ok(verbose);
+    }
+    for (name, value) in QueryParams::parse(query)? {
+        match &*name {
+            "verbose" => verbose = parse_flag(&value)?,
+            _ => return Err(StatusCode::BadRequest)
+        }
+    }
+    Ok(verbose)
+}
+
+// Comma-separated 'ids', the only parameter of multi-get requests
+#[inline]
+fn parse_id_list(value: &str) -> Result<Vec<u32>, StatusCode> {
+    value.split(',')
+        .map(|id| id.parse().map_err(|_| StatusCode::BadRequest))
+        .collect()
+}
+
+#[inline]
+fn parse_ids(query: &str) -> Result<Vec<u32>, StatusCode> {
+    let mut ids = None;
+    for (name, value) in QueryParams::parse(query)? {
+        match &*name {
+            "ids" => ids = Some(parse_id_list(&value)?),
+            _ => return Err(StatusCode::BadRequest)
+        }
+    }
+    ids.ok_or(StatusCode::BadRequest)
+}
+
+// both bounds are required, a full scan has to be asked for explicitly.
+// With 'ids' instead, the visits are looked up one by one
+#[inline]
+fn parse_visit_range(query: &str) -> Result<GetRequest, StatusCode> {
+    let (mut from_id, mut to_id, mut ids) = (None, None, None);
+    for (name, value) in QueryParams::parse(query)? {
+        match &*name {
+            "fromId" => from_id = Some(VisitId(value.parse().map_err(|_| StatusCode::BadRequest)?)),
+            "toId" => to_id = Some(VisitId(value.parse().map_err(|_| StatusCode::BadRequest)?)),
+            "ids" => ids = Some(parse_id_list(&value)?),
+            _ => return Err(StatusCode::BadRequest)
+        }
+    }
+
+    match (from_id, to_id, ids) {
+        (Some(from_id), Some(to_id), None) => Ok(GetRequest::GetVisitRange(from_id, to_id)),
+        (None, None, Some(ids)) => Ok(GetRequest::GetEntities(EntityKind::Visits, ids)),
+        _ => Err(StatusCode::BadRequest)
+    }
+}
+
+#[inline]
+fn parse_location_visits_parameters(query: &str) -> Result<request::GetLocationVisits, StatusCode> {
+    let mut result = request::GetLocationVisits::default();
+    for (name, value) in QueryParams::parse(query)? {
+        let value: &str = &value;
+        match &*name {
+            "fromDate" => result.from_date = Some(value.parse()
+                .map_err(|_| StatusCode::BadRequest)?),
+            "toDate" => result.to_date = Some(value.parse()
+                .map_err(|_| StatusCode::BadRequest)?),
+            "fromDateInclusive" => result.from_date_inclusive = parse_flag(value)?,
+            "toDateInclusive" => result.to_date_inclusive = parse_flag(value)?,
+            "fromMark" => result.from_mark = Some(parse_mark(value)?),
+            "toMark" => result.to_mark = Some(parse_mark(value)?),
+            _ => return Err(StatusCode::BadRequest),
+        };
+    }
+
+    Ok(result)
+}
+
 #[inline]
 fn parse_alr_parameters(query: &str) -> Result<request::GetAverageLocationRating, StatusCode> {
     use data::Gender;
 
     let mut result = request::GetAverageLocationRating::default();
-    for pair in query.split('&') {
-        let mut iter = pair.split('=');
-        let name  = iter.next().ok_or(StatusCode::BadRequest)?;
-        let value = iter.next().ok_or(StatusCode::BadRequest)?;
-
-        match name {
+    for (name, value) in QueryParams::parse(query)? {
+        let value: &str = &value;
+        match &*name {
             "fromDate" => result.from_date = Some(value.parse()
                 .map_err(|_| StatusCode::BadRequest)?),
             "toDate" => result.to_date = Some(value.parse()
                 .map_err(|_| StatusCode::BadRequest)?),
+            "fromDateInclusive" => result.from_date_inclusive = parse_flag(value)?,
+            "toDateInclusive" => result.to_date_inclusive = parse_flag(value)?,
+            "withCount" => result.with_count = parse_flag(value)?,
             "fromAge" => result.from_age = Some(value.parse()
                 .map_err(|_| StatusCode::BadRequest)?),
             "toAge" => result.to_age = Some(value.parse()
                 .map_err(|_| StatusCode::BadRequest)?),
+            "round" => {
+                let round = value.parse()
+                    .map_err(|_| StatusCode::BadRequest)?;
+                if round > request::MAX_DECIMAL_PLACES {
+                    return Err(StatusCode::BadRequest);
+                }
+                result.round = Some(round);
+            },
             "gender" => {
                 match value {
                     "m" => result.gender = Some(Gender::Male),
@@ -126,10 +547,106 @@ fn parse_alr_parameters(query: &str) -> Result<request::GetAverageLocationRating
     Ok(result)
 }
 
+#[derive(Clone, Copy)]
+enum FieldType {
+    String,
+    U8,
+    U32,
+    I64
+}
+
+// name, messages for a missing and a mistyped field, type
+type Field = (&'static str, &'static str, &'static str, FieldType);
+
+macro_rules! fields {
+    ($($name:expr => $field_type:ident),*) => {
+        &[$(($name, concat!("missing field: ", $name), concat!("invalid type: ", $name), FieldType::$field_type)),*]
+    }
+}
+
+static USER_FIELDS: &[Field] = fields!(
+    "id" => U32, "email" => String, "first_name" => String, "last_name" => String,
+    "gender" => String, "birth_date" => I64);
+static LOCATION_FIELDS: &[Field] = fields!(
+    "id" => U32, "place" => String, "country" => String, "city" => String, "distance" => U32);
+static VISIT_FIELDS: &[Field] = fields!(
+    "id" => U32, "location" => U32, "user" => U32, "visited_at" => I64, "mark" => U8);
+
+// Created entities refuse unknown fields to catch typos in client requests,
+// which would otherwise come out as missing fields. The dataset and the log
+// are read with the plain entities, which ignore them
+#[derive(Deserialize)]
+#[serde(remote = "User", deny_unknown_fields)]
+struct StrictUser {
+    id:         UserId,
+    email:      String,
+    first_name: String,
+    last_name:  String,
+    gender:     Gender,
+    birth_date: Timestamp
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "Location", deny_unknown_fields)]
+struct StrictLocation {
+    id:       LocationId,
+    place:    Name,
+    country:  Name,
+    city:     Name,
+    distance: u32
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "Visit", deny_unknown_fields)]
+struct StrictVisit {
+    id:         VisitId,
+    location:   LocationId,
+    user:       UserId,
+    visited_at: Timestamp,
+    mark:       u8
+}
+
+#[derive(Deserialize)]
+struct NewUser(#[serde(with = "StrictUser")] User);
+
+#[derive(Deserialize)]
+struct NewLocation(#[serde(with = "StrictLocation")] Location);
+
+#[derive(Deserialize)]
+struct NewVisit(#[serde(with = "StrictVisit")] Visit);
+
+// A valid body is deserialized right away. Otherwise it's parsed once more
+// to tell which required field is missing or has a wrong type, other errors
+// like unknown fields or invalid values have no message
 #[inline]
-fn route_post_request(uri: Uri, body: &[u8]) -> Result<PostRequest, StatusCode> {
-    use serde_json;
+fn parse_entity<T: DeserializeOwned>(body: &[u8], fields: &[Field]) -> Result<T, ApiError> {
+    let error = match serde_json::from_slice(body) {
+        Ok(entity) => return Ok(entity),
+        Err(_) => ApiError::from(StatusCode::BadRequest)
+    };
 
+    let object = match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(object)) => object,
+        _ => return Err(error)
+    };
+    for &(name, missing, mistyped, field_type) in fields {
+        let value = object.get(name)
+            .ok_or(ApiError { code: StatusCode::BadRequest, message: missing })?;
+        let is_valid = match field_type {
+            FieldType::String => value.is_string(),
+            FieldType::U8 => value.as_u64().is_some_and(|value| value <= u8::MAX as u64),
+            FieldType::U32 => value.as_u64().is_some_and(|value| value <= u32::MAX as u64),
+            FieldType::I64 => value.is_i64()
+        };
+        if !is_valid {
+            return Err(ApiError { code: StatusCode::BadRequest, message: mistyped });
+        }
+    }
+    Err(error)
+}
+
+#[inline]
+fn route_post_request(uri: Uri, body: &[u8]) -> Result<PostRequest, ApiError> {
     let (entity, id) = {
         let path = uri.path();
         let mut iter = path.split('/').skip(1);
@@ -139,28 +656,54 @@ fn route_post_request(uri: Uri, body: &[u8]) -> Result<PostRequest, StatusCode>
     };
 
     let request = if id == "new" {
+        let request = match entity {
+            "users" => CreateEntity::User(parse_entity::<NewUser>(body, USER_FIELDS)?.0),
+            "locations" => CreateEntity::Location(parse_entity::<NewLocation>(body, LOCATION_FIELDS)?.0),
+            "visits" => CreateEntity::Visit(parse_entity::<NewVisit>(body, VISIT_FIELDS)?.0),
+            _ => return Err(StatusCode::BadRequest.into()),
+        };
+
+        PostRequest::CreateEntity(request)
+    } else if id == "bulk" {
         let request = match entity {
             "users" => {
-                let user = serde_json::from_slice(body)
+                #[derive(Deserialize)]
+                struct Users {
+                    users: Vec<User>
+                }
+
+                let Users { users } = serde_json::from_slice(body)
                     .map_err(|_| StatusCode::BadRequest)?;
-                CreateEntity::User(user)
+                CreateEntity::UserBatch(users)
             }
             "locations" => {
-                let location = serde_json::from_slice(body)
+                #[derive(Deserialize)]
+                struct Locations {
+                    locations: Vec<Location>
+                }
+
+                let Locations { locations } = serde_json::from_slice(body)
                     .map_err(|_| StatusCode::BadRequest)?;
-                CreateEntity::Location(location)
+                CreateEntity::LocationBatch(locations)
             }
             "visits" => {
-                let visit = serde_json::from_slice(body)
+                #[derive(Deserialize)]
+                struct Visits {
+                    visits: Vec<Visit>
+                }
+
+                let Visits { visits } = serde_json::from_slice(body)
                     .map_err(|_| StatusCode::BadRequest)?;
-                CreateEntity::Visit(visit)
+                CreateEntity::VisitBatch(visits)
             }
-            _ => return Err(StatusCode::BadRequest),
+            _ => return Err(StatusCode::BadRequest.into()),
         };
 
         PostRequest::CreateEntity(request)
     } else {
-        let id: u32 = id.parse().map_err(|_| StatusCode::NotFound)?;
+        // every field is optional, so '{}' is a valid update changing nothing.
+        // An empty body isn't JSON and is refused like any malformed one
+        let id = parse_id(id)?;
         let request = match entity {
             "users" => {
                 let user_update = serde_json::from_slice(body)
@@ -177,11 +720,495 @@ fn route_post_request(uri: Uri, body: &[u8]) -> Result<PostRequest, StatusCode>
                     .map_err(|_| StatusCode::BadRequest)?;
                 UpdateEntity::Visit(VisitId(id), visit_update)
             }
-            _ => return Err(StatusCode::BadRequest),
+            _ => return Err(StatusCode::BadRequest.into()),
         };
 
         PostRequest::UpdateEntity(request)
     };
 
     Ok(request)
-}
\ No newline at end of file
+}
+
+#[inline]
+fn route_delete_request(uri: Uri) -> Result<DeleteRequest, StatusCode> {
+    let (entity, id) = {
+        let path = uri.path();
+        let mut iter = path.split('/').skip(1);
+        let entity = iter.next().ok_or(StatusCode::NotFound)?;
+        let id = iter.next().ok_or(StatusCode::NotFound)?;
+        (entity, id)
+    };
+
+    let id = parse_id(id)?;
+    let entity = match entity {
+        "users" => DeleteEntity::User(UserId(id)),
+        "locations" => DeleteEntity::Location(LocationId(id)),
+        "visits" => DeleteEntity::Visit(VisitId(id)),
+        _ => return Err(StatusCode::BadRequest),
+    };
+
+    let mut cascade = false;
+    if let Some(query) = uri.query() {
+        for (name, value) in QueryParams::parse(query)? {
+            match &*name {
+                "cascade" => cascade = parse_flag(&value)?,
+                _ => return Err(StatusCode::BadRequest),
+            }
+        }
+    }
+
+    Ok(DeleteRequest { entity, cascade })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health() {
+        let request = route(Method::Get, "/health".parse().unwrap(), b"");
+        match request {
+            Ok(ApiRequest::Get(GetRequest::Health)) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+    }
+
+    #[test]
+    fn null_update_fields() {
+        let fields: [(&str, &[&str]); 3] = [
+            ("/users/1", &["email", "first_name", "last_name", "gender", "birth_date"]),
+            ("/locations/1", &["place", "country", "city", "distance"]),
+            ("/visits/1", &["location", "user", "visited_at", "mark"])
+        ];
+        for &(uri, fields) in &fields {
+            for field in fields {
+                let body = format!(r#"{{"{}": null}}"#, field);
+                let request = route(Method::Post, uri.parse().unwrap(), body.as_bytes());
+                assert_eq!(request.unwrap_err().code, StatusCode::BadRequest, "{} {}", uri, body);
+            }
+
+            match route(Method::Post, uri.parse().unwrap(), b"{}") {
+                Ok(ApiRequest::Post(PostRequest::UpdateEntity(_))) => {},
+                request => panic!("Unexpected request: {:?}", request)
+            }
+        }
+    }
+
+    #[test]
+    fn empty_update_body() {
+        for uri in &["/users/1", "/locations/1", "/visits/1"] {
+            let request = route(Method::Post, uri.parse().unwrap(), b"");
+            assert_eq!(request.unwrap_err().code, StatusCode::BadRequest, "{}", uri);
+        }
+
+        match route(Method::Post, "/users/1".parse().unwrap(), b"{}") {
+            Ok(ApiRequest::Post(PostRequest::UpdateEntity(UpdateEntity::User(UserId(1), update)))) => {
+                assert!(update.email.is_nothing() && update.first_name.is_nothing() && update.last_name.is_nothing()
+                        && update.gender.is_nothing() && update.birth_date.is_nothing());
+            }
+            request => panic!("Unexpected request: {:?}", request)
+        }
+
+        match route(Method::Post, "/visits/1".parse().unwrap(), br#"{"mark": 3}"#) {
+            Ok(ApiRequest::Post(PostRequest::UpdateEntity(UpdateEntity::Visit(VisitId(1), update)))) => {
+                assert!(matches!(update.mark, request::Optional::Something(3)));
+                assert!(update.location.is_nothing() && update.user.is_nothing() && update.visited_at.is_nothing());
+            }
+            request => panic!("Unexpected request: {:?}", request)
+        }
+    }
+
+    #[test]
+    fn empty_segments() {
+        for uri in &["/users/1/", "//users/1", "/users//1", "/users/1//"] {
+            match route(Method::Get, uri.parse().unwrap(), b"") {
+                Ok(ApiRequest::Get(GetRequest::GetEntity(GetEntity::User(UserId(1))))) => {},
+                request => panic!("Unexpected request for {}: {:?}", uri, request)
+            }
+        }
+        match route(Method::Get, "/users/1/visits/?limit=2".parse().unwrap(), b"") {
+            Ok(ApiRequest::Get(GetRequest::GetVisits(UserId(1), request::GetVisits { limit: Some(2), .. }))) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+        match route(Method::Delete, "/visits//1/".parse().unwrap(), b"") {
+            Ok(ApiRequest::Delete(DeleteRequest { entity: DeleteEntity::Visit(VisitId(1)), .. })) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+        assert!(matches!(route(Method::Options, "//".parse().unwrap(), b""), Ok(ApiRequest::Discovery)));
+    }
+
+    #[test]
+    fn fast_routes() {
+        let shapes = [
+            ("/users/1", Shape::User), ("/users/1/visits", Shape::UserVisits),
+            ("/locations/1", Shape::Location), ("/locations/1/visits", Shape::LocationVisits),
+            ("/locations/1/avg", Shape::LocationAverage), ("/visits/1", Shape::Visit)
+        ];
+        for &(path, shape) in &shapes {
+            assert_eq!(fast_shape(path.as_bytes()), Some((shape, 1)), "{}", path);
+        }
+        assert_eq!(fast_shape(b"/visits/4294967295"), Some((Shape::Visit, u32::MAX)));
+
+        // left to 'route_path'
+        let others = ["/users", "/users/", "/users/a", "/users/+1", "/users/%31", "/users/1x", "/users/1/avg",
+                      "/users/1/visits/2", "/users/4294967296", "/users/00000000001", "/locations/1/visitsx",
+                      "/locations/1/av", "/visits/1/visits", "/userz/1", "/health", "/export/users"];
+        for path in &others {
+            assert_eq!(fast_shape(path.as_bytes()), None, "{}", path);
+        }
+
+        // both agree, including the errors
+        let queries = ["", "?expand=visits", "?withVisits=count", "?fromDate=1&toMark=3", "?gender=m", "?limit=x", "?x=1"];
+        for path in shapes.iter().map(|&(path, _)| path).chain(others.iter().cloned()) {
+            for query in &queries {
+                let uri: Uri = format!("{}{}", path, query).parse().unwrap();
+                assert_eq!(format!("{:?}", route_get_request(uri.clone())), format!("{:?}", route_path(uri)), "{}{}", path, query);
+            }
+        }
+    }
+
+    #[test]
+    fn percent_encoded_id() {
+        match route(Method::Get, "/users/%31".parse().unwrap(), b"") {
+            Ok(ApiRequest::Get(GetRequest::GetEntity(GetEntity::User(UserId(1))))) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+        match route(Method::Delete, "/visits/%31%32".parse().unwrap(), b"") {
+            Ok(ApiRequest::Delete(DeleteRequest { entity: DeleteEntity::Visit(VisitId(12)), .. })) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+
+        for uri in &["/users/%2F1", "/users/1%2Fvisits", "/locations/%2f1/avg"] {
+            let request = route(Method::Get, uri.parse().unwrap(), b"");
+            assert_eq!(request.unwrap_err().code, StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn location_with_visit_count() {
+        let get = |uri: &str| route(Method::Get, uri.parse().unwrap(), b"");
+        match get("/locations/1?withVisits=count") {
+            Ok(ApiRequest::Get(GetRequest::GetEntity(GetEntity::LocationWithVisitCount(LocationId(1))))) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+        match get("/locations/1?query_id=1") {
+            Ok(ApiRequest::Get(GetRequest::GetEntity(GetEntity::Location(LocationId(1))))) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+        assert_eq!(get("/locations/1?withVisits=all").unwrap_err().code, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn user_with_visits() {
+        let get = |uri: &str| route(Method::Get, uri.parse().unwrap(), b"");
+        match get("/users/1?expand=visits") {
+            Ok(ApiRequest::Get(GetRequest::GetEntity(GetEntity::UserWithVisits(UserId(1), None)))) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+        match get("/users/1?limit=2&expand=visits") {
+            Ok(ApiRequest::Get(GetRequest::GetEntity(GetEntity::UserWithVisits(UserId(1), Some(2))))) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+        match get("/users/1?limit=2") {
+            Ok(ApiRequest::Get(GetRequest::GetEntity(GetEntity::User(UserId(1))))) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+        assert_eq!(get("/users/1?expand=locations").unwrap_err().code, StatusCode::BadRequest);
+        assert_eq!(get("/users/1?expand=visits&limit=-1").unwrap_err().code, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn visit_range() {
+        match route(Method::Get, "/visits?fromId=100&toId=200".parse().unwrap(), b"") {
+            Ok(ApiRequest::Get(GetRequest::GetVisitRange(VisitId(100), VisitId(200)))) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+
+        for uri in &["/visits", "/visits?fromId=1", "/visits?fromId=1&toId=a", "/visits?fromId=1&toId=2&limit=1"] {
+            let request = route(Method::Get, uri.parse().unwrap(), b"");
+            assert_eq!(request.unwrap_err().code, StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn verbose_stats() {
+        for &(uri, verbose) in &[("/stats", false), ("/stats?verbose=1", true), ("/stats?verbose=false", false)] {
+            match route(Method::Get, uri.parse().unwrap(), b"") {
+                Ok(ApiRequest::Get(GetRequest::Stats(v))) if v == verbose => {},
+                request => panic!("Unexpected request: {:?}", request)
+            }
+        }
+        for uri in &["/stats?verbose=2", "/stats?limit=1"] {
+            assert_eq!(route(Method::Get, uri.parse().unwrap(), b"").unwrap_err().code, StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn multi_get() {
+        match route(Method::Get, "/users?ids=1,2,3".parse().unwrap(), b"") {
+            Ok(ApiRequest::Get(GetRequest::GetEntities(EntityKind::Users, ref ids))) if *ids == [1, 2, 3] => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+        match route(Method::Get, "/locations?ids=5".parse().unwrap(), b"") {
+            Ok(ApiRequest::Get(GetRequest::GetEntities(EntityKind::Locations, ref ids))) if *ids == [5] => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+        match route(Method::Get, "/visits?ids=4%2C2".parse().unwrap(), b"") {
+            Ok(ApiRequest::Get(GetRequest::GetEntities(EntityKind::Visits, ref ids))) if *ids == [4, 2] => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+
+        for uri in &["/users", "/users?ids=", "/users?ids=1,,2", "/users?ids=1,a", "/locations?ids=1&limit=2",
+                     "/visits?ids=1&fromId=1&toId=2"] {
+            let request = route(Method::Get, uri.parse().unwrap(), b"");
+            assert_eq!(request.unwrap_err().code, StatusCode::BadRequest, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn export() {
+        for &(uri, kind) in &[("/export/users", EntityKind::Users), ("/export/locations", EntityKind::Locations), 
+                              ("/export/visits/", EntityKind::Visits)] {
+            match route(Method::Get, uri.parse().unwrap(), b"") {
+                Ok(ApiRequest::Get(GetRequest::Export(exported))) => assert_eq!(exported, kind),
+                request => panic!("Unexpected request: {:?}", request)
+            }
+        }
+        let request = route(Method::Get, "/export/marks".parse().unwrap(), b"");
+        assert_eq!(request.unwrap_err().code, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn visits_limit_parameter() {
+        let parameters = parse_visits_parameters("limit=2").unwrap();
+        assert_eq!(parameters.limit, Some(2));
+        assert_eq!(parse_visits_parameters("limit=abc").unwrap_err(), StatusCode::BadRequest);
+        assert_eq!(parse_visits_parameters("limit=-1").unwrap_err(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn visits_city_parameter() {
+        let parameters = parse_visits_parameters("city=%D0%9D%D0%BE%D0%B2%D1%8B%D0%B9%20%D0%A3%D1%80%D0%B5%D0%BD%D0%B3%D0%BE%D0%B9").unwrap();
+        assert_eq!(parameters.city, Some("Новый Уренгой".to_string()));
+        let parameters = parse_visits_parameters("city=New+York").unwrap();
+        assert_eq!(parameters.city, Some("New York".to_string()));
+        assert_eq!(parse_visits_parameters("city=%FF").unwrap_err(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn query_params() {
+        let parameters = parse_visits_parameters("country=a=b&city=%3D").unwrap();
+        assert_eq!(parameters.country, Some("a=b".to_string()));
+        assert_eq!(parameters.city, Some("=".to_string()));
+
+        assert_eq!(parse_visits_parameters("country=").unwrap().country, Some("".to_string()));
+        assert_eq!(parse_visits_parameters("toDistance=").unwrap_err(), StatusCode::BadRequest);
+        assert_eq!(parse_visits_parameters("fromDistance=-1").unwrap_err(), StatusCode::BadRequest);
+        assert_eq!(parse_visits_parameters("country").unwrap_err(), StatusCode::BadRequest);
+
+        for query in &["country=a&country=b", "limit=1&limit=1", "fromDate=1&from%44ate=2"] {
+            assert_eq!(parse_visits_parameters(query).unwrap_err(), StatusCode::BadRequest);
+        }
+        assert_eq!(parse_alr_parameters("gender=m&gender=f").unwrap_err(), StatusCode::BadRequest);
+        let delete = route(Method::Delete, "/users/1?cascade=1&cascade=0".parse().unwrap(), b"");
+        assert_eq!(delete.unwrap_err().code, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn visits_count_parameter() {
+        assert!(parse_visits_parameters("count=1").unwrap().count);
+        assert!(parse_visits_parameters("count=true").unwrap().count);
+        assert!(!parse_visits_parameters("count=0").unwrap().count);
+        assert!(!parse_visits_parameters("limit=1").unwrap().count);
+        assert_eq!(parse_visits_parameters("count=yes").unwrap_err(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn inclusive_date_parameters() {
+        let parameters = parse_visits_parameters("fromDate=1&fromDateInclusive=1").unwrap();
+        assert!(parameters.from_date_inclusive && !parameters.to_date_inclusive);
+        let parameters = parse_alr_parameters("toDateInclusive=true").unwrap();
+        assert!(!parameters.from_date_inclusive && parameters.to_date_inclusive);
+        assert_eq!(parse_alr_parameters("toDateInclusive=2").unwrap_err(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn avg_gender_parameter() {
+        use data::Gender;
+        assert_eq!(parse_alr_parameters("gender=m").unwrap().gender, Some(Gender::Male));
+        assert_eq!(parse_alr_parameters("gender=f").unwrap().gender, Some(Gender::Female));
+        for query in &["gender=male", "gender=M", "gender=", "gender=x"] {
+            assert_eq!(parse_alr_parameters(query).unwrap_err(), StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn avg_round_parameter() {
+        assert_eq!(parse_alr_parameters("round=0").unwrap().round, Some(0));
+        assert_eq!(parse_alr_parameters("round=5").unwrap().round, Some(5));
+        for query in &["round=6", "round=-1", "round=two"] {
+            assert_eq!(parse_alr_parameters(query).unwrap_err(), StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn location_visits() {
+        match route(Method::Get, "/locations/2/visits?fromDate=10&toMark=3&toDateInclusive=1".parse().unwrap(), b"") {
+            Ok(ApiRequest::Get(GetRequest::GetLocationVisits(LocationId(2), parameters))) => {
+                assert_eq!((parameters.from_date, parameters.to_date), (Some(10), None));
+                assert_eq!((parameters.from_mark, parameters.to_mark), (None, Some(3)));
+                assert!(!parameters.from_date_inclusive && parameters.to_date_inclusive);
+            },
+            request => panic!("Unexpected request: {:?}", request)
+        }
+        assert!(matches!(route(Method::Get, "/locations/2/visits".parse().unwrap(), b""),
+                         Ok(ApiRequest::Get(GetRequest::GetLocationVisits(LocationId(2), _)))));
+        assert!(matches!(route(Method::Get, "/users/2/visits".parse().unwrap(), b""),
+                         Ok(ApiRequest::Get(GetRequest::GetVisits(UserId(2), _)))));
+
+        // filters of users' visits that don't apply to a location
+        for query in &["country=Russia", "limit=1", "fromMark=6", "fromDate=x"] {
+            let uri = format!("/locations/2/visits?{}", query);
+            assert_eq!(route(Method::Get, uri.parse().unwrap(), b"").unwrap_err().code, StatusCode::BadRequest, "{}", query);
+        }
+    }
+
+    #[test]
+    fn avg_with_count_parameter() {
+        assert!(!parse_alr_parameters("round=1").unwrap().with_count);
+        assert!(parse_alr_parameters("withCount=1").unwrap().with_count);
+        assert!(!parse_alr_parameters("withCount=0").unwrap().with_count);
+        assert_eq!(parse_alr_parameters("withCount=yes").unwrap_err(), StatusCode::BadRequest);
+        assert_eq!(parse_alr_parameters("withcount=1").unwrap_err(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn visits_mark_parameters() {
+        let parameters = parse_visits_parameters("fromMark=4&toMark=5").unwrap();
+        assert_eq!((parameters.from_mark, parameters.to_mark), (Some(4), Some(5)));
+        assert_eq!(parse_visits_parameters("fromMark=0").unwrap().from_mark, Some(0));
+        for query in &["fromMark=6", "toMark=-1", "toMark=256", "fromMark=high"] {
+            assert_eq!(parse_visits_parameters(query).unwrap_err(), StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn visits_after_parameter() {
+        match route(Method::Get, "/users/1/visits?after=-100:20&limit=2".parse().unwrap(), b"") {
+            Ok(ApiRequest::Get(GetRequest::GetVisits(UserId(1), ref parameters))) 
+                if parameters.after == Some((-100, VisitId(20))) && parameters.limit == Some(2) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+
+        for uri in &["/users/1/visits?after=100", "/users/1/visits?after=100:", "/users/1/visits?after=:1",
+                     "/users/1/visits?after=a:1", "/users/1/visits?after=1:2:3"] {
+            let request = route(Method::Get, uri.parse().unwrap(), b"");
+            assert_eq!(request.unwrap_err().code, StatusCode::BadRequest, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn visits_order_parameter() {
+        use request::Order;
+        assert_eq!(parse_visits_parameters("order=asc").unwrap().order, Order::Ascending);
+        assert_eq!(parse_visits_parameters("order=desc").unwrap().order, Order::Descending);
+        assert_eq!(parse_visits_parameters("order=random").unwrap_err(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn bulk_create() {
+        let body = br#"{"visits":[{"id":1,"location":2,"user":3,"visited_at":4,"mark":5}]}"#;
+        match route(Method::Post, "/visits/bulk".parse().unwrap(), body) {
+            Ok(ApiRequest::Post(PostRequest::CreateEntity(CreateEntity::VisitBatch(ref visits))))
+                if visits.len() == 1 && visits[0].id == VisitId(1) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+
+        let request = route(Method::Post, "/visits/bulk".parse().unwrap(), br#"{"users":[]}"#);
+        assert_eq!(request.unwrap_err().code, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn unknown_fields_on_create() {
+        let create = |path: &str, body: &str| route(Method::Post, path.parse().unwrap(), body.as_bytes());
+
+        let user = r#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0"#;
+        assert!(create("/users/new", &format!("{}}}", user)).is_ok());
+        assert_eq!(create("/users/new", &format!(r#"{},"age":30}}"#, user)).unwrap_err().code, 
+                   StatusCode::BadRequest);
+
+        let typo = r#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birthDate":0}"#;
+        assert_eq!(create("/users/new", typo).unwrap_err().code, StatusCode::BadRequest);
+
+        let visit = r#"{"id":1,"location":2,"user":3,"visited_at":4,"mark":5,"rating":5}"#;
+        assert_eq!(create("/visits/new", visit).unwrap_err().code, StatusCode::BadRequest);
+
+        let location = r#"{"id":1,"place":"a","country":"b","city":"c","distance":1"#;
+        assert!(create("/locations/new", &format!("{}}}", location)).is_ok());
+        assert_eq!(create("/locations/new", &format!(r#"{},"rating":5}}"#, location)).unwrap_err().code,
+                   StatusCode::BadRequest);
+
+        // updates are partial and keep ignoring what they don't know
+        assert!(create("/users/1", r#"{"email":"a@b.c","age":30}"#).is_ok());
+    }
+
+    #[test]
+    fn create_field_errors() {
+        let create = |path: &str, body: &str| route(Method::Post, path.parse().unwrap(), body.as_bytes());
+        let message = |path: &str, body: &str| create(path, body).unwrap_err().message;
+
+        assert_eq!(message("/visits/new", r#"{"id":1,"location":2,"user":3,"visited_at":4}"#), "missing field: mark");
+        assert_eq!(message("/visits/new", r#"{"id":1,"location":2,"user":3,"visited_at":4,"mark":"5"}"#), 
+                   "invalid type: mark");
+        assert_eq!(message("/visits/new", r#"{"id":1,"location":2,"user":3,"visited_at":4,"mark":256}"#), 
+                   "invalid type: mark");
+        assert_eq!(message("/visits/new", r#"{"id":-1,"location":2,"user":3,"visited_at":4,"mark":5}"#), 
+                   "invalid type: id");
+        assert_eq!(message("/users/new", r#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m"}"#), 
+                   "missing field: birth_date");
+        assert_eq!(message("/users/new", r#"{"id":1,"email":null,"first_name":"a","last_name":"b","gender":"m","birth_date":0}"#), 
+                   "invalid type: email");
+        assert_eq!(message("/locations/new", r#"{"id":1,"place":"a","country":"b","distance":1}"#), "missing field: city");
+        assert_eq!(message("/locations/new", r#"{"id":1,"place":"a","country":"b","city":"c","distance":1.5}"#), 
+                   "invalid type: distance");
+
+        // the fields are fine, but the values or the rest of the body aren't
+        assert_eq!(message("/users/new", r#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"x","birth_date":0}"#), "");
+        assert_eq!(message("/visits/new", r#"{"id":1,"location":2,"user":3,"visited_at":4,"mark":5,"rating":5}"#), "");
+        assert_eq!(message("/visits/new", "[]"), "");
+        assert_eq!(create("/visits/new", "{").unwrap_err(), ApiError::from(StatusCode::BadRequest));
+    }
+
+    #[test]
+    fn preflight() {
+        for path in &["/users/1", "/users/abc", "/users"] {
+            match route(Method::Options, path.parse().unwrap(), b"") {
+                Ok(ApiRequest::Preflight) => {},
+                request => panic!("Unexpected request: {:?}", request)
+            }
+        }
+    }
+
+    #[test]
+    fn discovery() {
+        match route(Method::Options, "/".parse().unwrap(), b"") {
+            Ok(ApiRequest::Discovery) => {},
+            request => panic!("Unexpected request: {:?}", request)
+        }
+
+        let body = String::from_utf8(super::discovery().to_vec()).unwrap();
+        for path in &["/users/{id}", "/locations/{id}", "/visits/{id}", "/users/{id}/visits",
+                      "/locations/{id}/avg", "/{entity}/new", "/export/{entity}"] {
+            assert!(body.contains(&format!(r#""path":"{}""#, path)), "{} is missing", path);
+        }
+        assert!(body.contains(r#""parameters":["fromDate","toDate","fromDateInclusive","toDateInclusive","fromAge","toAge","gender","round","withCount"]"#));
+    }
+
+    #[test]
+    fn unsupported_methods() {
+        for method in &[Method::Put, Method::Patch] {
+            let request = route(method.clone(), "/users/1".parse().unwrap(), b"");
+            assert_eq!(request.unwrap_err().code, StatusCode::MethodNotAllowed);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+use serde_json;
+
+use request::{PostRequest, DeleteRequest};
+
+// Mutation as it is stored in the log, one JSON object per line
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Record {
+    Post(PostRequest),
+    Delete(DeleteRequest)
+}
+
+// Append-only log of mutations. Records are written before they are
+// applied, the lock is held until the mutation is done, so the order in
+// the log is the order in which the database has seen them. A record is
+// written with its newline in one call, a line without one is a record
+// torn by a crash and is not replayed
+pub struct Wal {
+    file: Mutex<File>
+}
+
+impl Wal {
+    // the torn record is cut off, the next one would be appended to it
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Wal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let complete = complete_len(&file)?;
+        if complete < file.metadata()?.len() {
+            file.set_len(complete)?;
+        }
+
+        Ok(Wal { file: Mutex::new(file) })
+    }
+
+    // missing log is the same as an empty one
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<Record>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e)
+        };
+
+        let mut records = Vec::new();
+        let mut reader = BufReader::new(file);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            if line.len() == 1 {
+                continue;
+            }
+
+            let record = serde_json::from_slice(&line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            records.push(record);
+        }
+
+        Ok(records)
+    }
+
+    pub fn append<F, R>(&self, record: Record, apply: F) -> io::Result<R>
+    where
+        F: FnOnce(Record) -> R
+    {
+        let mut line = serde_json::to_vec(&record).map_err(io::Error::other)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        file.write_all(&line)?;
+
+        Ok(apply(record))
+    }
+}
+
+// Length of the log up to the end of its last complete line
+fn complete_len(file: &File) -> io::Result<u64> {
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    let mut complete = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 || line.last() != Some(&b'\n') {
+            return Ok(complete);
+        }
+        complete += line.len() as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use hyper::StatusCode;
+    use data::*;
+    use request::*;
+    use database::Database;
+    use api::Api;
+    use super::Record;
+
+    fn read_user(api: &Api, id: UserId) -> Result<String, StatusCode> {
+        let request = GetRequest::GetEntity(GetEntity::User(id));
+        api.do_get(request).map(|body| String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn replay_after_restart() {
+        let path = env::temp_dir().join(format!("highloadcup-wal-{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+
+        {
+            let api = Api::with_wal(Database::new(4), &path).unwrap();
+            let user = User {
+                id: UserId(1),
+                email: "user@mail.ru".to_string(),
+                first_name: "Данила".to_string(),
+                last_name: "Стамленский".to_string(),
+                gender: Gender::Male,
+                birth_date: 345081600
+            };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::User(user))).unwrap();
+
+            let update = UserUpdate {
+                email: Optional::Something("new@mail.ru".to_string()),
+                first_name: Optional::Nothing,
+                last_name: Optional::Nothing,
+                gender: Optional::Nothing,
+                birth_date: Optional::Nothing
+            };
+            api.do_post(PostRequest::UpdateEntity(UpdateEntity::User(UserId(1), update))).unwrap();
+
+            let user = User {
+                id: UserId(2),
+                email: "other@mail.ru".to_string(),
+                first_name: "Иван".to_string(),
+                last_name: "Петров".to_string(),
+                gender: Gender::Male,
+                birth_date: 0
+            };
+            api.do_post(PostRequest::CreateEntity(CreateEntity::User(user))).unwrap();
+            let delete = DeleteRequest { entity: DeleteEntity::User(UserId(2)), cascade: false };
+            api.do_delete(delete).unwrap();
+        }
+
+        let api = Api::with_wal(Database::new(4), &path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let user = read_user(&api, UserId(1)).unwrap();
+        assert!(user.contains("\"email\":\"new@mail.ru\""));
+        assert!(user.contains("\"first_name\":\"Данила\""));
+        assert_eq!(read_user(&api, UserId(2)), Err(StatusCode::NotFound));
+    }
+
+    #[test]
+    fn replay_with_torn_record() {
+        use std::io::Write;
+
+        let path = env::temp_dir().join(format!("highloadcup-wal-torn-{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+        let user = |id, email: &str| User {
+            id: UserId(id),
+            email: email.to_string(),
+            first_name: "Иван".to_string(),
+            last_name: "Петров".to_string(),
+            gender: Gender::Male,
+            birth_date: 0
+        };
+
+        {
+            let api = Api::with_wal(Database::new(4), &path).unwrap();
+            api.do_post(PostRequest::CreateEntity(CreateEntity::User(user(1, "first@mail.ru")))).unwrap();
+        }
+        // crashed in the middle of the second record
+        let record = Record::Post(PostRequest::CreateEntity(CreateEntity::User(user(2, "second@mail.ru"))));
+        let record = serde_json::to_vec(&record).unwrap();
+        fs::OpenOptions::new().append(true).open(&path).unwrap()
+            .write_all(&record[..record.len() - 10]).unwrap();
+
+        {
+            let api = Api::with_wal(Database::new(4), &path).unwrap();
+            assert!(read_user(&api, UserId(1)).is_ok());
+            assert_eq!(read_user(&api, UserId(2)), Err(StatusCode::NotFound));
+            api.do_post(PostRequest::CreateEntity(CreateEntity::User(user(3, "third@mail.ru")))).unwrap();
+        }
+
+        let api = Api::with_wal(Database::new(4), &path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(read_user(&api, UserId(1)).is_ok());
+        assert!(read_user(&api, UserId(3)).unwrap().contains("\"email\":\"third@mail.ru\""));
+    }
+}
@@ -0,0 +1,89 @@
+// Helpers for tests running the server binary, not every test uses all of them
+#![allow(dead_code)]
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Lines, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{self, Child, ChildStderr, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use zip::write::{ZipWriter, FileOptions};
+
+pub struct Server {
+    pub process: Child,
+    pub address: SocketAddr,
+    // kept open until the server exits, it still writes on shutdown
+    pub stderr: Lines<BufReader<ChildStderr>>,
+    directory: PathBuf
+}
+
+impl Server {
+    // workers bind after 'Server started' is logged, so it may take a moment
+    pub fn connect(&self) -> TcpStream {
+        let started = Instant::now();
+        loop {
+            match TcpStream::connect(self.address) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                    return stream;
+                },
+                Err(_) if started.elapsed() < Duration::from_secs(5) => {
+                    thread::sleep(Duration::from_millis(20));
+                },
+                Err(e) => panic!("Unable to connect: {}", e)
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.directory);
+    }
+}
+
+fn write_dataset(path: &::std::path::Path) {
+    let mut zip = ZipWriter::new(File::create(path).unwrap());
+    for &(name, content) in &[("users_1.json", r#"{"users":[]}"#),
+                              ("locations_1.json", r#"{"locations":[]}"#),
+                              ("visits_1.json", r#"{"visits":[]}"#)] {
+        zip.start_file(name, FileOptions::default()).unwrap();
+        zip.write_all(content.as_bytes()).unwrap();
+    }
+    zip.finish().unwrap();
+}
+
+// starts the server with an empty dataset and waits until it accepts connections
+pub fn start_server(name: &str, env: &[(&str, &str)]) -> Server {
+    let directory = env::temp_dir().join(format!("highloadcup-{}-{}", name, process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let data_file = directory.join("data.zip");
+    write_dataset(&data_file);
+
+    let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_highloadcup"));
+    command.current_dir(&directory)
+        .env("BIND_ADDR", address.to_string())
+        .env("DATA_FILE", &data_file)
+        .env("NUM_THREADS", "1")
+        .env("LOG_LEVEL", "info")
+        .stderr(Stdio::piped());
+    for &(key, value) in env {
+        command.env(key, value);
+    }
+
+    let mut process = command.spawn().unwrap();
+    let mut stderr = BufReader::new(process.stderr.take().unwrap()).lines();
+    for line in stderr.by_ref() {
+        if line.unwrap().contains("Server started") {
+            break;
+        }
+    }
+
+    Server { process, address, stderr, directory }
+}
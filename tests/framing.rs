@@ -0,0 +1,48 @@
+extern crate zip;
+
+mod common;
+
+use std::io::{Read, Write};
+
+const USER: &str = r#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}"#;
+
+fn read_all(stream: &mut impl Read) -> String {
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+#[test]
+fn chunked_body() {
+    let server = common::start_server("chunked", &[("KEEP_ALIVE", "false")]);
+    let (first, second) = USER.split_at(20);
+    let request = format!("POST /users/new HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+                           {:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n", 
+                          first.len(), first, second.len(), second);
+    let mut stream = server.connect();
+    stream.write_all(request.as_bytes()).unwrap();
+    let response = read_all(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let mut stream = server.connect();
+    stream.write_all(b"GET /users/1 HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(read_all(&mut stream).ends_with(USER));
+}
+
+#[test]
+fn conflicting_framing() {
+    let server = common::start_server("conflicting-framing", &[]);
+    // chunked the body is empty and a smuggled request follows, by the 
+    // length it's all body
+    let smuggled = "GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let body = format!("0\r\n\r\n{}", smuggled);
+    let request = format!("POST /users/new HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\
+                           Transfer-Encoding: chunked\r\n\r\n{}", body.len(), body);
+    let mut stream = server.connect();
+    stream.write_all(request.as_bytes()).unwrap();
+
+    // a single response and the connection is closed
+    let response = read_all(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    assert_eq!(response.matches("HTTP/1.1").count(), 1, "{}", response);
+}
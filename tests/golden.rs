@@ -0,0 +1,64 @@
+// Responses compared byte for byte with the files in 'tests/golden'. The
+// field order follows the declaration order of the structs in 'data', and
+// consumers rely on it, so a change here is a change of the format
+extern crate highloadcup;
+
+use std::fs;
+use std::path::Path;
+
+use highloadcup::api::Api;
+use highloadcup::data::*;
+use highloadcup::database::Database;
+use highloadcup::request::{GetRequest, GetEntity, PostRequest, CreateEntity};
+
+fn golden(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name);
+    let golden = fs::read_to_string(&path).unwrap_or_else(|e| panic!("Unable to read {}: {}", path.display(), e));
+    golden.trim_end().to_string()
+}
+
+fn api() -> Api {
+    let api = Api::new(Database::default());
+    let entities = vec![
+        CreateEntity::User(User {
+            id: UserId(1),
+            email: "robosen@icloud.com".to_string(),
+            first_name: "Данила".to_string(),
+            last_name: "Стамленский".to_string(),
+            gender: Gender::Male,
+            birth_date: -1720915200
+        }),
+        CreateEntity::Location(Location {
+            id: LocationId(2),
+            place: "Набережная".into(),
+            country: "Аргентина".into(),
+            city: "Москва".into(),
+            distance: 31
+        }),
+        CreateEntity::Visit(Visit { id: VisitId(3), location: LocationId(2), user: UserId(1), visited_at: 1223268286, mark: 5 }),
+        CreateEntity::Visit(Visit { id: VisitId(4), location: LocationId(2), user: UserId(1), visited_at: 958656902, mark: 2 })
+    ];
+    for entity in entities {
+        api.do_post(PostRequest::CreateEntity(entity)).unwrap();
+    }
+    api
+}
+
+fn get(api: &Api, request: GetRequest) -> String {
+    String::from_utf8(api.do_get(request).unwrap().to_vec()).unwrap()
+}
+
+#[test]
+fn entities() {
+    let api = api();
+    assert_eq!(get(&api, GetRequest::GetEntity(GetEntity::User(UserId(1)))), golden("user.json"));
+    assert_eq!(get(&api, GetRequest::GetEntity(GetEntity::Location(LocationId(2)))), golden("location.json"));
+    assert_eq!(get(&api, GetRequest::GetEntity(GetEntity::Visit(VisitId(3)))), golden("visit.json"));
+}
+
+#[test]
+fn queries() {
+    let api = api();
+    assert_eq!(get(&api, GetRequest::GetVisits(UserId(1), Default::default())), golden("visits.json"));
+    assert_eq!(get(&api, GetRequest::GetAverageLocationRating(LocationId(2), Default::default())), golden("avg.json"));
+}
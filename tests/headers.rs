@@ -0,0 +1,31 @@
+extern crate hyper;
+extern crate zip;
+
+mod common;
+
+use std::io::{Read, Write};
+
+use hyper::header::HttpDate;
+
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    let prefix = format!("{}: ", name);
+    response.lines()
+        .take_while(|line| !line.is_empty())
+        .find(|line| line.starts_with(&prefix))
+        .map(|line| &line[prefix.len()..])
+}
+
+#[test]
+fn date_and_server() {
+    let server = common::start_server("headers", &[("KEEP_ALIVE", "false")]);
+    for request in &["GET /health", "GET /users/1", "POST /users/1"] {
+        let mut stream = server.connect();
+        write!(stream, "{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n", request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let date = header(&response, "Date").unwrap_or_else(|| panic!("no Date: {}", response));
+        assert!(date.parse::<HttpDate>().is_ok(), "{}", date);
+        assert_eq!(header(&response, "Server"), Some(concat!("highloadcup/", env!("CARGO_PKG_VERSION"))));
+    }
+}
@@ -0,0 +1,36 @@
+#![feature(test)]
+
+extern crate test;
+extern crate highloadcup;
+
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use test::Bencher;
+
+use highloadcup::data::UserId;
+use highloadcup::database::IdMap;
+
+const COUNT: u32 = 300000;
+
+fn insert_and_lookup<S: BuildHasher>(map: &mut HashMap<UserId, u32, S>) -> u64 {
+    for id in 0..COUNT {
+        map.insert(UserId(id), id);
+    }
+
+    let mut sum = 0u64;
+    for id in 0..COUNT {
+        sum += map[&UserId(id)] as u64;
+    }
+    sum
+}
+
+#[bench]
+fn default_hasher(b: &mut Bencher) {
+    b.iter(|| insert_and_lookup(&mut HashMap::new()));
+}
+
+#[bench]
+fn id_hasher(b: &mut Bencher) {
+    b.iter(|| insert_and_lookup(&mut IdMap::default()));
+}
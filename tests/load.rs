@@ -0,0 +1,296 @@
+#![feature(test)]
+
+extern crate test;
+extern crate zip;
+extern crate highloadcup;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process;
+
+use test::Bencher;
+use zip::write::{ZipWriter, FileOptions};
+
+use highloadcup::data::{UserId, LocationId, VisitId};
+use highloadcup::database::{Database, CheckReport};
+
+const FILES: u32 = 4;
+const VISITS_PER_FILE: u32 = 5000;
+const USERS: u32 = 2000;
+const LOCATIONS: u32 = 1000;
+
+// users and locations come last to check that indexing waits for all members
+fn write_dataset(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("highloadcup-{}-{}.zip", name, process::id()));
+    let mut zip = ZipWriter::new(File::create(&path).unwrap());
+
+    for file in 0..FILES {
+        zip.start_file(format!("visits_{}.json", file + 1), FileOptions::default()).unwrap();
+        let visits: Vec<String> = (0..VISITS_PER_FILE)
+            .map(|i| file * VISITS_PER_FILE + i + 1)
+            .map(|id| format!(r#"{{"id":{},"location":{},"user":{},"visited_at":{},"mark":{}}}"#, 
+                              id, id % LOCATIONS + 1, id % USERS + 1, 1000000000 + id, id % 6))
+            .collect();
+        write!(zip, r#"{{"visits":[{}]}}"#, visits.join(",")).unwrap();
+    }
+
+    zip.start_file("users_1.json", FileOptions::default()).unwrap();
+    let users: Vec<String> = (1..USERS + 1)
+        .map(|id| format!(r#"{{"id":{},"email":"user{}@mail.ru","first_name":"Иван","last_name":"Петров","gender":"m","birth_date":{}}}"#, 
+                          id, id, id * 1000))
+        .collect();
+    write!(zip, r#"{{"users":[{}]}}"#, users.join(",")).unwrap();
+
+    zip.start_file("locations_1.json", FileOptions::default()).unwrap();
+    let locations: Vec<String> = (1..LOCATIONS + 1)
+        .map(|id| format!(r#"{{"id":{},"place":"Набережная","country":"Страна {}","city":"Город","distance":{}}}"#, 
+                          id, id % 50, id % 100))
+        .collect();
+    write!(zip, r#"{{"locations":[{}]}}"#, locations.join(",")).unwrap();
+
+    zip.finish().unwrap();
+    path
+}
+
+#[test]
+fn parallel_load_matches_sequential() {
+    let path = write_dataset("load");
+    let sequential = Database::from_file(path.to_str().unwrap(), 1, false).unwrap();
+    let parallel = Database::from_file(path.to_str().unwrap(), 4, false).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(parallel.users.len(), USERS as usize);
+    assert_eq!(parallel.locations.len(), LOCATIONS as usize);
+    assert_eq!(parallel.visits.len(), (FILES * VISITS_PER_FILE) as usize);
+
+    for id in 1..USERS + 1 {
+        let id = UserId(id);
+        assert_eq!(parallel.users.read(&id).get(&id), sequential.users.read(&id).get(&id));
+    }
+    for id in 1..LOCATIONS + 1 {
+        let id = LocationId(id);
+        assert_eq!(parallel.locations.read(&id).get(&id), sequential.locations.read(&id).get(&id));
+    }
+    for id in 1..FILES * VISITS_PER_FILE + 1 {
+        let id = VisitId(id);
+        assert_eq!(parallel.visits.read(&id).get(&id), sequential.visits.read(&id).get(&id));
+    }
+
+    let parallel = parallel.index.read().unwrap();
+    let sequential = sequential.index.read().unwrap();
+    assert_eq!(parallel.visits_by_user, sequential.visits_by_user);
+    assert_eq!(parallel.visits_by_user_country, sequential.visits_by_user_country);
+}
+
+#[test]
+fn location_names_are_shared() {
+    let path = write_dataset("names");
+    let database = Database::from_file(path.to_str().unwrap(), 4, false).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let locations = database.locations.read_all();
+    let first = locations.get(&LocationId(1)).unwrap();
+    for location in locations.values() {
+        assert!(location.place.ptr_eq(&first.place));
+        assert!(location.city.ptr_eq(&first.city));
+        assert_eq!(location.country.ptr_eq(&first.country), location.country == first.country);
+    }
+    // a place, a city and 50 countries
+    assert_eq!(database.names.lock().unwrap().len(), 52);
+}
+
+#[test]
+fn duplicates_are_resolved_in_archive_order() {
+    let path = env::temp_dir().join(format!("highloadcup-duplicates-{}.zip", process::id()));
+    {
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        for &(name, mark) in &[("visits_1.json", 1), ("visits_2.json", 2), ("visits_3.json", 3)] {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            write!(zip, r#"{{"visits":[{{"id":1,"location":1,"user":1,"visited_at":0,"mark":{}}}]}}"#, mark).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    for _ in 0..10 {
+        let database = Database::from_file(path.to_str().unwrap(), 3, false).unwrap();
+        let visits = database.visits.read(&VisitId(1));
+        assert_eq!(visits.get(&VisitId(1)).unwrap().mark, 3);
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn dangling_visits() {
+    let path = env::temp_dir().join(format!("highloadcup-dangling-{}.zip", process::id()));
+    {
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let files: [(&str, &[u8]); 3] = [
+            ("visits_1.json", br#"{"visits":[
+                {"id":1,"location":1,"user":1,"visited_at":0,"mark":5},
+                {"id":2,"location":1,"user":2,"visited_at":0,"mark":4},
+                {"id":3,"location":2,"user":1,"visited_at":0,"mark":3}]}"#),
+            ("users_1.json", br#"{"users":[
+                {"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}]}"#),
+            ("locations_1.json", br#"{"locations":[
+                {"id":1,"place":"a","country":"b","city":"c","distance":1}]}"#),
+        ];
+        for &(name, json) in &files {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(json).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    let lenient = Database::from_file(path.to_str().unwrap(), 2, false).unwrap();
+    assert_eq!(lenient.visits.len(), 3);
+
+    let strict = Database::from_file(path.to_str().unwrap(), 2, true).unwrap();
+    assert_eq!(strict.visits.len(), 1);
+    assert!(strict.visits.contains_key(&VisitId(1)));
+    let index = strict.index.read().unwrap();
+    assert_eq!(index.visits_by_user[&UserId(1)].len(), 1);
+    assert!(!index.visits_by_user.contains_key(&UserId(2)));
+    assert!(!index.visits_by_location.contains_key(&LocationId(2)));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn check_corrupt_dataset() {
+    let path = env::temp_dir().join(format!("highloadcup-check-{}.zip", process::id()));
+    {
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let files: [(&str, &[u8]); 4] = [
+            ("visits_1.json", br#"{"visits":[
+                {"id":1,"location":1,"user":1,"visited_at":0,"mark":5},
+                {"id":2,"location":1,"user":2,"visited_at":0,"mark":4},
+                {"id":3,"location":2,"user":1,"visited_at":0,"mark":9}]}"#),
+            ("visits_2.json", br#"{"visits":[
+                {"id":1,"location":1,"user":1,"visited_at":0,"mark":3}]}"#),
+            ("users_1.json", br#"{"users":[
+                {"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}]}"#),
+            ("locations_1.json", br#"{"locations":[
+                {"id":1,"place":"a","country":"b","city":"c","distance":1}]}"#),
+        ];
+        for &(name, json) in &files {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(json).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    for shards in 1..3 {
+        let report = Database::check_file(path.to_str().unwrap(), shards).unwrap();
+        assert_eq!(report, CheckReport {
+            users: 1,
+            locations: 1,
+            visits: 3,
+            duplicate_ids: 1,
+            unknown_users: 1,
+            unknown_locations: 1,
+            invalid_marks: 1
+        });
+        assert!(!report.is_clean());
+    }
+    fs::remove_file(&path).unwrap();
+
+    let path = write_dataset("check-clean");
+    let report = Database::check_file(path.to_str().unwrap(), 4).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(report.is_clean(), "{}", report);
+    assert_eq!(report.visits, (FILES * VISITS_PER_FILE) as usize);
+}
+
+#[test]
+fn unknown_fields_are_ignored() {
+    let path = env::temp_dir().join(format!("highloadcup-unknown-{}.zip", process::id()));
+    {
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let files: [(&str, &[u8]); 3] = [
+            ("visits_1.json", br#"{"visits":[
+                {"id":1,"location":1,"user":1,"visited_at":0,"mark":5,"rating":5}]}"#),
+            ("users_1.json", br#"{"users":[
+                {"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0,"age":30}]}"#),
+            ("locations_1.json", br#"{"locations":[
+                {"id":1,"place":"a","country":"b","city":"c","distance":1,"rating":{"avg":5}}]}"#),
+        ];
+        for &(name, json) in &files {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(json).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    for shards in 1..3 {
+        let database = Database::from_file(path.to_str().unwrap(), shards, true).unwrap();
+        assert_eq!(database.users.len(), 1);
+        assert_eq!(database.locations.len(), 1);
+        assert_eq!(database.visits.len(), 1);
+
+        let report = Database::check_file(path.to_str().unwrap(), shards).unwrap();
+        assert!(report.is_clean(), "{}", report);
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn corrupt_archive() {
+    let path = write_dataset("corrupt");
+    let mut bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // without the central directory at the end it isn't a zip archive
+    let truncated = env::temp_dir().join(format!("highloadcup-truncated-{}.zip", process::id()));
+    fs::write(&truncated, &bytes[..bytes.len() / 2]).unwrap();
+    let error = Database::from_file(truncated.to_str().unwrap(), 1, false).err().unwrap();
+    fs::remove_file(&truncated).unwrap();
+    assert!(error.to_string().starts_with("Invalid zip archive"), "{}", error);
+
+    // garbage in the compressed data of the first member
+    for byte in &mut bytes[100..1000] {
+        *byte = 0xff;
+    }
+    let corrupt = env::temp_dir().join(format!("highloadcup-garbage-{}.zip", process::id()));
+    fs::write(&corrupt, &bytes).unwrap();
+    for shards in 1..3 {
+        let error = Database::from_file(corrupt.to_str().unwrap(), shards, false).err().unwrap();
+        assert!(error.to_string().starts_with("Unable to load visits_1.json"), "{}", error);
+    }
+    fs::remove_file(&corrupt).unwrap();
+}
+
+#[test]
+fn empty_archive() {
+    let path = env::temp_dir().join(format!("highloadcup-empty-{}.zip", process::id()));
+    for members in &[&[][..], &["readme.txt"][..]] {
+        {
+            let mut zip = ZipWriter::new(File::create(&path).unwrap());
+            for name in members.iter() {
+                zip.start_file(*name, FileOptions::default()).unwrap();
+                zip.write_all(b"not json").unwrap();
+            }
+            zip.finish().unwrap();
+        }
+
+        let database = Database::from_file(path.to_str().unwrap(), 2, false).unwrap();
+        assert_eq!(database.users.len(), 0);
+        assert_eq!(database.locations.len(), 0);
+        assert_eq!(database.visits.len(), 0);
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[bench]
+fn load_sequential(b: &mut Bencher) {
+    let path = write_dataset("sequential");
+    b.iter(|| Database::from_file(path.to_str().unwrap(), 1, false).unwrap());
+    fs::remove_file(&path).unwrap();
+}
+
+#[bench]
+fn load_parallel(b: &mut Bencher) {
+    let path = write_dataset("parallel");
+    b.iter(|| Database::from_file(path.to_str().unwrap(), 4, false).unwrap());
+    fs::remove_file(&path).unwrap();
+}
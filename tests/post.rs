@@ -0,0 +1,48 @@
+#![feature(test)]
+
+extern crate test;
+extern crate futures;
+extern crate hyper;
+extern crate bytes;
+extern crate highloadcup;
+
+use std::sync::Arc;
+
+use futures::Future;
+use hyper::{Method, Request};
+use hyper::server::Service;
+use bytes::Bytes;
+use test::Bencher;
+
+use highloadcup::api::Api;
+use highloadcup::database::Database;
+use highloadcup::http::{TravelsServer, DEFAULT_MAX_BODY_BYTES};
+
+fn server() -> TravelsServer {
+    TravelsServer {
+        api: Arc::new(Api::new(Database::default())).into(),
+        cors_origin: Bytes::from_static(b"*"),
+        keep_alive: true,
+        timeout: None,
+        max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+        handle: None,
+        maintenance: Default::default(),
+        admin_token: None,
+        dataset: None,
+        post_headers: Default::default()
+    }
+}
+
+fn post(server: &TravelsServer, path: &str, body: &'static str) -> hyper::StatusCode {
+    let mut request = Request::new(Method::Post, path.parse().unwrap());
+    request.set_body(body);
+    server.call(request).wait().unwrap().status()
+}
+
+#[bench]
+fn post_update(b: &mut Bencher) {
+    let server = server();
+    let user = r#"{"id":1,"email":"a@b.c","first_name":"a","last_name":"b","gender":"m","birth_date":0}"#;
+    assert!(post(&server, "/users/new", user).is_success());
+    b.iter(|| post(&server, "/users/1", r#"{"first_name":"Аня"}"#));
+}
@@ -0,0 +1,29 @@
+extern crate libc;
+extern crate zip;
+
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn exits_on_sigterm() {
+    let mut server = common::start_server("shutdown", &[]);
+    unsafe {
+        libc::kill(server.process.id() as libc::pid_t, libc::SIGTERM);
+    }
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = server.process.try_wait().unwrap() {
+            break status;
+        }
+
+        if started.elapsed() > Duration::from_secs(5) {
+            panic!("Server didn't stop in time");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    assert!(status.success());
+}
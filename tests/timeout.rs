@@ -0,0 +1,56 @@
+extern crate zip;
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn read_all(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+#[test]
+fn slow_headers_close_connection() {
+    let server = common::start_server("slow-headers", &[("REQUEST_TIMEOUT_MS", "200")]);
+    let mut stream = server.connect();
+    stream.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n").unwrap();
+
+    let started = Instant::now();
+    assert_eq!(read_all(&mut stream), "");
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn slow_body_is_rejected() {
+    let server = common::start_server("slow-body", &[("REQUEST_TIMEOUT_MS", "200")]);
+    let mut stream = server.connect();
+    stream.write_all(b"POST /users/new HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\n{").unwrap();
+
+    let mut response = [0; 12];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(&response, b"HTTP/1.1 408");
+}
+
+#[test]
+fn keep_alive_within_timeout() {
+    let server = common::start_server("keep-alive", &[("REQUEST_TIMEOUT_MS", "500")]);
+    let mut stream = server.connect();
+
+    for _ in 0..3 {
+        stream.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        // the body is the last thing in the response
+        let mut response = Vec::new();
+        while !response.ends_with(b"{\"status\":\"ok\"}") {
+            let mut buffer = [0; 512];
+            let length = stream.read(&mut buffer).unwrap();
+            assert!(length != 0, "Connection closed");
+            response.extend_from_slice(&buffer[..length]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        thread::sleep(Duration::from_millis(200));
+    }
+}